@@ -0,0 +1,150 @@
+//! Pruning of the dated archive directories.
+//!
+//! Every release is also uploaded to `dist/YYYY-MM-DD/`, and nothing ever deletes those
+//! directories. That's what we want for the production bucket, but on dev-static they pile up
+//! release after release. The prune-archives action deletes the ones older than the retention
+//! window, keeping the directories the live channel manifests still point to.
+
+use crate::config::Channel;
+use crate::Context;
+use anyhow::Error;
+use chrono::NaiveDate;
+use std::collections::BTreeSet;
+
+const CHANNELS: &[Channel] = &[Channel::Stable, Channel::Beta, Channel::Nightly];
+
+impl Context {
+    pub(crate) fn do_prune_archives(&mut self) -> Result<(), Error> {
+        let retention = self.config.archive_retention_days.ok_or_else(|| {
+            anyhow::anyhow!("PROMOTE_RELEASE_ARCHIVE_RETENTION_DAYS must be set to prune archives")
+        })?;
+        let today = NaiveDate::parse_from_str(&self.date, "%Y-%m-%d")?;
+        let cutoff = today - chrono::Duration::days(retention.into());
+
+        let dist = format!(
+            "s3://{}/{}/",
+            self.config.upload_bucket, self.config.upload_dir
+        );
+        let output = self.aws_s3().arg("ls").arg(&dist).output()?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "failed to list {}: {}",
+                dist,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        let archives = archive_dates(&String::from_utf8(output.stdout)?);
+        let live = self.live_archive_dates()?;
+
+        let expired = archives
+            .iter()
+            .filter(|date| **date < cutoff && !live.contains(*date))
+            .collect::<Vec<_>>();
+        println!(
+            "{} archives in {}, {} older than {} ({} days ago) and not live",
+            archives.len(),
+            dist,
+            expired.len(),
+            cutoff,
+            retention
+        );
+        for date in &expired {
+            let dir = format!("{}{}/", dist, date.format("%Y-%m-%d"));
+            if self.config.prune_archives_delete {
+                println!("deleting {}", dir);
+                crate::run(
+                    self.aws_s3()
+                        .arg("rm")
+                        .arg("--recursive")
+                        .arg("--only-show-errors")
+                        .arg(&dir),
+                )?;
+            } else {
+                println!("would delete {}", dir);
+            }
+        }
+        if !self.config.prune_archives_delete && !expired.is_empty() {
+            println!("set PROMOTE_RELEASE_PRUNE_ARCHIVES_DELETE=1 to delete them");
+        }
+        Ok(())
+    }
+
+    /// Returns the archive dates of the channel manifests currently live in the upload bucket.
+    fn live_archive_dates(&self) -> Result<BTreeSet<NaiveDate>, Error> {
+        let mut dates = BTreeSet::new();
+        for &channel in CHANNELS {
+            let Some(manifest) = self.live_manifest(channel)? else {
+                continue;
+            };
+            if let Some(date) = manifest.get("date").and_then(|date| date.as_str()) {
+                dates.insert(NaiveDate::parse_from_str(date, "%Y-%m-%d")?);
+            }
+        }
+        Ok(dates)
+    }
+}
+
+/// Returns the dates of the `YYYY-MM-DD/` directories in the output of `aws s3 ls`, which lists
+/// them as `PRE YYYY-MM-DD/`. Other directories and files are ignored.
+fn archive_dates(listing: &str) -> BTreeSet<NaiveDate> {
+    listing
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("PRE "))
+        .filter_map(|dir| NaiveDate::parse_from_str(dir.trim_end_matches('/'), "%Y-%m-%d").ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_aws::FakeAws;
+
+    #[test]
+    fn prune_old_archives() {
+        let aws = FakeAws::new();
+        let work = tempfile::tempdir().unwrap();
+        for date in ["2023-01-01", "2023-02-01", "2023-05-20", "2023-06-01"] {
+            aws.put("static", &format!("dist/{}/rustc.tar.xz", date), "rustc");
+        }
+        aws.put("static", "dist/staging/rustc.tar.xz", "rustc");
+        aws.put(
+            "static",
+            "dist/channel-rust-stable.toml",
+            "date = \"2023-02-01\"\n",
+        );
+        let exists = |date: &str| aws.object("static", &format!("dist/{}", date)).is_dir();
+
+        let mut ctx = aws.context(work.path(), &[]);
+        assert!(ctx.do_prune_archives().is_err());
+
+        let mut ctx = aws.context(work.path(), &[("ARCHIVE_RETENTION_DAYS", "30")]);
+        ctx.do_prune_archives().unwrap();
+        assert!(exists("2023-01-01"));
+
+        let mut ctx = aws.context(
+            work.path(),
+            &[
+                ("ARCHIVE_RETENTION_DAYS", "30"),
+                ("PRUNE_ARCHIVES_DELETE", "1"),
+            ],
+        );
+        ctx.do_prune_archives().unwrap();
+        assert!(!exists("2023-01-01"));
+        // Still referenced by the live stable manifest.
+        assert!(exists("2023-02-01"));
+        assert!(exists("2023-05-20"));
+        assert!(exists("2023-06-01"));
+        assert!(exists("staging"));
+    }
+
+    #[test]
+    fn listing() {
+        let listing = "                           PRE 2023-06-01/\n\
+                                                   PRE staging/\n\
+                       2023-06-01 00:00:00        123 channel-rust-stable.toml\n";
+        assert_eq!(
+            vec![NaiveDate::from_ymd_opt(2023, 6, 1).unwrap()],
+            archive_dates(listing).into_iter().collect::<Vec<_>>()
+        );
+    }
+}
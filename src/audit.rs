@@ -0,0 +1,120 @@
+//! Audit of the contents of the tarballs, to catch regressions in how files are packaged before
+//! they're published. For example, key files of vendored crates must keep LF line endings, or
+//! their checksums won't match anymore once extracted.
+
+use crate::recompress::glob_matches;
+use crate::Context;
+use anyhow::Error;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+impl Context {
+    /// Ensures none of the files inside the `.tar.xz` tarballs matching the patterns in
+    /// PROMOTE_RELEASE_LF_ONLY_PATTERNS contain CRLF line endings.
+    pub(crate) fn audit_tarballs(&self) -> Result<(), Error> {
+        let patterns = &self.config.lf_only_patterns;
+        if patterns.is_empty() {
+            return Ok(());
+        }
+
+        let mut tarballs = Vec::new();
+        for entry in self.dl_dir().read_dir()? {
+            let path = entry?.path();
+            if path.to_string_lossy().ends_with(".tar.xz") {
+                tarballs.push(path);
+            }
+        }
+
+        let start = Instant::now();
+        let violations = tarballs
+            .par_iter()
+            .map(|tarball| {
+                Ok(find_crlf_files(tarball, patterns)?
+                    .into_iter()
+                    .map(|file| format!("{}: {}", tarball.display(), file.display()))
+                    .collect::<Vec<_>>())
+            })
+            .collect::<Result<Vec<_>, Error>>()?
+            .concat();
+        println!(
+            "audited {} tarballs in {:.2?}",
+            tarballs.len(),
+            start.elapsed()
+        );
+
+        if !violations.is_empty() {
+            anyhow::bail!(
+                "files with CRLF line endings found in the tarballs:\n{}",
+                violations.join("\n")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Returns the paths of the files inside the tarball matching any of `patterns` and containing
+/// CRLF line endings.
+fn find_crlf_files(tarball: &Path, patterns: &[String]) -> Result<Vec<PathBuf>, Error> {
+    let mut archive = tar::Archive::new(xz2::read::XzDecoder::new(File::open(tarball)?));
+
+    let mut found = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.into_owned();
+        let name = path.to_string_lossy();
+        if !patterns.iter().any(|pattern| glob_matches(pattern, &name)) {
+            continue;
+        }
+
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        if contents.windows(2).any(|window| window == b"\r\n") {
+            found.push(path);
+        }
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crlf_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let tarball = dir.path().join("rust-src.tar.xz");
+
+        let mut builder = tar::Builder::new(xz2::write::XzEncoder::new(
+            File::create(&tarball).unwrap(),
+            1,
+        ));
+        for (path, contents) in [
+            ("rust-src/vendor/libssh2-sys/libssh2/key.pem", "a\r\nb\r\n"),
+            ("rust-src/vendor/openssl/key.pem", "a\nb\n"),
+            ("rust-src/README.md", "a\r\nb\r\n"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+
+        assert_eq!(
+            find_crlf_files(&tarball, &["*.pem".to_string()]).unwrap(),
+            vec![PathBuf::from("rust-src/vendor/libssh2-sys/libssh2/key.pem")]
+        );
+        assert!(find_crlf_files(&tarball, &["*.rs".to_string()])
+            .unwrap()
+            .is_empty());
+    }
+}
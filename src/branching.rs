@@ -56,6 +56,14 @@ impl Context {
             );
         }
 
+        if self.config.strict_branching {
+            check_version_steps(
+                &stable_version.content()?,
+                &beta_version.content()?,
+                &future_beta_version.content()?,
+            )?;
+        }
+
         // No need to disable branch protection, as the promote-release app is
         // specifically authorized to force-push to these branches.
         token.update_ref("heads/stable", &beta_sha, true)?;
@@ -75,6 +83,36 @@ impl Context {
     }
 }
 
+/// Parses the major and minor components of a `x.y.z` version.
+fn parse_minor_version(version: &str) -> anyhow::Result<(u64, u64)> {
+    let mut parts = version.trim().split('.');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(major), Some(minor), Some(_patch), None) => Ok((major.parse()?, minor.parse()?)),
+        _ => anyhow::bail!("invalid version: {:?}", version.trim()),
+    }
+}
+
+/// Ensures that each branch is exactly one minor release ahead of the previous one, so that
+/// promoting them can't accidentally skip a release.
+fn check_version_steps(stable: &str, beta: &str, prebump: &str) -> anyhow::Result<()> {
+    let versions = [
+        ("stable", parse_minor_version(stable)?),
+        ("beta", parse_minor_version(beta)?),
+        ("pre-bump master", parse_minor_version(prebump)?),
+    ];
+    for pair in versions.windows(2) {
+        let (prev_name, (prev_major, prev_minor)) = pair[0];
+        let (name, (major, minor)) = pair[1];
+        if major != prev_major || minor != prev_minor + 1 {
+            anyhow::bail!(
+                "{name} ({major}.{minor}) is not exactly one release after {prev_name} \
+                 ({prev_major}.{prev_minor}); refusing to promote branches."
+            );
+        }
+    }
+    Ok(())
+}
+
 fn branchpoint(client: &mut RepositoryClient<'_>, start: &str) -> anyhow::Result<FullCommitData> {
     client.merge_commit_for_file(start, "src/version")
 }
@@ -89,9 +127,24 @@ fn check_branchpoint() {
     };
     let master_pinned = "4f9898a7947059433d08357cdaaba84c4705873d";
     let mut client = curl::easy::Easy::new();
-    let mut client = RepositoryClient::from_pat(&mut client, token.trim(), "rust-lang/rust");
+    let mut client = RepositoryClient::from_pat(
+        &mut client,
+        "https://api.github.com",
+        token.trim(),
+        "rust-lang/rust",
+    );
     assert_eq!(
         branchpoint(&mut client, master_pinned).unwrap().sha,
         "bb71929892c4e7200d66b3efba9febead7056891"
     );
 }
+
+#[test]
+fn check_version_steps_strict() {
+    check_version_steps("1.61.0\n", "1.62.0\n", "1.63.0\n").unwrap();
+    check_version_steps("1.61.1", "1.62.0", "1.63.0").unwrap();
+    assert!(check_version_steps("1.61.0", "1.62.0", "1.64.0").is_err());
+    assert!(check_version_steps("1.61.0", "1.63.0", "1.64.0").is_err());
+    assert!(check_version_steps("1.61.0", "1.62.0", "1.62.0").is_err());
+    assert!(check_version_steps("1.61.0", "1.62", "1.63.0").is_err());
+}
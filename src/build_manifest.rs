@@ -1,5 +1,6 @@
 use crate::Context;
 use anyhow::{Context as _, Error};
+use sha2::Digest;
 use std::{
     collections::{HashMap, HashSet},
     fs::File,
@@ -13,7 +14,9 @@ use xz2::read::XzDecoder;
 
 pub(crate) struct BuildManifest<'a> {
     builder: &'a Context,
-    executable: NamedTempFile,
+    executable: PathBuf,
+    // Keeps the binary extracted from the tarball alive, if we're not using a pinned one.
+    _extracted: Option<NamedTempFile>,
 
     _metadata_dir: TempDir,
     checksum_cache_path: PathBuf,
@@ -26,10 +29,22 @@ impl<'a> BuildManifest<'a> {
         let checksum_cache_path = metadata_dir.path().join("checksum-cache.json");
         let shipped_files_path = metadata_dir.path().join("shipped-files.txt");
 
+        let (executable, extracted) = match &builder.config.build_manifest_path {
+            Some(path) => {
+                println!("using the pinned build-manifest binary at {}", path);
+                (PathBuf::from(path), None)
+            }
+            None => {
+                let extracted = Self::extract(builder)
+                    .context("failed to extract build-manifest from the tarball")?;
+                (extracted.path().to_path_buf(), Some(extracted))
+            }
+        };
+
         Ok(Self {
             builder,
-            executable: Self::extract(builder)
-                .context("failed to extract build-manifest from the tarball")?,
+            executable,
+            _extracted: extracted,
 
             _metadata_dir: metadata_dir,
             checksum_cache_path,
@@ -59,12 +74,14 @@ impl<'a> BuildManifest<'a> {
         println!("running build-manifest...");
         // build-manifest <input-dir> <output-dir> <date> <upload-addr> <channel>
         let num_threads = self.builder.config.num_threads.to_string();
-        let status = Command::new(self.executable.path())
+        let status = Command::new(&self.executable)
             .arg(self.builder.dl_dir())
             .arg(dest)
             .arg(&self.builder.date)
             .arg(upload_base)
             .arg(config.channel.to_string())
+            // Set first, so that they can't override the variables we rely on.
+            .envs(config.build_manifest_env.iter().cloned())
             .env("BUILD_MANIFEST_CHECKSUM_CACHE", &self.checksum_cache_path)
             .env("BUILD_MANIFEST_NUM_THREADS", num_threads)
             .env(
@@ -106,6 +123,44 @@ impl<'a> BuildManifest<'a> {
     }
 }
 
+/// Ensures the build-manifest tarballs in `dl` match the checksums CI uploaded along with them.
+/// The binary inside is executed during the release, so we refuse to use a tarball that was
+/// corrupted or replaced after CI built it. This must run before the checksum files are deleted.
+pub(crate) fn verify_tarball_checksums(dl: &Path) -> Result<(), Error> {
+    for entry in dl.read_dir()? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if !name.starts_with("build-manifest-")
+            || !(name.ends_with(".tar.xz") || name.ends_with(".tar.gz"))
+        {
+            continue;
+        }
+
+        let sha256_path = dl.join(format!("{}.sha256", name));
+        let expected = std::fs::read_to_string(&sha256_path).with_context(|| {
+            format!(
+                "missing the checksum of {}, refusing to run build-manifest from it",
+                name
+            )
+        })?;
+        let expected = expected.split_whitespace().next().unwrap_or_default();
+
+        let mut digest = sha2::Sha256::default();
+        std::io::copy(&mut File::open(&path)?, &mut digest)?;
+        let actual = hex::encode(digest.finalize());
+        if !actual.eq_ignore_ascii_case(expected) {
+            anyhow::bail!(
+                "the checksum of {} is {}, but CI recorded {}",
+                name,
+                actual,
+                expected
+            );
+        }
+        println!("verified the checksum of {}", name);
+    }
+    Ok(())
+}
+
 pub(crate) struct Execution {
     pub(crate) shipped_files: HashSet<PathBuf>,
     pub(crate) checksum_cache: HashMap<PathBuf, String>,
@@ -127,3 +182,27 @@ impl Execution {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tarball_checksums() {
+        let dir = tempfile::tempdir().unwrap();
+        let tarball = "build-manifest-nightly-x86_64-unknown-linux-gnu.tar.xz";
+        std::fs::write(dir.path().join(tarball), "build-manifest").unwrap();
+        // Other tarballs are not checked.
+        std::fs::write(dir.path().join("rustc-nightly.tar.xz"), "rustc").unwrap();
+        assert!(verify_tarball_checksums(dir.path()).is_err());
+
+        let sha256 = dir.path().join(format!("{}.sha256", tarball));
+        std::fs::write(&sha256, format!("{}  {}\n", "0".repeat(64), tarball)).unwrap();
+        let err = verify_tarball_checksums(dir.path()).unwrap_err();
+        assert!(err.to_string().contains("CI recorded"), "{}", err);
+
+        let actual = hex::encode(sha2::Sha256::digest(b"build-manifest"));
+        std::fs::write(&sha256, format!("{}  {}\n", actual, tarball)).unwrap();
+        verify_tarball_checksums(dir.path()).unwrap();
+    }
+}
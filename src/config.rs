@@ -1,13 +1,30 @@
+use crate::delta::VersionChange;
 use crate::discourse::Discourse;
-use crate::fastly::Fastly;
+use crate::fastly::{Fastly, SurrogateKey};
 use crate::github::Github;
 use crate::Context;
 use anyhow::{Context as _, Error};
 use std::env::VarError;
+use std::ffi::OsString;
 use std::str::FromStr;
 
 const ENVIRONMENT_VARIABLE_PREFIX: &str = "PROMOTE_RELEASE_";
 
+/// Deepest xz match finder search allowed. Deeper searches make the recompression take hours for
+/// no measurable gain.
+const MAX_XZ_DEPTH: u32 = 10_000;
+
+/// Front matter of the dev-static blog posts, unless PROMOTE_RELEASE_BLOG_FRONT_MATTER_FILE is set.
+/// `{version}` is replaced with the version being released.
+const DEFAULT_BLOG_FRONT_MATTER: &str = r#"---
+layout: post
+title: "{version} pre-release testing"
+author: Release automation
+team: The Release Team <https://www.rust-lang.org/governance/teams/release>
+---
+
+"#;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub(crate) enum Channel {
     Stable,
@@ -64,6 +81,39 @@ pub(crate) enum Action {
     /// * Create a rust-lang/cargo branch for the appropriate beta commit.
     /// * Post a PR against the newly created beta branch bump src/ci/channel to `beta`.
     PromoteBranches,
+
+    /// This only invalidates the CDN caches (CloudFront and Fastly) for the configured channel,
+    /// both for the release artifacts and for the documentation. It's useful after manually
+    /// fixing something in the bucket, without doing a whole new release.
+    Invalidate,
+
+    /// This only (re)publishes the documentation of the configured channel, from the docs
+    /// tarballs already uploaded to the release bucket. For the stable channel the version to
+    /// publish must be set with PROMOTE_RELEASE_DOCS_VERSION, and the docs are uploaded both to
+    /// `/doc/stable/` and to `/doc/$version/`.
+    PublishDocs,
+
+    /// This runs synthetic artifacts through the release pipeline (recompression, signing and
+    /// the smoke test server) without touching S3, GitHub or Discourse, to test promote-release
+    /// itself.
+    SelfTest,
+
+    /// This makes a release staged with PROMOTE_RELEASE_ARCHIVE_ONLY live, downloading it from
+    /// the dated archive directory (of today, or of PROMOTE_RELEASE_ARCHIVE_DATE) and publishing
+    /// it to the live `dist/` directory, along with its documentation, announcements and tags.
+    PromoteArchive,
+
+    /// This deletes the dated archive directories (`dist/YYYY-MM-DD/`) of the upload bucket older
+    /// than PROMOTE_RELEASE_ARCHIVE_RETENTION_DAYS, except the ones the live channel manifests
+    /// point to. Nothing is deleted unless PROMOTE_RELEASE_PRUNE_ARCHIVES_DELETE is set, the
+    /// directories are only listed.
+    PruneArchives,
+
+    /// This re-signs the release of the configured channel archived today (or on
+    /// PROMOTE_RELEASE_ARCHIVE_DATE) with the configured key, uploading only the new signatures
+    /// to the archive, and to the live `dist/` directory if the release is still live. This is
+    /// meant for key rotations, and doesn't touch the tarballs, manifests or checksums.
+    Resign,
 }
 
 impl FromStr for Action {
@@ -73,17 +123,265 @@ impl FromStr for Action {
         match input {
             "promote-release" => Ok(Action::PromoteRelease),
             "promote-branches" => Ok(Action::PromoteBranches),
+            "invalidate" => Ok(Action::Invalidate),
+            "publish-docs" => Ok(Action::PublishDocs),
+            "self-test" => Ok(Action::SelfTest),
+            "promote-archive" => Ok(Action::PromoteArchive),
+            "prune-archives" => Ok(Action::PruneArchives),
+            "resign" => Ok(Action::Resign),
             _ => anyhow::bail!("unknown channel: {}", input),
         }
     }
 }
 
+/// When the tarballs are recompressed during a release. Which formats are produced is controlled
+/// separately by `recompress_gz` and `recompress_xz`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum RecompressStage {
+    /// Recompress before pruning the files build-manifest doesn't ship. This is needed when the
+    /// input lacks some formats, as build-manifest must see the full set of artifacts to decide
+    /// what to ship.
+    BeforePruning,
+    /// Recompress only the shipped files, after pruning. Requires the input to already contain
+    /// every format (nightly 1.71+ does, see rust-lang/rust#110436).
+    AfterPruning,
+    /// Never recompress, shipping the tarballs as produced by CI.
+    Never,
+}
+
+impl RecompressStage {
+    fn default_for(channel: Channel) -> Self {
+        match channel {
+            Channel::Nightly => RecompressStage::AfterPruning,
+            Channel::Beta | Channel::Stable => RecompressStage::BeforePruning,
+        }
+    }
+}
+
+impl FromStr for RecompressStage {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "before-pruning" => Ok(RecompressStage::BeforePruning),
+            "after-pruning" => Ok(RecompressStage::AfterPruning),
+            "never" => Ok(RecompressStage::Never),
+            _ => anyhow::bail!(
+                "unknown recompress stage (expected before-pruning, after-pruning or never): {}",
+                input
+            ),
+        }
+    }
+}
+
+/// Hash algorithm used in the GPG signatures we produce.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) struct GpgHashAlgorithm(pub(crate) pgp::crypto::hash::HashAlgorithm);
+
+impl FromStr for GpgHashAlgorithm {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        use pgp::crypto::hash::HashAlgorithm;
+        Ok(GpgHashAlgorithm(match input {
+            "sha256" => HashAlgorithm::SHA2_256,
+            "sha384" => HashAlgorithm::SHA2_384,
+            "sha512" => HashAlgorithm::SHA2_512,
+            _ => anyhow::bail!(
+                "unsupported hash algorithm (expected sha256, sha384 or sha512): {}",
+                input
+            ),
+        }))
+    }
+}
+
+/// Match finder of the xz encoder used when recompressing tarballs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum XzMatchFinder {
+    HashChain3,
+    HashChain4,
+    BinaryTree2,
+    BinaryTree3,
+    BinaryTree4,
+}
+
+impl XzMatchFinder {
+    pub(crate) fn to_xz2(self) -> xz2::stream::MatchFinder {
+        use xz2::stream::MatchFinder;
+        match self {
+            XzMatchFinder::HashChain3 => MatchFinder::HashChain3,
+            XzMatchFinder::HashChain4 => MatchFinder::HashChain4,
+            XzMatchFinder::BinaryTree2 => MatchFinder::BinaryTree2,
+            XzMatchFinder::BinaryTree3 => MatchFinder::BinaryTree3,
+            XzMatchFinder::BinaryTree4 => MatchFinder::BinaryTree4,
+        }
+    }
+
+    /// liblzma rejects nice lengths shorter than the number of bytes hashed by the match finder.
+    fn min_nice_len(self) -> u32 {
+        match self {
+            XzMatchFinder::BinaryTree2 => 2,
+            XzMatchFinder::HashChain3 | XzMatchFinder::BinaryTree3 => 3,
+            XzMatchFinder::HashChain4 | XzMatchFinder::BinaryTree4 => 4,
+        }
+    }
+}
+
+impl FromStr for XzMatchFinder {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        Ok(match input {
+            "hc3" => XzMatchFinder::HashChain3,
+            "hc4" => XzMatchFinder::HashChain4,
+            "bt2" => XzMatchFinder::BinaryTree2,
+            "bt3" => XzMatchFinder::BinaryTree3,
+            "bt4" => XzMatchFinder::BinaryTree4,
+            _ => anyhow::bail!(
+                "unsupported match finder (expected hc3, hc4, bt2, bt3 or bt4): {}",
+                input
+            ),
+        })
+    }
+}
+
+impl std::fmt::Display for XzMatchFinder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            XzMatchFinder::HashChain3 => "hc3",
+            XzMatchFinder::HashChain4 => "hc4",
+            XzMatchFinder::BinaryTree2 => "bt2",
+            XzMatchFinder::BinaryTree3 => "bt3",
+            XzMatchFinder::BinaryTree4 => "bt4",
+        })
+    }
+}
+
+/// A secondary bucket the release is also uploaded to, configured as `bucket/dir`, optionally
+/// followed by `@endpoint-url` when the mirror lives on a different S3-compatible service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UploadMirror {
+    pub(crate) bucket: String,
+    pub(crate) dir: String,
+    pub(crate) endpoint_url: Option<String>,
+}
+
+impl FromStr for UploadMirror {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (location, endpoint_url) = match input.split_once('@') {
+            Some((location, endpoint)) => (location, Some(endpoint.to_string())),
+            None => (input, None),
+        };
+        match location.split_once('/') {
+            Some((bucket, dir)) if !bucket.is_empty() && !dir.is_empty() => Ok(UploadMirror {
+                bucket: bucket.to_string(),
+                dir: dir.trim_matches('/').to_string(),
+                endpoint_url,
+            }),
+            _ => anyhow::bail!("invalid mirror (expected bucket/dir[@endpoint]): {}", input),
+        }
+    }
+}
+
+impl std::fmt::Display for UploadMirror {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.dir)?;
+        if let Some(endpoint_url) = &self.endpoint_url {
+            write!(f, " ({})", endpoint_url)?;
+        }
+        Ok(())
+    }
+}
+
+/// Fingerprint of the only GPG key allowed to sign releases uploaded to a bucket, configured as
+/// `bucket=fingerprint`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BucketKey {
+    pub(crate) bucket: String,
+    pub(crate) fingerprint: String,
+}
+
+impl FromStr for BucketKey {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.split_once('=') {
+            Some((bucket, fingerprint)) if !bucket.is_empty() && !fingerprint.is_empty() => {
+                Ok(BucketKey {
+                    bucket: bucket.trim().to_string(),
+                    fingerprint: fingerprint.to_string(),
+                })
+            }
+            _ => anyhow::bail!(
+                "invalid bucket key (expected bucket=fingerprint): {}",
+                input
+            ),
+        }
+    }
+}
+
+/// Secret material, either stored in a file or passed directly through the environment (for
+/// example by a secret manager), so that it never has to be written to disk.
+pub(crate) enum Secret {
+    File(String),
+    Value(String),
+}
+
+impl Secret {
+    pub(crate) fn read(&self) -> Result<String, Error> {
+        match self {
+            Secret::File(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read the secret at {}", path)),
+            Secret::Value(value) => Ok(value.clone()),
+        }
+    }
+}
+
+/// Identity of the tagger of the release tags, configured as `Name <email>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Tagger {
+    pub(crate) name: String,
+    pub(crate) email: String,
+}
+
+impl Default for Tagger {
+    fn default() -> Self {
+        Tagger {
+            name: "rust-lang/promote-release".into(),
+            email: "release-team@rust-lang.org".into(),
+        }
+    }
+}
+
+impl FromStr for Tagger {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input
+            .trim()
+            .strip_suffix('>')
+            .and_then(|s| s.split_once('<'))
+        {
+            Some((name, email)) if !name.trim().is_empty() && !email.is_empty() => Ok(Tagger {
+                name: name.trim().to_string(),
+                email: email.to_string(),
+            }),
+            _ => anyhow::bail!("invalid tagger (expected Name <email>): {}", input),
+        }
+    }
+}
+
 pub(crate) struct Config {
     /// This is the action we're expecting to take.
     pub(crate) action: Action,
 
     /// The channel we're currently releasing.
     pub(crate) channel: Channel,
+    /// The channels to release one after the other, configured as a comma-separated list in
+    /// PROMOTE_RELEASE_CHANNEL. `channel` starts as the first of them.
+    pub(crate) channels: Vec<Channel>,
     /// CloudFront distribution ID for doc.rust-lang.org.
     pub(crate) cloudfront_doc_id: String,
     /// CloudFront distribution ID for static.rust-lang.org.
@@ -92,14 +390,62 @@ pub(crate) struct Config {
     pub(crate) download_bucket: String,
     /// The S3 directory that CI artifacts will be downloaded from.
     pub(crate) download_dir: String,
-    /// Path to the file containing the ASCII-armored, encrypted GPG secret key.
-    pub(crate) gpg_key_file: String,
-    /// Path of the file containing the password of the GPG secret key.
-    pub(crate) gpg_password_file: String,
+    /// The ASCII-armored, encrypted GPG secret key, either read from the file at
+    /// PROMOTE_RELEASE_GPG_KEY_FILE or passed directly in PROMOTE_RELEASE_GPG_KEY.
+    pub(crate) gpg_key: Secret,
+    /// The password of the GPG secret key, either read from the file at
+    /// PROMOTE_RELEASE_GPG_PASSWORD_FILE or passed directly in PROMOTE_RELEASE_GPG_PASSWORD.
+    pub(crate) gpg_password: Secret,
+    /// Path of a file to append a JSON line to for every permanent signature we produce, with
+    /// the file name, its SHA256, the signature creation time and the signing key ID.
+    pub(crate) signatures_log: Option<String>,
+    /// Command invoked with the path of every macOS `.pkg` installer before it's hashed and
+    /// signed, to apply the Apple signature and notarization. Installers are left untouched when
+    /// this is not set.
+    pub(crate) pkg_sign_command: Option<String>,
+    /// Hash algorithm used for GPG signatures, both of the artifacts and of the git tags.
+    pub(crate) gpg_hash_algorithm: GpgHashAlgorithm,
+    /// Warn if the GPG key expires within this many days.
+    pub(crate) gpg_key_expiry_days: i64,
+    /// Whether to fail the release instead of only warning when the GPG key is about to expire.
+    pub(crate) gpg_key_expiry_fatal: bool,
+    /// Fingerprint of the key the release must be signed with. When set, the signature of the
+    /// channel manifest is verified against it before anything is published.
+    pub(crate) expected_gpg_fingerprint: Option<String>,
+    /// Path of the ASCII-armored public key the signatures are verified against once the release
+    /// is signed, such as the key rustup pins. Defaults to the public part of the signing key.
+    pub(crate) gpg_public_key_file: Option<String>,
+    /// Which GPG key signs the releases of each upload bucket, as a comma-separated list of
+    /// `bucket=fingerprint` entries. Signing refuses to start if the key is bound to a different
+    /// bucket than the one we upload to, or if the upload bucket is bound to a different key, so
+    /// that the dev key can never sign a production release (and vice versa).
+    pub(crate) gpg_bucket_keys: Vec<BucketKey>,
+    /// Path of a build-manifest binary to use instead of the one shipped in the channel's own
+    /// `build-manifest` tarball, for example to debug a specific build-manifest version.
+    pub(crate) build_manifest_path: Option<String>,
+    /// Extra environment variables passed to build-manifest, from every
+    /// `PROMOTE_RELEASE_BUILD_MANIFEST_ENV_$NAME` variable (passed as `$NAME`). This allows using
+    /// new build-manifest options without changing promote-release.
+    pub(crate) build_manifest_env: Vec<(String, String)>,
     // Number of concurrent threads to start during the parallel segments of promote-release.
     pub(crate) num_threads: usize,
+    /// Number of files hashed and signed concurrently. Signing reads whole files in memory, so
+    /// this might need to be lower than `num_threads` to avoid running out of memory.
+    pub(crate) sign_threads: usize,
+    /// Upper bound, in MiB, of the memory used by the files being recompressed and signed at the
+    /// same time. Threads wait for memory to be available before starting on a file. Unbounded
+    /// when not set.
+    pub(crate) memory_budget_mb: Option<u64>,
+    /// Free space, in MiB, the filesystem of the work directory must have before the artifacts
+    /// are downloaded. The release is aborted early otherwise, rather than failing with an I/O
+    /// error halfway through. The space needed by the recompression is checked separately, once
+    /// the size of the artifacts is known.
+    pub(crate) min_free_space_mb: u64,
     /// URL of the git repository containing the Rust source code.
     pub(crate) repository: String,
+    /// URLs of mirrors of `repository`, tried in order when the commit to release can't be found
+    /// in it (for example during an outage).
+    pub(crate) repository_mirrors: Vec<String>,
     /// Remote HTTP host artifacts will be uploaded to. Note that this is *not* the same as what's
     /// configured in `config.toml` for rustbuild, it's just the *host* that we're uploading to and
     /// going to be looking at urls from.
@@ -113,11 +459,26 @@ pub(crate) struct Config {
     pub(crate) upload_addr: String,
     /// The S3 bucket that release artifacts will be uploaded to.
     pub(crate) upload_bucket: String,
+    /// Additional buckets the release and the archive are uploaded to, for redundancy. Configured
+    /// as a comma-separated list of `bucket/dir[@endpoint]` entries.
+    pub(crate) extra_upload_buckets: Vec<UploadMirror>,
+    /// Whether a failed upload to one of the extra buckets should abort the release. By default
+    /// such failures are only logged, as the primary bucket is the one that matters.
+    pub(crate) extra_upload_failures_fatal: bool,
     /// The storage class artifacts are created in. Primarily used for testing
     /// (we default to INTELLIGENT_TIERING if not set).
     pub(crate) storage_class: String,
     /// The S3 directory that release artifacts will be uploaded to.
     pub(crate) upload_dir: String,
+    /// Whether to also upload a `latest-$channel.txt` file containing the released version, after
+    /// all the other files were uploaded.
+    pub(crate) upload_latest_file: bool,
+    /// Whether to upload `release-info-$channel.json` next to the channel manifest, with the
+    /// versions, commit, date and components of the release for downstream automation.
+    pub(crate) upload_release_info: bool,
+    /// Whether to publish `channel-rust-$channel-delta.toml`, listing the components that changed
+    /// since the previous release of the channel.
+    pub(crate) delta_manifest: bool,
     /// Whether to run the checks at startup that prevent a potentially unwanted release from
     /// happening. If this is set to `true`, the following checks will be disabled:
     ///
@@ -125,6 +486,28 @@ pub(crate) struct Config {
     /// * Preventing multiple releases on the channel of the same git commit.
     /// * Preventing multiple releases on stable and beta of the same version number.
     pub(crate) bypass_startup_checks: bool,
+    /// Whether to write a `version-report.json` file in the work directory, recording the
+    /// version and commit we detected and whether we decided to release them.
+    pub(crate) version_report: bool,
+
+    /// Components that must be present for x86_64-unknown-linux-gnu for the release to happen.
+    /// Defaults to rustc, rust-std and cargo on nightly, and to nothing on other channels.
+    pub(crate) required_components: Vec<String>,
+    /// Targets whose artifacts are removed before generating the manifests, so that a release can
+    /// ship without a target whose artifacts are broken. The targets the docs and build-manifest
+    /// are taken from can't be excluded.
+    pub(crate) exclude_targets: Vec<String>,
+    /// Size in bytes below which a `.tar.xz` tarball is considered truncated. Set to 0 to disable
+    /// the check.
+    pub(crate) min_tarball_size: u64,
+    /// Whether to fail the release instead of only warning when truncated tarballs are found.
+    pub(crate) small_tarballs_fatal: bool,
+    /// Maximum total size in bytes of the artifacts referenced by the channel manifest. A sudden
+    /// increase usually means a packaging bug, like debug symbols being shipped by accident.
+    pub(crate) max_total_size: Option<u64>,
+    /// Maximum growth, in percent, of the total size of the artifacts compared to the previous
+    /// release of the channel.
+    pub(crate) max_size_growth_percent: Option<f64>,
 
     /// Whether to force the recompression from input tarballs into .gz compressed tarballs.
     ///
@@ -132,17 +515,118 @@ pub(crate) struct Config {
     pub(crate) recompress_gz: bool,
     /// Whether to force the recompression from input tarballs into highly compressed .xz tarballs.
     pub(crate) recompress_xz: bool,
+    /// Number of tarballs, starting from the largest, to recompress with a multi-threaded xz
+    /// encoder. The largest tarballs are otherwise recompressed by a single thread at the end of
+    /// the recompression, while every other thread is idle. Disabled when zero.
+    pub(crate) xz_parallel_files: usize,
+    /// Number of threads used by each of the multi-threaded xz encoders.
+    pub(crate) xz_parallel_threads: u32,
+    /// Tuning of the xz encoder used for recompression, configurable to run compression
+    /// experiments on a real release. The preset (0-9) provides the defaults of the other
+    /// settings, except for the dictionary size which is always 64MiB.
+    pub(crate) xz_preset: u32,
+    /// How deep the match finder searches, where 0 lets liblzma pick a depth automatically.
+    pub(crate) xz_depth: u32,
+    /// Length of a match that's considered good enough to stop searching, from 2 to 273.
+    pub(crate) xz_nice_len: u32,
+    /// The match finder of the xz encoder: hc3, hc4, bt2, bt3 or bt4.
+    pub(crate) xz_match_finder: XzMatchFinder,
+    /// Whether to check that every recompressed tarball decompresses to the same contents as the
+    /// original one. This roughly doubles the recompression time, and it's meant to validate
+    /// changes to the compression settings.
+    pub(crate) verify_recompression: bool,
+    /// When to recompress the tarballs. Defaults to after pruning on nightly, and before pruning
+    /// on the other channels (see `recompress_stage()`).
+    pub(crate) recompress_stage: Option<RecompressStage>,
+    /// Only recompress the tarballs whose file name matches this pattern, where `*` matches any
+    /// sequence of characters and `?` any single character (e.g. `rust-docs-*`).
+    pub(crate) recompress_filter: Option<String>,
+    /// Patterns (with the same syntax as `recompress_filter`) of paths inside the `.tar.xz`
+    /// tarballs which must not contain CRLF line endings. The tarballs are not audited when empty.
+    pub(crate) lf_only_patterns: Vec<String>,
 
     /// The compression level to use when recompressing tarballs with gzip.
     pub(crate) gzip_compression_level: u32,
     /// Custom sha of the commit to release, instead of the latest commit in the channel's branch.
     pub(crate) override_commit: Option<String>,
+    /// Custom git ref to release from, instead of the channel's branch. The ref is resolved when
+    /// the release starts, so this is handy to test changes to the release process from a
+    /// throwaway branch. Branch names without a `refs/` prefix are looked up in `refs/heads/`.
+    pub(crate) override_ref: Option<String>,
+    /// Whether to upload only the documentation files that changed since the previous release,
+    /// using an index of content hashes stored alongside the docs, instead of a full
+    /// `aws s3 sync --delete`.
+    pub(crate) incremental_docs: bool,
+    /// Whether to also publish the `rust-docs` tarball as `/doc/$dir/rust-docs.tar.gz`, next to
+    /// the extracted docs, for people who want to browse them offline.
+    pub(crate) docs_tarball: bool,
+    /// Whether to keep the downloaded, recompressed and signed artifacts around after the release,
+    /// instead of deleting them. Useful when debugging a release.
+    pub(crate) keep_artifacts: bool,
+    /// Whether to only publish the release to the dated archive directory (and stable docs to
+    /// their versioned directory), leaving the live `dist/` untouched. The archived copy can then
+    /// be tested before making it live with the promote-archive action.
+    pub(crate) archive_only: bool,
+    /// Whether to stop the release after listing the files that would be pruned (the ones not
+    /// shipped in the manifests generated by build-manifest), without deleting or publishing
+    /// anything. This is meant to debug why a component is missing from a release.
+    pub(crate) prune_dry_run: bool,
+    /// Age (in days) after which the prune-archives action deletes a dated archive directory.
+    pub(crate) archive_retention_days: Option<u32>,
+    /// Whether the prune-archives action actually deletes the old archive directories, rather
+    /// than only listing them.
+    pub(crate) prune_archives_delete: bool,
+    /// Stable version whose docs are published by the `publish-docs` action.
+    pub(crate) docs_version: Option<String>,
+    /// Path to the AWS CLI executable, which is used for all the interactions with S3 and
+    /// CloudFront. Defaults to looking up `aws` in the PATH.
+    pub(crate) aws_cli: String,
     /// Custom Endpoint URL for S3. Set this if you want to point to an S3-compatible service
     /// instead of the AWS one.
     pub(crate) s3_endpoint_url: Option<String>,
+    /// Number of concurrent requests the AWS CLI performs for each `aws s3` command. Uses the AWS
+    /// CLI default when not set.
+    pub(crate) s3_max_concurrent_requests: Option<u32>,
+    /// Size of each part of multipart S3 transfers, in a format accepted by the AWS CLI (for
+    /// example `64MB`). Uses the AWS CLI default when not set.
+    pub(crate) s3_multipart_chunksize: Option<String>,
+    /// Whether to keep the `.sha256` files that come with the downloaded artifacts instead of
+    /// regenerating them, only producing new `.asc` signatures. This is meant for promoting a
+    /// dev-static release to stable, where the artifacts are byte-for-byte the same and only the
+    /// signing key changes. The existing checksums are still verified before being shipped.
+    pub(crate) reuse_checksums: bool,
+    /// Whether to skip installing the release with rustup and building a crate with it, for dev
+    /// environments without rustup or cargo. This is unrelated to `bypass_startup_checks`.
+    pub(crate) skip_smoke_test: bool,
+    /// Maximum time (in seconds) each command of the smoke test is allowed to run for, before
+    /// being killed and failing the release.
+    pub(crate) smoke_test_timeout: u64,
+    /// Edition of the sample crate built by the smoke test. Uses the default of `cargo init`
+    /// when not set.
+    pub(crate) smoke_test_edition: Option<String>,
+    /// Dependency added to the sample crate built by the smoke test, as a `name@version` spec
+    /// accepted by `cargo add`. This exercises the resolver and linking against a real crate, at
+    /// the cost of fetching it from crates.io.
+    pub(crate) smoke_test_dependency: Option<String>,
+    /// Whether the smoke test should run cargo in offline mode (`CARGO_NET_OFFLINE`), for
+    /// sandboxed environments without internet access.
+    pub(crate) smoke_test_offline: bool,
+    /// Directory of vendored crates (as produced by `cargo vendor`) replacing crates.io when
+    /// building the sample crate of the smoke test. This allows using `smoke_test_dependency`
+    /// without internet access, and implies `smoke_test_offline`.
+    pub(crate) smoke_test_vendor_dir: Option<String>,
+    /// Whether the smoke test server should refuse to serve the gzip tarballs, so that rustup has
+    /// to install the toolchain from the (recompressed) xz tarballs.
+    pub(crate) smoke_test_xz_only: bool,
     /// Whether to skip invalidating the CloudFront distributions. This is useful when running the
     /// release process locally, without access to the production AWS account.
     pub(crate) skip_cloudfront_invalidations: bool,
+    /// User agent of all the HTTP requests, followed by the ID of the run.
+    pub(crate) user_agent: String,
+
+    /// Whether promoting branches should check that stable, beta and the pre-bump master are
+    /// exactly one minor version apart, instead of only checking that they're all different.
+    pub(crate) strict_branching: bool,
 
     /// Where to tag stable rustc releases.
     ///
@@ -159,6 +643,22 @@ pub(crate) struct Config {
     ///
     /// Should be a org/repo code, e.g., rust-lang/cargo.
     pub(crate) cargo_tag_repository: Option<String>,
+    /// Whether to list the rustc and cargo commits of the release in the message of the tags.
+    pub(crate) tag_include_commits: bool,
+    /// Identities used to tag the rustc and cargo repositories, as `Name <email>`. Both default
+    /// to `rust-lang/promote-release <release-team@rust-lang.org>`.
+    pub(crate) rustc_tagger: Tagger,
+    pub(crate) cargo_tagger: Tagger,
+    /// Names of the rustc and cargo tags, where `{version}` is replaced with the released version.
+    /// Both default to PROMOTE_RELEASE_TAG_FORMAT, which defaults to `{version}`.
+    pub(crate) rustc_tag_format: String,
+    pub(crate) cargo_tag_format: String,
+
+    /// Repository, workflow file and branch of the "thanks" workflow to dispatch after tagging a
+    /// stable release. Setting the repository to an empty string disables the dispatch.
+    pub(crate) thanks_repository: String,
+    pub(crate) thanks_workflow: String,
+    pub(crate) thanks_branch: String,
 
     /// Where to publish new blog PRs.
     ///
@@ -174,6 +674,20 @@ pub(crate) struct Config {
     /// This is currently used for stable releases but in principle could be used for arbitrary
     /// releases.
     pub(crate) blog_pr: Option<u32>,
+    /// Path of the dev-static blog posts in the blog repository, where `{date}` is replaced with
+    /// today's date and `{version}` with the version being released.
+    pub(crate) blog_post_path: String,
+    /// Front matter of the dev-static blog posts, read from the file at
+    /// PROMOTE_RELEASE_BLOG_FRONT_MATTER_FILE if set. `{version}` is replaced with the version
+    /// being released.
+    pub(crate) blog_front_matter: String,
+
+    /// How long (in seconds) to wait for GitHub Pages to deploy the blog after merging the blog
+    /// PR, before giving up. The PR is already merged at that point, so the announcement on
+    /// Discourse will have to be posted manually.
+    pub(crate) blog_pages_timeout: u64,
+    /// How long (in seconds) to wait between checks of the GitHub Pages deployment status.
+    pub(crate) blog_pages_poll_interval: u64,
 
     /// The expected release date, for the blog post announcing dev-static
     /// releases. Expected to be in YYYY-MM-DD format.
@@ -182,6 +696,19 @@ pub(crate) struct Config {
     /// generate the release notes URL (targeting stable branch on
     /// rust-lang/rust).
     pub(crate) scheduled_release_date: Option<chrono::NaiveDate>,
+    /// Whether to check that the release notes linked from the dev-static announcements exist
+    /// before posting them, warning otherwise.
+    pub(crate) verify_release_notes_url: bool,
+    /// Whether to check that `RELEASES.md` on the stable branch of rust-lang/rust has a heading for
+    /// the release on `scheduled_release_date` before posting the dev-static announcements. A
+    /// heading with a different date fails the release, while a missing heading only warns.
+    pub(crate) check_releases_md: bool,
+    /// Date of the release, set with PROMOTE_RELEASE_DATE (or PROMOTE_RELEASE_ARCHIVE_DATE). It's
+    /// the date of the archive directory (`dist/YYYY-MM-DD/`) the release is published to, the
+    /// date embedded in the manifests, and the date of the announcements. Pinning it allows
+    /// resuming an interrupted release on a later day with byte-identical manifests. Defaults to
+    /// today, and is independent from `scheduled_release_date`.
+    pub(crate) archive_date: Option<chrono::NaiveDate>,
 
     /// These are Discourse configurations for where to post dev-static
     /// announcements. Currently we only post dev release announcements.
@@ -202,57 +729,284 @@ pub(crate) struct Config {
     /// The app ID associated with the private key being passed.
     pub(crate) github_app_id: Option<u32>,
 
+    /// Root URL of the GitHub API. Can be pointed to a GitHub Enterprise instance, or to a mock
+    /// server for testing.
+    pub(crate) github_api_base: String,
+
     /// An API token for Fastly with the `purge_select` scope.
     pub(crate) fastly_api_token: Option<String>,
     /// The Fastly service ID to purge when releasing.
     pub(crate) fastly_service_id: Option<String>,
+    /// Comma-separated `pattern=key` rules mapping the invalidated paths to the Fastly surrogate
+    /// keys to purge, where the first matching rule wins. Defaults to `/dist/*=dist,/doc/*=doc`.
+    pub(crate) fastly_surrogate_keys: Vec<SurrogateKey>,
+    /// Patterns of the invalidated paths (like `/dist/*`) to soft purge on Fastly, which marks
+    /// the cached content as stale rather than evicting it. Use this for the paths with the most
+    /// traffic, to avoid all the requests hitting the origin at once after the purge.
+    pub(crate) fastly_soft_purge_paths: Vec<String>,
 
     /// Temporary variable to test Fastly in the dev environment only.
     pub(crate) invalidate_fastly: bool,
+
+    /// Whether downloading from and uploading to the same bucket is intentional, silencing the
+    /// warning we'd otherwise emit at startup.
+    pub(crate) allow_same_bucket: bool,
+
+    /// URL to POST a JSON summary of the release (channel, version, date) to once it's done.
+    pub(crate) notify_webhook_url: Option<String>,
+    /// Whether to also notify the webhook when the release fails.
+    pub(crate) notify_on_failure: bool,
 }
 
 impl Config {
     pub(crate) fn from_env() -> Result<Self, Error> {
-        Ok(Self {
+        let channels: Vec<Channel> = list_env("CHANNEL")?;
+        let Some(&channel) = channels.first() else {
+            anyhow::bail!("missing environment variable CHANNEL");
+        };
+        let num_threads = default_env("NUM_THREADS", num_cpus::get())?;
+        let config = Self {
             action: default_env("ACTION", Action::PromoteRelease)?,
             bypass_startup_checks: bool_env("BYPASS_STARTUP_CHECKS")?,
-            channel: require_env("CHANNEL")?,
+            version_report: bool_env("VERSION_REPORT")?,
+            channel,
+            channels,
             cloudfront_doc_id: require_env("CLOUDFRONT_DOC_ID")?,
             cloudfront_static_id: require_env("CLOUDFRONT_STATIC_ID")?,
             download_bucket: require_env("DOWNLOAD_BUCKET")?,
             download_dir: require_env("DOWNLOAD_DIR")?,
-            gpg_key_file: require_env("GPG_KEY_FILE")?,
-            gpg_password_file: require_env("GPG_PASSWORD_FILE")?,
+            gpg_key: secret_env("GPG_KEY")?,
+            gpg_password: secret_env("GPG_PASSWORD")?,
+            signatures_log: maybe_env("SIGNATURES_LOG")?,
+            pkg_sign_command: maybe_env("PKG_SIGN_COMMAND")?,
+            gpg_hash_algorithm: default_env(
+                "GPG_HASH_ALGORITHM",
+                GpgHashAlgorithm(pgp::crypto::hash::HashAlgorithm::SHA2_512),
+            )?,
+            gpg_key_expiry_days: default_env("GPG_KEY_EXPIRY_DAYS", 30)?,
+            gpg_key_expiry_fatal: bool_env("GPG_KEY_EXPIRY_FATAL")?,
+            expected_gpg_fingerprint: maybe_env("EXPECTED_GPG_FINGERPRINT")?,
+            gpg_public_key_file: maybe_env("GPG_PUBLIC_KEY_FILE")?,
+            gpg_bucket_keys: list_env("GPG_BUCKET_KEYS")?,
             gzip_compression_level: default_env("GZIP_COMPRESSION_LEVEL", 9)?,
-            num_threads: default_env("NUM_THREADS", num_cpus::get())?,
+            build_manifest_path: maybe_env("BUILD_MANIFEST_PATH")?,
+            build_manifest_env: prefixed_env("BUILD_MANIFEST_ENV_")?,
+            num_threads,
+            sign_threads: default_env("SIGN_THREADS", num_threads)?,
+            memory_budget_mb: maybe_env("MEMORY_BUDGET_MB")?,
+            min_free_space_mb: default_env("MIN_FREE_SPACE_MB", 0)?,
             override_commit: maybe_env("OVERRIDE_COMMIT")?,
+            override_ref: maybe_env("CHANNEL_OVERRIDE_REF")?,
             repository: default_env("REPOSITORY", "https://github.com/rust-lang/rust.git".into())?,
+            repository_mirrors: list_env("REPOSITORY_MIRRORS")?,
+            incremental_docs: bool_env("INCREMENTAL_DOCS")?,
+            docs_tarball: bool_env("DOCS_TARBALL")?,
+            keep_artifacts: bool_env("KEEP_ARTIFACTS")?,
+            archive_only: bool_env("ARCHIVE_ONLY")?,
+            prune_dry_run: bool_env("PRUNE_DRY_RUN")?,
+            archive_retention_days: maybe_env("ARCHIVE_RETENTION_DAYS")?,
+            prune_archives_delete: bool_env("PRUNE_ARCHIVES_DELETE")?,
+            docs_version: maybe_env("DOCS_VERSION")?,
+            aws_cli: default_env("AWS_CLI", "aws".into())?,
             s3_endpoint_url: maybe_env("S3_ENDPOINT_URL")?,
+            s3_max_concurrent_requests: maybe_env("S3_MAX_CONCURRENT_REQUESTS")?,
+            s3_multipart_chunksize: maybe_env("S3_MULTIPART_CHUNKSIZE")?,
+            reuse_checksums: bool_env("REUSE_CHECKSUMS")?,
             skip_cloudfront_invalidations: bool_env("SKIP_CLOUDFRONT_INVALIDATIONS")?,
+            user_agent: default_env("USER_AGENT", crate::curl_helper::DEFAULT_USER_AGENT.into())?,
+            skip_smoke_test: bool_env("SKIP_SMOKE_TEST")?,
+            smoke_test_timeout: default_env("SMOKE_TEST_TIMEOUT", 5 * 60)?,
+            smoke_test_edition: maybe_env("SMOKE_TEST_EDITION")?,
+            smoke_test_dependency: maybe_env("SMOKE_TEST_DEPENDENCY")?,
+            smoke_test_offline: bool_env("SMOKE_TEST_OFFLINE")?,
+            smoke_test_vendor_dir: maybe_env("SMOKE_TEST_VENDOR_DIR")?,
+            smoke_test_xz_only: bool_env("SMOKE_TEST_XZ_ONLY")?,
             upload_addr: require_env("UPLOAD_ADDR")?,
             upload_bucket: require_env("UPLOAD_BUCKET")?,
+            extra_upload_buckets: list_env("EXTRA_UPLOAD_BUCKETS")?,
+            extra_upload_failures_fatal: bool_env("EXTRA_UPLOAD_FAILURES_FATAL")?,
             storage_class: default_env("UPLOAD_STORAGE_CLASS", "INTELLIGENT_TIERING".into())?,
             upload_dir: require_env("UPLOAD_DIR")?,
+            upload_latest_file: bool_env("UPLOAD_LATEST_FILE")?,
+            upload_release_info: bool_env("UPLOAD_RELEASE_INFO")?,
+            delta_manifest: bool_env("DELTA_MANIFEST")?,
             recompress_xz: bool_env("RECOMPRESS_XZ")?,
+            xz_parallel_files: default_env("XZ_PARALLEL_FILES", 0)?,
+            xz_parallel_threads: default_env("XZ_PARALLEL_THREADS", 4)?,
+            xz_preset: default_env("XZ_PRESET", 9)?,
+            xz_depth: default_env("XZ_DEPTH", 1000)?,
+            xz_nice_len: default_env("XZ_NICE_LEN", 273)?,
+            xz_match_finder: default_env("XZ_MATCH_FINDER", XzMatchFinder::BinaryTree4)?,
+            verify_recompression: bool_env("VERIFY_RECOMPRESSION")?,
+            recompress_stage: maybe_env("RECOMPRESS_STAGE")?,
+            required_components: list_env("REQUIRED_COMPONENTS")?,
+            exclude_targets: list_env("EXCLUDE_TARGETS")?,
+            min_tarball_size: default_env("MIN_TARBALL_SIZE", 1024)?,
+            small_tarballs_fatal: bool_env("SMALL_TARBALLS_FATAL")?,
+            max_total_size: maybe_env("MAX_TOTAL_SIZE")?,
+            max_size_growth_percent: maybe_env("MAX_SIZE_GROWTH_PERCENT")?,
             recompress_gz: bool_env("RECOMPRESS_GZ")?,
+            recompress_filter: maybe_env("RECOMPRESS_FILTER")?,
+            lf_only_patterns: list_env("LF_ONLY_PATTERNS")?,
+            strict_branching: bool_env("STRICT_BRANCHING")?,
             rustc_tag_repository: maybe_env("RUSTC_TAG_REPOSITORY")?,
             cargo_tag_repository: maybe_env("CARGO_TAG_REPOSITORY")?,
+            tag_include_commits: bool_env("TAG_INCLUDE_COMMITS")?,
+            rustc_tagger: default_env("RUSTC_TAGGER", Tagger::default())?,
+            cargo_tagger: default_env("CARGO_TAGGER", Tagger::default())?,
+            rustc_tag_format: maybe_env("RUSTC_TAG_FORMAT")?
+                .map_or_else(|| default_env("TAG_FORMAT", "{version}".into()), Ok)?,
+            cargo_tag_format: maybe_env("CARGO_TAG_FORMAT")?
+                .map_or_else(|| default_env("TAG_FORMAT", "{version}".into()), Ok)?,
+            thanks_repository: default_env("THANKS_REPO", "rust-lang/thanks".into())?,
+            thanks_workflow: default_env("THANKS_WORKFLOW", "ci.yml".into())?,
+            thanks_branch: default_env("THANKS_BRANCH", "master".into())?,
             blog_repository: maybe_env("BLOG_REPOSITORY")?,
             blog_pr: maybe_env("BLOG_MERGE_PR")?,
+            blog_post_path: default_env(
+                "BLOG_POST_PATH",
+                "posts/inside-rust/{date}-{version}-prerelease.md".into(),
+            )?,
+            blog_front_matter: match maybe_env::<String>("BLOG_FRONT_MATTER_FILE")? {
+                Some(path) => std::fs::read_to_string(&path)
+                    .with_context(|| format!("failed to read the blog front matter {}", path))?,
+                None => DEFAULT_BLOG_FRONT_MATTER.into(),
+            },
+            blog_pages_timeout: default_env("BLOG_PAGES_TIMEOUT", 20 * 60)?,
+            blog_pages_poll_interval: default_env("BLOG_PAGES_POLL_INTERVAL", 33)?,
             scheduled_release_date: maybe_env("BLOG_SCHEDULED_RELEASE_DATE")?,
+            verify_release_notes_url: bool_env("VERIFY_RELEASE_NOTES_URL")?,
+            check_releases_md: bool_env("CHECK_RELEASES_MD")?,
+            archive_date: match (maybe_env("DATE")?, maybe_env("ARCHIVE_DATE")?) {
+                (Some(date), Some(archive_date)) if date != archive_date => anyhow::bail!(
+                    "PROMOTE_RELEASE_DATE ({}) and PROMOTE_RELEASE_ARCHIVE_DATE ({}) differ",
+                    date,
+                    archive_date
+                ),
+                (date, archive_date) => date.or(archive_date),
+            },
             discourse_api_user: maybe_env("DISCOURSE_API_USER")?,
             discourse_api_key: maybe_env("DISCOURSE_API_KEY")?,
             github_app_key: maybe_env("GITHUB_APP_KEY")?,
             github_app_id: maybe_env("GITHUB_APP_ID")?,
+            github_api_base: default_env("GITHUB_API_BASE", "https://api.github.com".into())?,
             fastly_api_token: maybe_env("FASTLY_API_TOKEN")?,
             fastly_service_id: maybe_env("FASTLY_SERVICE_ID")?,
+            fastly_surrogate_keys: list_env("FASTLY_SURROGATE_KEYS")?,
+            fastly_soft_purge_paths: list_env("FASTLY_SOFT_PURGE_PATHS")?,
             invalidate_fastly: bool_env("INVALIDATE_FASTLY")?,
-        })
+            allow_same_bucket: bool_env("ALLOW_SAME_BUCKET")?,
+            notify_webhook_url: maybe_env("NOTIFY_WEBHOOK_URL")?,
+            notify_on_failure: bool_env("NOTIFY_ON_FAILURE")?,
+        };
+        if config.channels.len() > 1 && config.action != Action::PromoteRelease {
+            anyhow::bail!("only the promote-release action can release multiple channels");
+        }
+        config.check_single_channel_overrides()?;
+        config.check_buckets();
+        config.check_build_manifest_path()?;
+        config.check_xz_tuning()?;
+        for format in [&config.rustc_tag_format, &config.cargo_tag_format] {
+            tag_name(format, "1.0.0")?;
+        }
+        for target in ["x86_64-unknown-linux-gnu", crate::TARGET] {
+            if config
+                .exclude_targets
+                .iter()
+                .any(|excluded| excluded == target)
+            {
+                anyhow::bail!("the {} target can't be excluded from the release", target);
+            }
+        }
+        Ok(config)
+    }
+
+    /// When to recompress the tarballs of the channel we're currently releasing.
+    pub(crate) fn recompress_stage(&self) -> RecompressStage {
+        self.recompress_stage
+            .unwrap_or_else(|| RecompressStage::default_for(self.channel))
+    }
+
+    /// The overrides of a single release would apply to every channel when releasing several of
+    /// them, for example releasing nightly's commit on beta and stable too.
+    fn check_single_channel_overrides(&self) -> Result<(), Error> {
+        if self.channels.len() < 2 {
+            return Ok(());
+        }
+        let overrides = [
+            ("OVERRIDE_COMMIT", self.override_commit.is_some()),
+            ("CHANNEL_OVERRIDE_REF", self.override_ref.is_some()),
+            ("BLOG_MERGE_PR", self.blog_pr.is_some()),
+            (
+                "BLOG_SCHEDULED_RELEASE_DATE",
+                self.scheduled_release_date.is_some(),
+            ),
+        ];
+        for (name, set) in overrides {
+            if set {
+                anyhow::bail!(
+                    "{}{} can't be set when releasing multiple channels",
+                    ENVIRONMENT_VARIABLE_PREFIX,
+                    name
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn check_build_manifest_path(&self) -> Result<(), Error> {
+        use std::os::unix::fs::PermissionsExt;
+
+        if let Some(path) = &self.build_manifest_path {
+            let metadata = std::fs::metadata(path)
+                .with_context(|| format!("failed to access the build-manifest binary {}", path))?;
+            if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+                anyhow::bail!("the build-manifest binary {} is not executable", path);
+            }
+        }
+        Ok(())
+    }
+
+    fn check_xz_tuning(&self) -> Result<(), Error> {
+        if self.xz_preset > 9 {
+            anyhow::bail!("the xz preset must be between 0 and 9: {}", self.xz_preset);
+        }
+        if self.xz_depth > MAX_XZ_DEPTH {
+            anyhow::bail!(
+                "the xz depth must be between 0 and {}: {}",
+                MAX_XZ_DEPTH,
+                self.xz_depth
+            );
+        }
+        let min_nice_len = self.xz_match_finder.min_nice_len();
+        if !(min_nice_len..=273).contains(&self.xz_nice_len) {
+            anyhow::bail!(
+                "the xz nice length must be between {} and 273 with the {} match finder: {}",
+                min_nice_len,
+                self.xz_match_finder,
+                self.xz_nice_len
+            );
+        }
+        Ok(())
+    }
+
+    /// In the normal rust-lang topology CI artifacts and releases live in different buckets, so
+    /// using the same bucket for both is most likely a misconfiguration.
+    fn check_buckets(&self) {
+        if self.download_bucket == self.upload_bucket && !self.allow_same_bucket {
+            println!();
+            println!(
+                "WARNING! The download and upload buckets are the same ({})",
+                self.upload_bucket
+            );
+            println!("Set PROMOTE_RELEASE_ALLOW_SAME_BUCKET=1 if this is intentional");
+            println!();
+        }
     }
 
     pub(crate) fn github(&self) -> Option<Github> {
         if let (Some(key), Some(id)) = (&self.github_app_key, self.github_app_id) {
-            Some(Github::new(key, id))
+            Some(Github::new(key, id, &self.github_api_base))
         } else {
             None
         }
@@ -271,43 +1025,58 @@ impl Config {
 
     pub(crate) fn fastly(&self) -> Option<Fastly> {
         if let (Some(token), Some(service_id)) = (&self.fastly_api_token, &self.fastly_service_id) {
-            Some(Fastly::new(token.clone(), service_id.clone()))
+            Some(Fastly::new(
+                token.clone(),
+                service_id.clone(),
+                &self.fastly_surrogate_keys,
+            ))
         } else {
             None
         }
     }
 
+    pub(crate) fn dev_static_release_notes_url(release: &str) -> String {
+        // Can't use `doc.rust-lang.org`, because the release hasn't been promoted yet.
+        format!("https://dev-doc.rust-lang.org/{release}/releases.html")
+    }
+
     pub(crate) fn stable_dev_static_blog_contents(
         &self,
         release: &str,
         archive_date: &str,
         for_blog: bool,
         internals_url: Option<&str>,
+        changes: &[VersionChange],
     ) -> Option<String> {
         let scheduled_release_date = self.scheduled_release_date?;
-        // Can't use `doc.rust-lang.org`, because the release hasn't been promoted yet.
-        let release_notes_url = format!("https://dev-doc.rust-lang.org/{release}/releases.html");
+        let release_notes_url = Self::dev_static_release_notes_url(release);
         let human_date = scheduled_release_date.format("%B %-d");
         let internals = internals_url
             .map(|url| format!("You can leave feedback on the [internals thread]({url})."))
             .unwrap_or_default();
+        let mut changes_table = String::new();
+        if !changes.is_empty() {
+            changes_table.push_str(
+                "\nNotable version changes since the previous release:\n\n\
+                 | Component | Previous | New |\n\
+                 |-----------|----------|-----|\n",
+            );
+            for change in changes {
+                changes_table.push_str(&format!(
+                    "| {} | {} | {} |\n",
+                    change.name, change.previous, change.current
+                ));
+            }
+        }
         let prefix = if for_blog {
-            format!(
-                r#"---
-layout: post
-title: "{} pre-release testing"
-author: Release automation
-team: The Release Team <https://www.rust-lang.org/governance/teams/release>
----{}"#,
-                release, "\n\n",
-            )
+            render_template(&self.blog_front_matter, &[("version", release)])
         } else {
             String::new()
         };
         Some(format!(
             "{prefix}The {release} pre-release is ready for testing. The release is scheduled for
 {human_date}. [Release notes can be found here.][relnotes]
-
+{changes_table}
 You can try it out locally by running:
 
 ```plain
@@ -328,12 +1097,104 @@ we'd love your feedback [on this GitHub issue][feedback].
     }
 }
 
+/// Replaces every `{name}` in the template with the corresponding value.
+pub(crate) fn render_template(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |rendered, (name, value)| {
+            rendered.replace(&format!("{{{}}}", name), value)
+        })
+}
+
+/// Renders the name of the tag of `version`, ensuring it's a valid git ref name.
+pub(crate) fn tag_name(format: &str, version: &str) -> Result<String, Error> {
+    let name = render_template(format, &[("version", version)]);
+    // See `git help check-ref-format`.
+    let invalid = name.is_empty()
+        || name == "@"
+        || name.starts_with('-')
+        || name.ends_with('/')
+        || name.ends_with('.')
+        || name.ends_with(".lock")
+        || name.contains("..")
+        || name.contains("@{")
+        || name.chars().any(|c| {
+            c.is_ascii_control() || matches!(c, ' ' | '~' | '^' | ':' | '?' | '*' | '[' | '\\')
+        })
+        || name
+            .split('/')
+            .any(|component| component.is_empty() || component.starts_with('.'));
+    if invalid {
+        anyhow::bail!("{:?} is not a valid tag name (from {:?})", name, format);
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Environment variables seen by `Config::from_env` in tests. Tests can't use the real
+    /// environment, as it's shared by all the tests running in parallel.
+    static TEST_ENV: std::cell::RefCell<std::collections::HashMap<String, String>> =
+        Default::default();
+}
+
+#[cfg(test)]
+impl Config {
+    /// Creates a configuration from the given environment variables (without the
+    /// `PROMOTE_RELEASE_` prefix), on top of the variables required by `from_env`.
+    pub(crate) fn for_tests(vars: &[(&str, &str)]) -> Self {
+        let defaults = [
+            ("CHANNEL", "nightly"),
+            ("CLOUDFRONT_DOC_ID", "doc"),
+            ("CLOUDFRONT_STATIC_ID", "static"),
+            ("DOWNLOAD_BUCKET", "artifacts"),
+            ("DOWNLOAD_DIR", "builds"),
+            ("GPG_KEY_FILE", "/dev/null"),
+            ("GPG_PASSWORD_FILE", "/dev/null"),
+            ("UPLOAD_ADDR", "http://localhost:9000/static"),
+            ("UPLOAD_BUCKET", "static"),
+            ("UPLOAD_DIR", "dist"),
+        ];
+        TEST_ENV.with(|env| {
+            let mut env = env.borrow_mut();
+            env.clear();
+            for (name, value) in defaults.iter().chain(vars) {
+                env.insert(
+                    format!("{}{}", ENVIRONMENT_VARIABLE_PREFIX, name),
+                    value.to_string(),
+                );
+            }
+        });
+        Config::from_env().unwrap()
+    }
+}
+
+/// Returns the environment variables of the process, or `TEST_ENV` in tests.
+fn env_vars() -> Vec<(OsString, OsString)> {
+    #[cfg(test)]
+    return TEST_ENV.with(|env| {
+        env.borrow()
+            .iter()
+            .map(|(name, value)| (name.into(), value.into()))
+            .collect()
+    });
+    #[cfg(not(test))]
+    return std::env::vars_os().collect();
+}
+
+fn env_var(name: &str) -> Result<String, VarError> {
+    match env_vars().into_iter().find(|(var, _)| var == name) {
+        Some((_, value)) => value.into_string().map_err(VarError::NotUnicode),
+        None => Err(VarError::NotPresent),
+    }
+}
+
 fn maybe_env<R>(name: &str) -> Result<Option<R>, Error>
 where
     R: FromStr,
     Error: From<R::Err>,
 {
-    match std::env::var(format!("{}{}", ENVIRONMENT_VARIABLE_PREFIX, name)) {
+    match env_var(&format!("{}{}", ENVIRONMENT_VARIABLE_PREFIX, name)) {
         Ok(val) => Ok(Some(val.parse().map_err(Error::from).context(format!(
             "the {} environment variable has invalid content",
             name
@@ -345,6 +1206,28 @@ where
     }
 }
 
+/// Variables read with `secret_env`.
+const SECRET_VARIABLES: &[&str] = &["GPG_KEY", "GPG_PASSWORD"];
+
+/// Removes the secrets passed directly in variables from the environment of the process, as the
+/// commands we spawn don't need them. This must happen after `Config::from_env` read them.
+pub(crate) fn remove_secret_vars() {
+    for name in SECRET_VARIABLES {
+        std::env::remove_var(format!("{}{}", ENVIRONMENT_VARIABLE_PREFIX, name));
+    }
+}
+
+/// Reads the secret passed in the `name` variable, or stored in the file at `{name}_FILE`.
+fn secret_env(name: &str) -> Result<Secret, Error> {
+    let file_name = format!("{}_FILE", name);
+    match (maybe_env(name)?, maybe_env(&file_name)?) {
+        (Some(value), None) => Ok(Secret::Value(value)),
+        (None, Some(path)) => Ok(Secret::File(path)),
+        (Some(_), Some(_)) => anyhow::bail!("only one of {} and {} can be set", name, file_name),
+        (None, None) => anyhow::bail!("missing environment variable {} or {}", name, file_name),
+    }
+}
+
 fn require_env<R>(name: &str) -> Result<R, Error>
 where
     R: FromStr,
@@ -364,6 +1247,293 @@ where
     Ok(maybe_env(name)?.unwrap_or(default))
 }
 
+fn list_env<R>(name: &str) -> Result<Vec<R>, Error>
+where
+    R: FromStr,
+    Error: From<R::Err>,
+{
+    maybe_env::<String>(name)?
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            item.parse().map_err(Error::from).context(format!(
+                "the {} environment variable has invalid content",
+                name
+            ))
+        })
+        .collect()
+}
+
+/// Returns all the variables starting with `prefix`, with the prefix removed from their name.
+fn prefixed_env(prefix: &str) -> Result<Vec<(String, String)>, Error> {
+    let prefix = format!("{}{}", ENVIRONMENT_VARIABLE_PREFIX, prefix);
+    let vars = env_vars()
+        .into_iter()
+        // Variables with non-unicode names can't start with our prefix.
+        .filter_map(|(name, value)| Some((name.into_string().ok()?, value)))
+        .filter(|(name, _)| name.starts_with(&prefix))
+        .map(|(name, value)| match value.into_string() {
+            Ok(value) => Ok((name, value)),
+            Err(_) => anyhow::bail!("environment variable {} is not unicode!", name),
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let mut result = vars
+        .into_iter()
+        .filter_map(|(name, value)| Some((name.strip_prefix(&prefix)?.to_string(), value)))
+        .filter(|(name, _)| !name.is_empty())
+        .collect::<Vec<_>>();
+    result.sort();
+    Ok(result)
+}
+
 fn bool_env(name: &str) -> Result<bool, Error> {
     Ok(maybe_env::<String>(name)?.is_some())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_blog_front_matter() {
+        assert_eq!(
+            "---
+layout: post
+title: \"1.70.0 pre-release testing\"
+author: Release automation
+team: The Release Team <https://www.rust-lang.org/governance/teams/release>
+---
+
+",
+            render_template(DEFAULT_BLOG_FRONT_MATTER, &[("version", "1.70.0")])
+        );
+    }
+
+    #[test]
+    fn build_manifest_env() {
+        let config = Config::for_tests(&[
+            ("BUILD_MANIFEST_ENV_BUILD_MANIFEST_FOO", "1"),
+            ("BUILD_MANIFEST_ENV_", "ignored"),
+            ("BUILD_MANIFEST_PATH_", "ignored"),
+        ]);
+        assert_eq!(
+            vec![("BUILD_MANIFEST_FOO".to_string(), "1".to_string())],
+            config.build_manifest_env
+        );
+    }
+
+    #[test]
+    fn release_date() {
+        let date = chrono::NaiveDate::from_ymd_opt(2023, 6, 1);
+        assert_eq!(None, Config::for_tests(&[]).archive_date);
+        assert_eq!(
+            date,
+            Config::for_tests(&[("DATE", "2023-06-01")]).archive_date
+        );
+        assert_eq!(
+            date,
+            Config::for_tests(&[("ARCHIVE_DATE", "2023-06-01")]).archive_date
+        );
+        TEST_ENV.with(|env| {
+            let mut env = env.borrow_mut();
+            env.insert("PROMOTE_RELEASE_DATE".into(), "2023-06-02".into());
+        });
+        assert!(Config::from_env().is_err());
+    }
+
+    #[test]
+    fn tag_names() {
+        assert_eq!("1.70.0", tag_name("{version}", "1.70.0").unwrap());
+        assert_eq!("v1.70.0", tag_name("v{version}", "1.70.0").unwrap());
+        assert_eq!(
+            "release/1.70.0",
+            tag_name("release/{version}", "1.70.0").unwrap()
+        );
+        for invalid in [
+            "",
+            "{version}.",
+            "v {version}",
+            "/{version}",
+            "{version}.lock",
+        ] {
+            assert!(tag_name(invalid, "1.70.0").is_err(), "{}", invalid);
+        }
+
+        let config = Config::for_tests(&[("TAG_FORMAT", "v{version}")]);
+        assert_eq!("v{version}", config.rustc_tag_format);
+        assert_eq!("v{version}", config.cargo_tag_format);
+        let config = Config::for_tests(&[
+            ("TAG_FORMAT", "v{version}"),
+            ("CARGO_TAG_FORMAT", "{version}"),
+        ]);
+        assert_eq!("v{version}", config.rustc_tag_format);
+        assert_eq!("{version}", config.cargo_tag_format);
+        TEST_ENV.with(|env| {
+            let mut env = env.borrow_mut();
+            env.insert(
+                "PROMOTE_RELEASE_RUSTC_TAG_FORMAT".into(),
+                "v:{version}".into(),
+            );
+        });
+        assert!(Config::from_env().is_err());
+    }
+
+    #[test]
+    fn blog_version_changes() {
+        let config = Config::for_tests(&[("BLOG_SCHEDULED_RELEASE_DATE", "2023-06-01")]);
+        let changes = [VersionChange {
+            name: "Cargo",
+            previous: "1.69.0".into(),
+            current: "1.70.0".into(),
+        }];
+        let contents = config
+            .stable_dev_static_blog_contents("1.70.0", "2023-05-30", false, None, &changes)
+            .unwrap();
+        assert!(contents.contains(
+            "here.][relnotes]\n\n\
+             Notable version changes since the previous release:\n\n\
+             | Component | Previous | New |\n\
+             |-----------|----------|-----|\n\
+             | Cargo | 1.69.0 | 1.70.0 |\n\n\
+             You can try it out"
+        ));
+
+        let contents = config
+            .stable_dev_static_blog_contents("1.70.0", "2023-05-30", false, None, &[])
+            .unwrap();
+        assert!(contents.contains("here.][relnotes]\n\nYou can try it out"));
+    }
+
+    #[test]
+    fn recompress_stage() {
+        assert_eq!(
+            RecompressStage::AfterPruning,
+            Config::for_tests(&[]).recompress_stage()
+        );
+        assert_eq!(
+            RecompressStage::BeforePruning,
+            Config::for_tests(&[("CHANNEL", "beta")]).recompress_stage()
+        );
+        assert_eq!(
+            RecompressStage::Never,
+            Config::for_tests(&[("RECOMPRESS_STAGE", "never")]).recompress_stage()
+        );
+
+        // The default depends on the channel currently being released.
+        let mut config = Config::for_tests(&[("CHANNEL", "nightly,beta")]);
+        assert_eq!(vec![Channel::Nightly, Channel::Beta], config.channels);
+        config.channel = Channel::Beta;
+        assert_eq!(RecompressStage::BeforePruning, config.recompress_stage());
+    }
+
+    #[test]
+    fn multiple_channel_overrides() {
+        Config::for_tests(&[("CHANNEL", "nightly,beta")]);
+        Config::for_tests(&[("OVERRIDE_COMMIT", "abc123")]);
+        for (name, value) in [
+            ("OVERRIDE_COMMIT", "abc123"),
+            ("CHANNEL_OVERRIDE_REF", "refs/heads/test"),
+            ("BLOG_MERGE_PR", "42"),
+            ("BLOG_SCHEDULED_RELEASE_DATE", "2023-06-01"),
+        ] {
+            Config::for_tests(&[]);
+            TEST_ENV.with(|env| {
+                let mut env = env.borrow_mut();
+                env.insert("PROMOTE_RELEASE_CHANNEL".into(), "nightly,beta".into());
+                env.insert(format!("PROMOTE_RELEASE_{}", name), value.into());
+            });
+            assert!(Config::from_env().is_err(), "{} was accepted", name);
+        }
+    }
+
+    #[test]
+    fn parse_upload_mirror() {
+        assert_eq!(
+            UploadMirror {
+                bucket: "mirror-bucket".into(),
+                dir: "dist".into(),
+                endpoint_url: None,
+            },
+            "mirror-bucket/dist".parse().unwrap()
+        );
+        assert_eq!(
+            UploadMirror {
+                bucket: "mirror-bucket".into(),
+                dir: "dist".into(),
+                endpoint_url: Some("https://s3.example.com".into()),
+            },
+            "mirror-bucket/dist/@https://s3.example.com"
+                .parse()
+                .unwrap()
+        );
+        assert!("mirror-bucket".parse::<UploadMirror>().is_err());
+        assert!("/dist".parse::<UploadMirror>().is_err());
+    }
+
+    #[test]
+    fn parse_tagger() {
+        assert_eq!(
+            Tagger {
+                name: "Release Bot".into(),
+                email: "bot@example.com".into(),
+            },
+            "Release Bot <bot@example.com>".parse().unwrap()
+        );
+        assert_eq!(Tagger::default(), Config::for_tests(&[]).cargo_tagger,);
+        assert!("bot@example.com".parse::<Tagger>().is_err());
+        assert!("<bot@example.com>".parse::<Tagger>().is_err());
+    }
+
+    #[test]
+    fn gpg_secrets() {
+        let config = Config::for_tests(&[]);
+        assert!(matches!(&config.gpg_key, Secret::File(path) if path == "/dev/null"));
+        assert_eq!("", config.gpg_password.read().unwrap());
+
+        TEST_ENV.with(|env| {
+            let mut env = env.borrow_mut();
+            env.remove("PROMOTE_RELEASE_GPG_PASSWORD_FILE");
+            env.insert("PROMOTE_RELEASE_GPG_PASSWORD".into(), "hunter2".into());
+        });
+        assert_eq!(
+            "hunter2",
+            secret_env("GPG_PASSWORD").unwrap().read().unwrap()
+        );
+
+        TEST_ENV.with(|env| {
+            let mut env = env.borrow_mut();
+            env.insert(
+                "PROMOTE_RELEASE_GPG_PASSWORD_FILE".into(),
+                "/dev/null".into(),
+            );
+        });
+        assert!(secret_env("GPG_PASSWORD").is_err());
+    }
+
+    #[test]
+    fn xz_tuning() {
+        let mut config = Config::for_tests(&[("XZ_MATCH_FINDER", "hc3"), ("XZ_NICE_LEN", "3")]);
+        assert_eq!(XzMatchFinder::HashChain3, config.xz_match_finder);
+        assert_eq!(1000, config.xz_depth);
+        config.check_xz_tuning().unwrap();
+
+        config.xz_nice_len = 2;
+        assert!(config.check_xz_tuning().is_err());
+        config.xz_match_finder = XzMatchFinder::BinaryTree2;
+        config.check_xz_tuning().unwrap();
+        config.xz_nice_len = 274;
+        assert!(config.check_xz_tuning().is_err());
+        config.xz_nice_len = 273;
+        config.xz_preset = 10;
+        assert!(config.check_xz_tuning().is_err());
+        config.xz_preset = 9;
+        config.xz_depth = 0;
+        config.check_xz_tuning().unwrap();
+        config.xz_depth = MAX_XZ_DEPTH + 1;
+        assert!(config.check_xz_tuning().is_err());
+        assert!("bt5".parse::<XzMatchFinder>().is_err());
+    }
+}
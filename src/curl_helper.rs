@@ -1,5 +1,64 @@
 use anyhow::Context;
-use curl::easy::Easy;
+use curl::easy::{Easy, List};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
+
+/// User agent of the HTTP requests unless PROMOTE_RELEASE_USER_AGENT is set.
+pub(crate) const DEFAULT_USER_AGENT: &str = "rust-lang/promote-release";
+
+/// Number of times a rate limited request is retried before giving up.
+const RATE_LIMIT_RETRIES: u32 = 3;
+
+/// Longest we wait for a rate limit to reset. Past that we'd rather fail the announcement than
+/// stall the rest of the release.
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(15 * 60);
+
+/// When fewer requests than this are left before hitting the rate limit, we wait for the limit to
+/// reset before sending the next request.
+const RATE_LIMIT_LOW_WATERMARK: u64 = 5;
+
+/// Wait used when a rate limited response doesn't say when to retry.
+const DEFAULT_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+static USER_AGENT: OnceLock<String> = OnceLock::new();
+static RUN_ID: OnceLock<String> = OnceLock::new();
+
+/// Sets the user agent of all the HTTP requests, which can only happen once at startup.
+pub fn set_user_agent(user_agent: &str) -> anyhow::Result<()> {
+    USER_AGENT
+        .set(user_agent.to_string())
+        .map_err(|_| anyhow::anyhow!("the user agent was already set"))
+}
+
+/// Random identifier of this run (formatted as a UUID v4), sent with all the HTTP requests so
+/// that the requests of a failed release can be found in the logs of the services we call.
+pub fn run_id() -> &'static str {
+    RUN_ID.get_or_init(|| {
+        let mut bytes: [u8; 16] = rand::random();
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        let hex = hex::encode(bytes);
+        format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    })
+}
+
+/// Sets the user agent of `client`, and adds the run ID to the `headers` of the request.
+pub fn identify(client: &mut Easy, headers: &mut List) -> anyhow::Result<()> {
+    let user_agent = USER_AGENT
+        .get()
+        .map_or(DEFAULT_USER_AGENT, |ua| ua.as_str());
+    client.useragent(&format!("{} (run {})", user_agent, run_id()))?;
+    headers.append(&format!("X-Request-Id: {}", run_id()))?;
+    Ok(())
+}
 
 pub trait BodyExt {
     fn with_body<S>(&mut self, body: S) -> Request<'_, S>;
@@ -28,41 +87,210 @@ pub struct Request<'a, S> {
 
 impl<S: serde::Serialize> Request<'_, S> {
     pub fn send_with_response<T: serde::de::DeserializeOwned>(self) -> anyhow::Result<T> {
-        use std::io::Read;
         let mut response = Vec::new();
         let body = self.body.map(|body| serde_json::to_vec(&body).unwrap());
-        {
-            let mut transfer = self.client.transfer();
-            // The unwrap in the read_function is basically guaranteed to not
-            // happen: reading into a slice can't fail. We can't use `?` since the
-            // return type inside transfer isn't compatible with io::Error.
-            if let Some(mut body) = body.as_deref() {
-                transfer.read_function(move |dest| Ok(body.read(dest).unwrap()))?;
-            }
-            transfer.write_function(|new_data| {
-                response.extend_from_slice(new_data);
-                Ok(new_data.len())
-            })?;
-            transfer.perform()?;
-        }
+        perform(self.client, body.as_deref(), Some(&mut response))?;
         serde_json::from_slice(&response)
             .with_context(|| format!("{}", String::from_utf8_lossy(&response)))
     }
 
     pub fn send(self) -> anyhow::Result<()> {
-        use std::io::Read;
         let body = self.body.map(|body| serde_json::to_vec(&body).unwrap());
+        perform(self.client, body.as_deref(), None)
+    }
+}
+
+/// Performs the request, waiting and retrying when it's rejected by a rate limit, and waiting
+/// for the rate limit to reset when it's about to be hit.
+fn perform(
+    client: &mut Easy,
+    body: Option<&[u8]>,
+    mut response: Option<&mut Vec<u8>>,
+) -> anyhow::Result<()> {
+    use std::io::Read;
+    for attempt in 0.. {
+        let mut headers = HashMap::new();
+        if let Some(response) = response.as_deref_mut() {
+            response.clear();
+        }
         {
-            let mut transfer = self.client.transfer();
+            let mut transfer = client.transfer();
             // The unwrap in the read_function is basically guaranteed to not
             // happen: reading into a slice can't fail. We can't use `?` since the
             // return type inside transfer isn't compatible with io::Error.
-            if let Some(mut body) = body.as_deref() {
+            if let Some(mut body) = body {
                 transfer.read_function(move |dest| Ok(body.read(dest).unwrap()))?;
             }
+            if let Some(response) = response.as_deref_mut() {
+                transfer.write_function(move |new_data| {
+                    response.extend_from_slice(new_data);
+                    Ok(new_data.len())
+                })?;
+            }
+            transfer.header_function(|line| {
+                record_header(&mut headers, line);
+                true
+            })?;
             transfer.perform()?;
         }
 
-        Ok(())
+        let url = client.effective_url()?.unwrap_or_default().to_string();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs();
+        match rate_limit(client.response_code()?, &headers, now) {
+            RateLimit::None => {}
+            RateLimit::Low(wait) if wait > MAX_RATE_LIMIT_WAIT => {
+                println!(
+                    "WARNING! almost rate limited by {}, and the limit resets in {}s",
+                    url,
+                    wait.as_secs()
+                );
+            }
+            RateLimit::Low(wait) => {
+                println!(
+                    "almost rate limited by {}, waiting {}s for the limit to reset",
+                    url,
+                    wait.as_secs()
+                );
+                std::thread::sleep(wait);
+            }
+            RateLimit::Limited(wait) => {
+                if wait > MAX_RATE_LIMIT_WAIT {
+                    anyhow::bail!(
+                        "rate limited by {} for {}s, longer than the {}s we're willing to wait",
+                        url,
+                        wait.as_secs(),
+                        MAX_RATE_LIMIT_WAIT.as_secs()
+                    );
+                }
+                if attempt >= RATE_LIMIT_RETRIES {
+                    anyhow::bail!(
+                        "still rate limited by {} after {} retries",
+                        url,
+                        RATE_LIMIT_RETRIES
+                    );
+                }
+                println!("rate limited by {}, retrying in {}s", url, wait.as_secs());
+                std::thread::sleep(wait);
+                continue;
+            }
+        }
+        break;
+    }
+    Ok(())
+}
+
+/// Records a raw response header line in `headers`, with the name lowercased. The headers of
+/// previous responses (redirects, `100 Continue`) are discarded when a new status line arrives.
+fn record_header(headers: &mut HashMap<String, String>, line: &[u8]) {
+    let line = String::from_utf8_lossy(line);
+    if line.starts_with("HTTP/") {
+        headers.clear();
+    } else if let Some((name, value)) = line.split_once(':') {
+        headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum RateLimit {
+    None,
+    /// The request went through, but the next ones will be rejected until the limit resets.
+    Low(Duration),
+    /// The request was rejected, and has to be retried after waiting.
+    Limited(Duration),
+}
+
+/// Interprets the rate limit headers of a response, where `now` is the current UNIX timestamp.
+/// GitHub sends `X-RateLimit-Remaining` and `X-RateLimit-Reset` with every response (and
+/// rejects with a 403 or 429 once the limit is hit), while Discourse rejects with a 429 and a
+/// `Retry-After` header.
+fn rate_limit(status: u32, headers: &HashMap<String, String>, now: u64) -> RateLimit {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.parse::<u64>().ok())
+    };
+    let remaining = header("x-ratelimit-remaining");
+    let until_reset =
+        header("x-ratelimit-reset").map(|reset| Duration::from_secs(reset.saturating_sub(now) + 1));
+    let retry_after = header("retry-after").map(Duration::from_secs);
+
+    if status == 429 || (status == 403 && remaining == Some(0)) {
+        RateLimit::Limited(
+            retry_after
+                .or(until_reset)
+                .unwrap_or(DEFAULT_RATE_LIMIT_WAIT),
+        )
+    } else if status < 400 && remaining.is_some_and(|r| r < RATE_LIMIT_LOW_WATERMARK) {
+        match until_reset {
+            Some(wait) => RateLimit::Low(wait),
+            None => RateLimit::None,
+        }
+    } else {
+        RateLimit::None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_id_is_a_uuid() {
+        let id = run_id();
+        assert_eq!(id, run_id());
+        let groups = id.split('-').map(str::len).collect::<Vec<_>>();
+        assert_eq!(vec![8, 4, 4, 4, 12], groups);
+        assert_eq!(Some('4'), id.chars().nth(14));
+        assert!("89ab".contains(id.chars().nth(19).unwrap()));
+    }
+
+    #[test]
+    fn rate_limits() {
+        let now = 1_700_000_000;
+        let headers = |lines: &[&str]| {
+            let mut headers = HashMap::new();
+            record_header(&mut headers, b"HTTP/1.1 301 Moved Permanently\r\n");
+            record_header(&mut headers, b"Retry-After: 1\r\n");
+            record_header(&mut headers, b"HTTP/2 200\r\n");
+            for line in lines {
+                record_header(&mut headers, line.as_bytes());
+            }
+            headers
+        };
+        let secs = Duration::from_secs;
+
+        let github = headers(&[
+            "X-RateLimit-Remaining: 4000\r\n",
+            "X-RateLimit-Reset: 1700000100\r\n",
+        ]);
+        assert_eq!(RateLimit::None, rate_limit(200, &github, now));
+        // Errors unrelated to the rate limit are left to the caller.
+        assert_eq!(RateLimit::None, rate_limit(403, &github, now));
+
+        let github = headers(&[
+            "x-ratelimit-remaining: 2\r\n",
+            "x-ratelimit-reset: 1700000100\r\n",
+        ]);
+        assert_eq!(RateLimit::Low(secs(101)), rate_limit(201, &github, now));
+
+        let github = headers(&[
+            "X-RateLimit-Remaining: 0\r\n",
+            "X-RateLimit-Reset: 1700000100\r\n",
+        ]);
+        assert_eq!(RateLimit::Limited(secs(101)), rate_limit(403, &github, now));
+        assert_eq!(RateLimit::Limited(secs(101)), rate_limit(429, &github, now));
+
+        let discourse = headers(&["Retry-After: 42\r\n"]);
+        assert_eq!(
+            RateLimit::Limited(secs(42)),
+            rate_limit(429, &discourse, now)
+        );
+        assert_eq!(RateLimit::None, rate_limit(200, &discourse, now));
+        assert_eq!(
+            RateLimit::Limited(DEFAULT_RATE_LIMIT_WAIT),
+            rate_limit(429, &headers(&[]), now)
+        );
     }
 }
@@ -0,0 +1,216 @@
+//! Delta manifests, listing which components changed since the previous release of the channel.
+//!
+//! This is a first step towards faster rustup updates on slow connections: a client that already
+//! has the previous release installed only needs to download the changed components. The delta
+//! is published next to the channel manifest as `channel-rust-$channel-delta.toml`, and its format
+//! is expected to evolve together with its consumers.
+
+use crate::validate::package_version;
+use crate::Context;
+use anyhow::Error;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Default, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Delta<'a> {
+    date: Option<&'a str>,
+    previous_date: Option<&'a str>,
+    /// Components whose version changed, which have to be downloaded again.
+    changed: Vec<&'a str>,
+    /// Components with the same version, which can be kept from the previous release.
+    unchanged: Vec<&'a str>,
+    added: Vec<&'a str>,
+    removed: Vec<&'a str>,
+}
+
+impl Context {
+    /// Writes the delta between the `previous` live manifest and the channel manifest generated in
+    /// `dir` next to it, if enabled with PROMOTE_RELEASE_DELTA_MANIFEST.
+    pub(crate) fn write_delta_manifest(
+        &self,
+        dir: &Path,
+        previous: &toml::Value,
+    ) -> Result<(), Error> {
+        if !self.config.delta_manifest {
+            return Ok(());
+        }
+
+        let channel = self.config.channel;
+        let manifest: toml::Value =
+            fs::read_to_string(dir.join(format!("channel-rust-{}.toml", channel)))?.parse()?;
+        let delta = delta(previous, &manifest);
+        println!(
+            "{} components changed since the previous release, {} unchanged",
+            delta.changed.len(),
+            delta.unchanged.len()
+        );
+        fs::write(
+            dir.join(format!("channel-rust-{}-delta.toml", channel)),
+            toml::to_string(&delta)?,
+        )?;
+        Ok(())
+    }
+}
+
+/// Components whose version bumps are worth mentioning in the pre-release announcements, with
+/// their display names.
+const NOTABLE_COMPONENTS: &[(&str, &str)] = &[
+    ("rust", "Rust"),
+    ("cargo", "Cargo"),
+    ("clippy-preview", "Clippy"),
+    ("rustfmt-preview", "rustfmt"),
+    ("rust-analyzer-preview", "rust-analyzer"),
+];
+
+/// A notable component whose version changed since the previous release.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct VersionChange {
+    pub(crate) name: &'static str,
+    pub(crate) previous: String,
+    pub(crate) current: String,
+}
+
+/// Returns the notable components whose version changed between the `previous` and `current`
+/// manifests, ignoring the commit and date in the version strings.
+pub(crate) fn version_changes(previous: &toml::Value, current: &toml::Value) -> Vec<VersionChange> {
+    let version = |manifest, package| {
+        package_version(manifest, package)
+            .and_then(|version| version.split(' ').next())
+            .map(String::from)
+    };
+    NOTABLE_COMPONENTS
+        .iter()
+        .filter_map(|&(package, name)| {
+            let previous = version(previous, package)?;
+            let current = version(current, package)?;
+            (previous != current).then_some(VersionChange {
+                name,
+                previous,
+                current,
+            })
+        })
+        .collect()
+}
+
+fn delta<'a>(previous: &'a toml::Value, current: &'a toml::Value) -> Delta<'a> {
+    let packages = |manifest: &'a toml::Value| {
+        manifest
+            .get("pkg")
+            .and_then(|pkg| pkg.as_table())
+            .into_iter()
+            .flat_map(|pkg| pkg.keys())
+            .map(|name| name.as_str())
+            .filter(|name| package_version(manifest, name).is_some())
+            .collect::<BTreeSet<_>>()
+    };
+    let date = |manifest: &'a toml::Value| manifest.get("date").and_then(|date| date.as_str());
+
+    let mut delta = Delta {
+        date: date(current),
+        previous_date: date(previous),
+        ..Delta::default()
+    };
+    let previous_packages = packages(previous);
+    for name in packages(current) {
+        if !previous_packages.contains(name) {
+            delta.added.push(name);
+        } else if package_version(previous, name) == package_version(current, name) {
+            delta.unchanged.push(name);
+        } else {
+            delta.changed.push(name);
+        }
+    }
+    delta.removed = previous_packages
+        .difference(&packages(current))
+        .copied()
+        .collect();
+    delta
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn components_delta() {
+        let previous: toml::Value = r#"
+            date = "2023-06-01"
+            [pkg.rustc]
+            version = "1.72.0-nightly (8c74a5d27 2023-05-31)"
+            [pkg.cargo]
+            version = "1.72.0-nightly (0c14026aa 2023-05-25)"
+            [pkg.rls-preview]
+            version = "1.41.0 (0f2c7b3 2023-05-31)"
+            [pkg.miri]
+            version = ""
+        "#
+        .parse()
+        .unwrap();
+        let current: toml::Value = r#"
+            date = "2023-06-02"
+            [pkg.rustc]
+            version = "1.72.0-nightly (d59363ad0 2023-06-01)"
+            [pkg.cargo]
+            version = "1.72.0-nightly (0c14026aa 2023-05-25)"
+            [pkg.miri]
+            version = "0.1.0 (d59363a 2023-06-01)"
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            Delta {
+                date: Some("2023-06-02"),
+                previous_date: Some("2023-06-01"),
+                changed: vec!["rustc"],
+                unchanged: vec!["cargo"],
+                added: vec!["miri"],
+                removed: vec!["rls-preview"],
+            },
+            delta(&previous, &current)
+        );
+    }
+
+    #[test]
+    fn notable_version_changes() {
+        let previous: toml::Value = r#"
+            [pkg.rust]
+            version = "1.69.0 (84c898d65 2023-04-16)"
+            [pkg.cargo]
+            version = "1.69.0 (6e9a83356 2023-04-12)"
+            [pkg.rustfmt-preview]
+            version = "1.5.2 (84c898d6 2023-04-16)"
+            [pkg.clippy-preview]
+            version = "0.1.69 (84c898d 2023-04-16)"
+        "#
+        .parse()
+        .unwrap();
+        let current: toml::Value = r#"
+            [pkg.rust]
+            version = "1.70.0 (90c541806 2023-05-31)"
+            [pkg.cargo]
+            version = "1.70.0 (ec8a8a0ca 2023-04-25)"
+            [pkg.rustfmt-preview]
+            version = "1.5.2 (90c54180 2023-05-31)"
+            [pkg.rust-analyzer-preview]
+            version = "1.70.0 (90c5418 2023-05-31)"
+        "#
+        .parse()
+        .unwrap();
+
+        let change = |name, previous: &str, current: &str| VersionChange {
+            name,
+            previous: previous.into(),
+            current: current.into(),
+        };
+        assert_eq!(
+            version_changes(&previous, &current),
+            vec![
+                change("Rust", "1.69.0", "1.70.0"),
+                change("Cargo", "1.69.0", "1.70.0"),
+            ]
+        );
+    }
+}
@@ -20,8 +20,8 @@ impl Discourse {
 
     fn start_new_request(&mut self) -> anyhow::Result<()> {
         self.client.reset();
-        self.client.useragent("rust-lang/promote-release")?;
         let mut headers = curl::easy::List::new();
+        crate::curl_helper::identify(&mut self.client, &mut headers)?;
         headers.append(&format!("Api-Key: {}", self.api_key))?;
         headers.append(&format!("Api-Username: {}", self.api_username))?;
         headers.append("Content-Type: application/json")?;
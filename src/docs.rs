@@ -0,0 +1,405 @@
+//! Incremental upload of the documentation.
+//!
+//! The docs contain tens of thousands of files, and most of them don't change between releases.
+//! Rather than relying on `aws s3 sync --delete` (which has to compare every remote object, and
+//! which can briefly remove pages while it's running), we keep an index of the SHA256 of every
+//! uploaded file next to the docs. On the next release we only upload the files whose hash
+//! changed, and delete the files that are gone only after everything else was uploaded.
+
+use crate::Context;
+use anyhow::Error;
+use rayon::prelude::*;
+use sha2::Digest;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Name of the object, inside each docs directory in the bucket, storing the hashes index.
+const INDEX_NAME: &str = ".promote-release-index.json";
+
+/// Name of the object, inside each docs directory in the bucket, storing the docs tarball.
+pub(crate) const TARBALL_NAME: &str = "rust-docs.tar.gz";
+
+/// Maximum number of file paths CloudFront accepts in the invalidations in progress of a
+/// distribution. Wildcard paths are limited separately, and a single one covers any number of
+/// files, so we fall back to invalidating the whole directory above this.
+const MAX_INVALIDATION_PATHS: usize = 3000;
+
+/// Maximum number of keys that can be deleted with a single S3 DeleteObjects call.
+const MAX_DELETES_PER_REQUEST: usize = 1000;
+
+type Index = BTreeMap<String, String>;
+
+impl Context {
+    /// Uploads the contents of `docs` to `/doc/$dir/` in the upload bucket, returning the files
+    /// that were added, changed or removed when they are known (with an incremental upload).
+    pub(crate) fn upload_docs(&self, docs: &Path, dir: &str) -> Result<Option<Vec<String>>, Error> {
+        let bucket = &self.config.upload_bucket;
+        let dst = format!("s3://{}/doc/{}/", bucket, dir);
+        let index_url = format!("{}{}", dst, INDEX_NAME);
+
+        let previous = if self.config.incremental_docs {
+            self.download_docs_index(&index_url)?
+        } else {
+            None
+        };
+
+        let Some(previous) = previous else {
+            // Without an index we can't know what changed, so do a full sync. Note that this
+            // also deletes any stale index, as it's not present locally.
+            crate::run(
+                self.aws_s3()
+                    .arg("sync")
+                    .arg("--storage-class")
+                    .arg(&self.config.storage_class)
+                    .arg("--delete")
+                    .arg("--only-show-errors")
+                    .arg(format!("{}/", docs.display()))
+                    .arg(&dst),
+            )?;
+            if self.config.incremental_docs {
+                self.upload_docs_index(&index_url, &hash_directory(docs)?)?;
+            }
+            return Ok(None);
+        };
+
+        let current = hash_directory(docs)?;
+        let (changed, removed) = diff_indexes(&previous, &current);
+        println!(
+            "docs for {}: {} files, {} changed, {} removed",
+            dir,
+            current.len(),
+            changed.len(),
+            removed.len()
+        );
+
+        // Stage the changed files in a separate directory, so that they can be uploaded with a
+        // single recursive copy.
+        if !changed.is_empty() {
+            let staging = self.work.join("docs-changed");
+            let _ = fs::remove_dir_all(&staging);
+            for file in &changed {
+                let dest = staging.join(file);
+                fs::create_dir_all(dest.parent().unwrap())?;
+                if fs::hard_link(docs.join(file), &dest).is_err() {
+                    fs::copy(docs.join(file), &dest)?;
+                }
+            }
+            crate::run(
+                self.aws_s3()
+                    .arg("cp")
+                    .arg("--recursive")
+                    .arg("--storage-class")
+                    .arg(&self.config.storage_class)
+                    .arg("--only-show-errors")
+                    .arg(format!("{}/", staging.display()))
+                    .arg(&dst),
+            )?;
+            fs::remove_dir_all(&staging)?;
+        }
+
+        // Removals happen only after all the new files are available.
+        for chunk in removed.chunks(MAX_DELETES_PER_REQUEST) {
+            let objects = chunk
+                .iter()
+                .map(|file| serde_json::json!({ "Key": format!("doc/{}/{}", dir, file) }))
+                .collect::<Vec<_>>();
+            let payload = serde_json::json!({ "Objects": objects, "Quiet": true });
+            let payload_path = self.work.join("docs-delete.json");
+            fs::write(&payload_path, payload.to_string())?;
+            crate::run(
+                self.aws_s3api()
+                    .arg("delete-objects")
+                    .arg("--bucket")
+                    .arg(bucket)
+                    .arg("--delete")
+                    .arg(format!("file://{}", payload_path.display())),
+            )?;
+        }
+
+        self.upload_docs_index(&index_url, &current)?;
+        Ok(Some(changed.into_iter().chain(removed).collect()))
+    }
+
+    /// Uploads the `rust-docs` tarball to `/doc/$dir/rust-docs.tar.gz` in the upload bucket. This
+    /// must happen after `upload_docs`, as a full sync would delete it.
+    pub(crate) fn upload_docs_tarball(&self, tarball: &Path, dir: &str) -> Result<(), Error> {
+        crate::run(
+            self.aws_s3()
+                .arg("cp")
+                .arg("--only-show-errors")
+                .arg("--storage-class")
+                .arg(&self.config.storage_class)
+                .arg("--content-type")
+                .arg("application/gzip")
+                .arg(tarball)
+                .arg(format!(
+                    "s3://{}/doc/{}/{}",
+                    self.config.upload_bucket, dir, TARBALL_NAME
+                )),
+        )
+    }
+
+    /// Returns whether `/doc/$dir/` in the upload bucket already contains as many files as `docs`,
+    /// with the same total size. This is only a cheap heuristic, meant for the versioned docs of
+    /// stable releases: they never change once published, so a re-run can skip uploading them.
+    pub(crate) fn docs_already_published(&self, docs: &Path, dir: &str) -> Result<bool, Error> {
+        let output = self
+            .aws_s3()
+            .arg("ls")
+            .arg("--recursive")
+            .arg(format!("s3://{}/doc/{}/", self.config.upload_bucket, dir))
+            .output()?;
+        // Listing an empty prefix fails.
+        if !output.status.success() {
+            return Ok(false);
+        }
+        let remote = summarize_listing(&String::from_utf8(output.stdout)?);
+
+        let mut files = Vec::new();
+        collect_files(docs, &mut files)?;
+        let mut size = 0;
+        for file in &files {
+            size += fs::metadata(file)?.len();
+        }
+
+        println!(
+            "docs for {}: {} files ({} bytes) locally, {} files ({} bytes) already published",
+            dir,
+            files.len(),
+            size,
+            remote.0,
+            remote.1
+        );
+        Ok(remote == (files.len(), size))
+    }
+
+    fn download_docs_index(&self, index_url: &str) -> Result<Option<Index>, Error> {
+        let local = self.work.join("docs-index-previous.json");
+        let _ = fs::remove_file(&local);
+        let output = self
+            .aws_s3()
+            .arg("cp")
+            .arg("--only-show-errors")
+            .arg(index_url)
+            .arg(&local)
+            .output()?;
+        if !output.status.success() {
+            println!(
+                "no docs index found at {}, falling back to a full sync",
+                index_url
+            );
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&fs::read(&local)?)?))
+    }
+
+    fn upload_docs_index(&self, index_url: &str, index: &Index) -> Result<(), Error> {
+        let local = self.work.join("docs-index.json");
+        fs::write(&local, serde_json::to_vec(index)?)?;
+        crate::run(
+            self.aws_s3()
+                .arg("cp")
+                .arg("--only-show-errors")
+                .arg("--storage-class")
+                .arg(&self.config.storage_class)
+                .arg(&local)
+                .arg(index_url),
+        )
+    }
+
+    fn aws_s3api(&self) -> Command {
+        self.aws_command(self.config.s3_endpoint_url.as_deref(), "s3api")
+    }
+}
+
+/// Returns the SHA256 of every file inside `root`, keyed by their path relative to it (with `/`
+/// as the separator, matching S3 keys).
+fn hash_directory(root: &Path) -> Result<Index, Error> {
+    let mut files = Vec::new();
+    collect_files(root, &mut files)?;
+
+    files
+        .par_iter()
+        .map(|path| {
+            let relative = path
+                .strip_prefix(root)?
+                .iter()
+                .map(|c| c.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let hash = hex::encode(sha2::Sha256::digest(fs::read(path)?));
+            Ok((relative, hash))
+        })
+        .collect()
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Returns the number of files and their total size from the output of `aws s3 ls --recursive`,
+/// ignoring our hashes index and the docs tarball.
+fn summarize_listing(listing: &str) -> (usize, u64) {
+    let mut count = 0;
+    let mut size = 0;
+    for line in listing.lines() {
+        // Each line is `date time size key`, and the key can contain spaces.
+        let mut parts = line.split_whitespace();
+        let (Some(_date), Some(_time), Some(Ok(length)), Some(key)) = (
+            parts.next(),
+            parts.next(),
+            parts.next().map(str::parse::<u64>),
+            parts.next(),
+        ) else {
+            continue;
+        };
+        if key.ends_with(INDEX_NAME) || key.ends_with(TARBALL_NAME) {
+            continue;
+        }
+        count += 1;
+        size += length;
+    }
+    (count, size)
+}
+
+/// Returns the CloudFront paths to invalidate after uploading the docs in `/doc/$dir/`, only
+/// listing the `changed` files when they are known and not too many. The stable docs are served
+/// from the root of the distribution, the other directories from `/$dir/`.
+pub(crate) fn invalidation_paths(dir: &str, changed: Option<&[String]>) -> Vec<String> {
+    let prefix = if dir == "stable" {
+        String::new()
+    } else {
+        format!("/{}", dir)
+    };
+    let Some(changed) = changed else {
+        return vec![format!("{}/*", prefix)];
+    };
+
+    let mut paths = BTreeSet::new();
+    for file in changed {
+        paths.insert(format!("{}/{}", prefix, file));
+        // Directories are also reachable without the `index.html`.
+        if let Some(directory) = file.strip_suffix("index.html") {
+            paths.insert(format!("{}/{}", prefix, directory));
+        }
+    }
+    if paths.len() > MAX_INVALIDATION_PATHS {
+        return vec![format!("{}/*", prefix)];
+    }
+    paths.into_iter().collect()
+}
+
+/// Returns the files that were added or changed, and the files that were removed.
+fn diff_indexes(previous: &Index, current: &Index) -> (Vec<String>, Vec<String>) {
+    let changed = current
+        .iter()
+        .filter(|(file, hash)| previous.get(*file) != Some(hash))
+        .map(|(file, _)| file.clone())
+        .collect();
+    let removed = previous
+        .keys()
+        .filter(|file| !current.contains_key(*file))
+        .cloned()
+        .collect();
+    (changed, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(entries: &[(&str, &str)]) -> Index {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn diff() {
+        let previous = index(&[
+            ("index.html", "a"),
+            ("std/index.html", "b"),
+            ("old.html", "c"),
+        ]);
+        let current = index(&[
+            ("index.html", "a"),
+            ("std/index.html", "d"),
+            ("new.html", "e"),
+        ]);
+
+        let (changed, removed) = diff_indexes(&previous, &current);
+        assert_eq!(changed, vec!["new.html", "std/index.html"]);
+        assert_eq!(removed, vec!["old.html"]);
+    }
+
+    #[test]
+    fn invalidations() {
+        let changed = vec!["index.html".to_string(), "std/vec/index.html".to_string()];
+        assert_eq!(vec!["/*"], invalidation_paths("stable", None));
+        assert_eq!(vec!["/1.70.0/*"], invalidation_paths("1.70.0", None));
+        assert_eq!(
+            vec!["/", "/index.html", "/std/vec/", "/std/vec/index.html"],
+            invalidation_paths("stable", Some(&changed))
+        );
+        assert_eq!(
+            vec![
+                "/nightly/",
+                "/nightly/index.html",
+                "/nightly/std/vec/",
+                "/nightly/std/vec/index.html"
+            ],
+            invalidation_paths("nightly", Some(&changed))
+        );
+        assert!(invalidation_paths("nightly", Some(&[])).is_empty());
+
+        let many = (0..MAX_INVALIDATION_PATHS + 1)
+            .map(|i| format!("{}.html", i))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["/nightly/*"],
+            invalidation_paths("nightly", Some(&many))
+        );
+    }
+
+    #[test]
+    fn listing() {
+        let listing = "\
+2023-06-01 12:00:00       1234 doc/1.70.0/index.html
+2023-06-01 12:00:00         10 doc/1.70.0/std/file with spaces.html
+2023-06-01 12:00:00        999 doc/1.70.0/.promote-release-index.json
+2023-06-01 12:00:00       4567 doc/1.70.0/rust-docs.tar.gz
+";
+        assert_eq!((2, 1244), summarize_listing(listing));
+        assert_eq!((0, 0), summarize_listing(""));
+    }
+
+    #[test]
+    fn hash_nested_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("std/vec")).unwrap();
+        fs::write(dir.path().join("index.html"), "hello").unwrap();
+        fs::write(dir.path().join("std/vec/index.html"), "world").unwrap();
+
+        let hashes = hash_directory(dir.path()).unwrap();
+        assert_eq!(
+            hashes.keys().collect::<Vec<_>>(),
+            vec!["index.html", "std/vec/index.html"]
+        );
+        assert_eq!(
+            hashes["index.html"],
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+    }
+}
@@ -0,0 +1,145 @@
+//! Exit codes of promote-release, so that monitoring can tell apart runs that had nothing to do,
+//! runs that failed because of a network hiccup (and will most likely succeed when retried), and
+//! runs that need a human to look at them. Runs interrupted by a signal exit with `128 + signal`,
+//! see the `interrupt` module.
+
+use anyhow::Error;
+use std::io::ErrorKind;
+use std::process::ExitCode;
+
+/// Nothing was released, as there was nothing new to release.
+const SKIPPED: u8 = 3;
+/// The run failed because of a network or service error, and can be retried as-is.
+const TRANSIENT: u8 = 75;
+/// Any other failure.
+const FAILURE: u8 = 1;
+
+/// How a successful run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Done,
+    Skipped,
+}
+
+/// How the release of a single channel ended. Only `Released` and `Archived` published anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReleaseOutcome {
+    /// The release was published and made live.
+    Released,
+    /// The release was only published to the dated archive (PROMOTE_RELEASE_ARCHIVE_ONLY).
+    Archived,
+    /// The commit to release is the one the live release was built from.
+    SkippedSameCommit,
+    /// A release of the channel was already archived today.
+    SkippedAlreadyToday,
+    /// There are no artifacts for the commit, usually as the branch is waiting for a PR changing
+    /// its channel.
+    SkippedNoArtifacts,
+    /// Something was merged, but the version number is still the one of the live release.
+    SkippedSameVersion,
+    /// Only the files that would be pruned were listed (PROMOTE_RELEASE_PRUNE_DRY_RUN).
+    PruneDryRun,
+}
+
+impl ReleaseOutcome {
+    /// The decision recorded in the version report, see `Context::record_version_decision`.
+    pub(crate) fn decision(self) -> &'static str {
+        match self {
+            ReleaseOutcome::Released | ReleaseOutcome::Archived | ReleaseOutcome::PruneDryRun => {
+                "release"
+            }
+            ReleaseOutcome::SkippedSameCommit => "skip-same-rev",
+            ReleaseOutcome::SkippedAlreadyToday => "skip-released-today",
+            ReleaseOutcome::SkippedNoArtifacts => "skip-no-artifacts",
+            ReleaseOutcome::SkippedSameVersion => "skip-version-unchanged",
+        }
+    }
+
+    pub(crate) fn outcome(self) -> Outcome {
+        match self {
+            // The dry run did everything it was asked to, so it's not a skipped release.
+            ReleaseOutcome::Released | ReleaseOutcome::Archived | ReleaseOutcome::PruneDryRun => {
+                Outcome::Done
+            }
+            ReleaseOutcome::SkippedSameCommit
+            | ReleaseOutcome::SkippedAlreadyToday
+            | ReleaseOutcome::SkippedNoArtifacts
+            | ReleaseOutcome::SkippedSameVersion => Outcome::Skipped,
+        }
+    }
+}
+
+pub(crate) fn exit_code(result: &Result<Outcome, Error>) -> ExitCode {
+    match result {
+        Ok(Outcome::Done) => ExitCode::SUCCESS,
+        Ok(Outcome::Skipped) => ExitCode::from(SKIPPED),
+        Err(err) if is_transient(err) => ExitCode::from(TRANSIENT),
+        Err(_) => ExitCode::from(FAILURE),
+    }
+}
+
+/// Whether any of the causes of `err` is a network or timeout error.
+fn is_transient(err: &Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(err) = cause.downcast_ref::<curl::Error>() {
+            err.is_couldnt_resolve_host()
+                || err.is_couldnt_resolve_proxy()
+                || err.is_couldnt_connect()
+                || err.is_operation_timedout()
+                || err.is_send_error()
+                || err.is_recv_error()
+                || err.is_got_nothing()
+                || err.is_partial_file()
+        } else if let Some(err) = cause.downcast_ref::<git2::Error>() {
+            err.class() == git2::ErrorClass::Net
+        } else if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+            matches!(
+                err.kind(),
+                ErrorKind::TimedOut
+                    | ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+            )
+        } else {
+            false
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context as _;
+
+    #[test]
+    fn release_outcomes() {
+        assert_eq!(Outcome::Done, ReleaseOutcome::Archived.outcome());
+        assert_eq!(Outcome::Done, ReleaseOutcome::PruneDryRun.outcome());
+        assert_eq!(
+            Outcome::Skipped,
+            ReleaseOutcome::SkippedSameVersion.outcome()
+        );
+        assert_eq!(
+            "skip-version-unchanged",
+            ReleaseOutcome::SkippedSameVersion.decision()
+        );
+        assert_eq!("release", ReleaseOutcome::Released.decision());
+    }
+
+    #[test]
+    fn classify_errors() {
+        let timeout = Err::<(), _>(std::io::Error::from(ErrorKind::TimedOut))
+            .context("failed to download the manifest")
+            .unwrap_err();
+        assert!(is_transient(&timeout));
+
+        let curl = Error::from(curl::Error::new(7)).context("failed to call the GitHub API");
+        assert!(is_transient(&curl));
+
+        let missing = Error::from(std::io::Error::from(ErrorKind::NotFound));
+        assert!(!is_transient(&missing));
+        assert!(!is_transient(&anyhow::anyhow!(
+            "required components missing"
+        )));
+    }
+}
@@ -0,0 +1,436 @@
+//! A fake AWS CLI backed by a local directory, to test the code paths interacting with S3 without
+//! any network access or credentials.
+//!
+//! Every bucket is a directory inside the root of the fake, and every invocation is appended to a
+//! log so tests can assert on the flags we pass (cache control, content types...). Only the
+//! subset of the CLI used by promote-release is supported: `s3 cp`, `s3 sync`, `s3 ls`, `s3 rm`,
+//! and logging of any other command. The `--exclude` and `--include` filters of recursive copies
+//! are applied like the real CLI does, with the later filters taking precedence.
+
+use crate::config::Config;
+use crate::Context;
+use curl::easy::Easy;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+const SCRIPT: &str = r#"#!/bin/bash
+set -euo pipefail
+ROOT="@ROOT@"
+echo "$*" >> "$ROOT/.invocations"
+
+recursive=0
+args=()
+filters=()
+while [ $# -gt 0 ]; do
+    case "$1" in
+        --recursive) recursive=1; shift ;;
+        --exclude|--include) filters+=("${1#--}" "$2"); shift 2 ;;
+        --only-show-errors|--quiet) shift ;;
+        --delete) if [ "${args[0]:-}" = "s3" ]; then shift; else shift 2; fi ;;
+        --*) shift 2 ;;
+        *) args+=("$1"); shift ;;
+    esac
+done
+
+# Only S3 commands are emulated, everything else is just logged.
+if [ "${args[0]:-}" != "s3" ]; then
+    exit 0
+fi
+
+local_path() {
+    case "$1" in
+        s3://*) echo "$ROOT/buckets/${1#s3://}" ;;
+        *) echo "$1" ;;
+    esac
+}
+
+# Whether the relative path $1 passes the filters.
+included() {
+    local result=include i
+    for ((i = 0; i < ${#filters[@]}; i += 2)); do
+        # The pattern is intentionally unquoted, to be matched as a glob.
+        if [[ "$1" == ${filters[i + 1]} ]]; then
+            result="${filters[i]}"
+        fi
+    done
+    [ "$result" = include ]
+}
+
+# Copies the files of the directory $1 passing the filters into the directory $2.
+copy_tree() {
+    mkdir -p "$2"
+    [ -d "$1" ] || return 0
+    (cd "$1" && find . -type f -printf '%P\n') | while IFS= read -r file; do
+        if included "$file"; then
+            mkdir -p "$(dirname "$2/$file")"
+            cp "$1/$file" "$2/$file"
+        fi
+    done
+}
+
+if [ "${args[1]}" = "ls" ]; then
+    # Listing a missing prefix fails in S3.
+    location="${args[2]#s3://}"
+    bucket="${location%%/*}"
+    prefix="${location#*/}"
+    [ -d "$ROOT/buckets/$bucket/$prefix" ] || exit 1
+    cd "$ROOT/buckets/$bucket"
+    if [ "$recursive" = 1 ]; then
+        find "$prefix" -type f -printf '2023-06-01 00:00:00 %s %p\n'
+    else
+        find "$prefix" -mindepth 1 -maxdepth 1 -type d \
+            -printf '                           PRE %f/\n'
+        find "$prefix" -mindepth 1 -maxdepth 1 -type f -printf '2023-06-01 00:00:00 %s %f\n'
+    fi
+    exit 0
+fi
+
+if [ "${args[1]}" = "rm" ]; then
+    target="$(local_path "${args[2]}")"
+    if [ "$recursive" = 1 ]; then
+        rm -rf "$target"
+    else
+        rm -f "$target"
+    fi
+    exit 0
+fi
+
+src="$(local_path "${args[2]}")"
+dst="$(local_path "${args[3]}")"
+
+case "${args[1]}" in
+    cp)
+        if [ "$recursive" = 1 ]; then
+            # Copying a missing prefix is not an error in S3.
+            copy_tree "$src" "$dst"
+        else
+            if [ ! -f "$src" ]; then
+                echo "fatal error: $src does not exist" >&2
+                exit 1
+            fi
+            case "$dst" in
+                */) mkdir -p "$dst" ;;
+                *) mkdir -p "$(dirname "$dst")" ;;
+            esac
+            cp "$src" "$dst"
+        fi
+        ;;
+    sync)
+        copy_tree "$src" "$dst"
+        ;;
+    *)
+        echo "unsupported command: ${args[1]}" >&2
+        exit 1
+        ;;
+esac
+"#;
+
+pub(crate) struct FakeAws {
+    root: TempDir,
+}
+
+impl FakeAws {
+    pub(crate) fn new() -> Self {
+        let root = tempfile::tempdir().unwrap();
+        let script = root.path().join("aws");
+        fs::write(
+            &script,
+            SCRIPT.replace("@ROOT@", &root.path().display().to_string()),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::create_dir_all(root.path().join("buckets")).unwrap();
+        fs::write(root.path().join(".invocations"), "").unwrap();
+
+        FakeAws { root }
+    }
+
+    pub(crate) fn cli(&self) -> PathBuf {
+        self.root.path().join("aws")
+    }
+
+    /// Returns the local path of `key` inside `bucket`.
+    pub(crate) fn object(&self, bucket: &str, key: &str) -> PathBuf {
+        self.root.path().join("buckets").join(bucket).join(key)
+    }
+
+    pub(crate) fn put(&self, bucket: &str, key: &str, contents: &str) {
+        let path = self.object(bucket, key);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// Returns the arguments of every command executed so far, one line per command.
+    pub(crate) fn invocations(&self) -> Vec<String> {
+        fs::read_to_string(self.root.path().join(".invocations"))
+            .unwrap()
+            .lines()
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    /// Creates a `Context` using this fake, with the given configuration overrides.
+    pub(crate) fn context(&self, work: &Path, vars: &[(&str, &str)]) -> Context {
+        let cli = self.cli().display().to_string();
+        let mut vars = vars.to_vec();
+        vars.push(("AWS_CLI", &cli));
+
+        Context {
+            work: work.to_path_buf(),
+            handle: Easy::new(),
+            config: Config::for_tests(&vars),
+            date: "2023-06-01".into(),
+            current_version: None,
+            current_cargo_version: None,
+            memory: Default::default(),
+        }
+    }
+
+    /// Returns a context working in a directory of the fake, whose `dl_dir` contains `files`
+    /// (each containing its own name).
+    pub(crate) fn context_with_dl(&self, vars: &[(&str, &str)], files: &[&str]) -> Context {
+        let ctx = self.context(&self.root.path().join("work"), vars);
+        fs::create_dir_all(ctx.dl_dir()).unwrap();
+        for file in files {
+            fs::write(ctx.dl_dir().join(file), file).unwrap();
+        }
+        ctx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_artifacts() {
+        let aws = FakeAws::new();
+        let work = tempfile::tempdir().unwrap();
+        aws.put("artifacts", "builds/abc/rustc-nightly.tar.xz", "rustc");
+        aws.put("artifacts", "builds/abc/rustc-nightly.tar.xz.asc", "sig");
+        aws.put("artifacts", "builds/abc/rustc-nightly.tar.xz.sha256", "sha");
+
+        let mut ctx = aws.context(work.path(), &[]);
+        assert!(ctx.download_artifacts("abc").unwrap());
+        let dl = ctx.dl_dir();
+        assert!(dl.join("rustc-nightly.tar.xz").is_file());
+        assert!(!dl.join("rustc-nightly.tar.xz.asc").exists());
+        assert!(!dl.join("rustc-nightly.tar.xz.sha256").exists());
+
+        let mut ctx = aws.context(work.path(), &[("REUSE_CHECKSUMS", "1")]);
+        assert!(ctx.download_artifacts("abc").unwrap());
+        assert!(ctx.dl_dir().join("rustc-nightly.tar.xz.sha256").is_file());
+    }
+
+    #[test]
+    fn download_missing_artifacts() {
+        let aws = FakeAws::new();
+        let work = tempfile::tempdir().unwrap();
+
+        let mut ctx = aws.context(work.path(), &[]);
+        assert!(!ctx.download_artifacts("missing").unwrap());
+    }
+
+    #[test]
+    fn publish_release_and_archive() {
+        let aws = FakeAws::new();
+        let mut ctx = aws.context_with_dl(
+            &[("EXTRA_UPLOAD_BUCKETS", "mirror/rust")],
+            &["rustc-nightly.tar.xz", "channel-rust-nightly.toml"],
+        );
+
+        ctx.publish_archive().unwrap();
+        ctx.publish_release("abc").unwrap();
+
+        for (bucket, dir) in [("static", "dist"), ("mirror", "rust")] {
+            for file in ["rustc-nightly.tar.xz", "channel-rust-nightly.toml"] {
+                assert!(aws.object(bucket, &format!("{}/{}", dir, file)).is_file());
+                assert!(aws
+                    .object(bucket, &format!("{}/2023-06-01/{}", dir, file))
+                    .is_file());
+            }
+        }
+
+        // Only the archive is immutable on nightly.
+        let invocations = aws.invocations();
+        let immutable = |dst: &str| {
+            invocations.iter().any(|line| {
+                line.ends_with(dst)
+                    && line.contains("--include *.tar.xz ")
+                    && line.contains("immutable")
+            })
+        };
+        assert!(immutable("s3://static/dist/2023-06-01/"));
+        assert!(!immutable("s3://static/dist/"));
+    }
+
+    #[test]
+    fn full_docs_sync() {
+        let aws = FakeAws::new();
+        let work = tempfile::tempdir().unwrap();
+        let ctx = aws.context(work.path(), &[]);
+        let docs = work.path().join("docs");
+        fs::create_dir_all(docs.join("std")).unwrap();
+        fs::write(docs.join("std/index.html"), "std").unwrap();
+
+        ctx.upload_docs(&docs, "nightly").unwrap();
+        assert!(aws.object("static", "doc/nightly/std/index.html").is_file());
+    }
+
+    #[test]
+    fn s3_transfer_settings() {
+        let aws = FakeAws::new();
+        let work = tempfile::tempdir().unwrap();
+        let ctx = aws.context(
+            work.path(),
+            &[
+                ("S3_MAX_CONCURRENT_REQUESTS", "64"),
+                ("S3_MULTIPART_CHUNKSIZE", "64MB"),
+            ],
+        );
+
+        ctx.configure_aws_cli().unwrap();
+        assert_eq!(
+            aws.invocations(),
+            vec![
+                "configure set default.s3.max_concurrent_requests 64",
+                "configure set default.s3.multipart_chunksize 64MB",
+            ]
+        );
+
+        let cmd = ctx.aws_s3();
+        let config_file = ctx.aws_config_file();
+        assert!(config_file.is_file());
+        assert!(cmd
+            .get_envs()
+            .any(|(k, v)| k == "AWS_CONFIG_FILE" && v == Some(config_file.as_os_str())));
+    }
+
+    #[test]
+    fn publish_stable_docs() {
+        let aws = FakeAws::new();
+        let work = tempfile::tempdir().unwrap();
+        let mut ctx = aws.context(
+            work.path(),
+            &[
+                ("CHANNEL", "stable"),
+                ("DOCS_VERSION", "1.70.0"),
+                ("DOCS_TARBALL", "1"),
+            ],
+        );
+
+        let prefix = "rust-docs-1.70.0-x86_64-unknown-linux-gnu";
+        let tarball = aws.object("static", &format!("dist/{}.tar.gz", prefix));
+        fs::create_dir_all(tarball.parent().unwrap()).unwrap();
+        let mut builder = tar::Builder::new(flate2::write::GzEncoder::new(
+            fs::File::create(&tarball).unwrap(),
+            flate2::Compression::fast(),
+        ));
+        let mut header = tar::Header::new_gnu();
+        header.set_size(3);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(
+                &mut header,
+                format!("{}/rust-docs/share/doc/rust/html/index.html", prefix),
+                &b"std"[..],
+            )
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        ctx.do_publish_docs().unwrap();
+        assert!(aws.object("static", "doc/stable/index.html").is_file());
+        assert!(aws.object("static", "doc/1.70.0/index.html").is_file());
+        assert!(aws
+            .object("static", "doc/stable/rust-docs.tar.gz")
+            .is_file());
+        assert!(aws
+            .object("static", "doc/1.70.0/rust-docs.tar.gz")
+            .is_file());
+
+        // The versioned docs are not uploaded again when they didn't change.
+        let syncs = |aws: &FakeAws| {
+            aws.invocations()
+                .iter()
+                .filter(|line| line.starts_with("s3 sync") && line.ends_with("doc/1.70.0/"))
+                .count()
+        };
+        assert_eq!(1, syncs(&aws));
+        ctx.do_publish_docs().unwrap();
+        assert_eq!(1, syncs(&aws));
+    }
+
+    #[test]
+    fn download_archive() {
+        let aws = FakeAws::new();
+        let work = tempfile::tempdir().unwrap();
+        let mut ctx = aws.context(work.path(), &[("CHANNEL", "stable")]);
+        assert!(ctx.download_archive().is_err());
+
+        for file in [
+            "rustc-1.70.0.tar.xz",
+            "rustc-1.70.0.tar.xz.asc",
+            "channel-rust-stable.toml.asc",
+            // Released the same day on another channel.
+            "rustc-nightly.tar.xz",
+            "channel-rust-nightly.toml",
+        ] {
+            aws.put("static", &format!("dist/2023-06-01/{}", file), file);
+        }
+        aws.put(
+            "static",
+            "dist/2023-06-01/channel-rust-stable.toml",
+            "[pkg.rust]\n\
+             version = \"1.70.0 (90c541806 2023-05-31)\"\n\
+             git_commit_hash = \"90c541806f23a127002de5b4038be731ba1458ca\"\n\
+             [pkg.rust.target.x86_64-unknown-linux-gnu]\n\
+             xz_url = \"https://static.rust-lang.org/dist/2023-06-01/rustc-1.70.0.tar.xz\"\n\
+             [pkg.cargo]\n\
+             version = \"1.70.0 (ec8a8a0ca 2023-04-25)\"\n",
+        );
+        assert_eq!(
+            "90c541806f23a127002de5b4038be731ba1458ca",
+            ctx.download_archive().unwrap()
+        );
+        let mut files = fs::read_dir(ctx.dl_dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            vec![
+                "channel-rust-stable.toml",
+                "channel-rust-stable.toml.asc",
+                "rustc-1.70.0.tar.xz",
+                "rustc-1.70.0.tar.xz.asc",
+            ],
+            files
+        );
+        assert_eq!(Some("1.70.0"), ctx.current_version.as_deref());
+        assert_eq!(Some("1.70.0"), ctx.current_cargo_version.as_deref());
+    }
+
+    #[test]
+    fn publish_latest_file() {
+        let aws = FakeAws::new();
+        let mut ctx = aws.context_with_dl(
+            &[("CHANNEL", "stable"), ("UPLOAD_LATEST_FILE", "1")],
+            &["rustc-1.70.0.tar.xz", "channel-rust-stable.toml"],
+        );
+        ctx.current_version = Some("1.70.0".into());
+
+        ctx.publish_release("abc").unwrap();
+        assert_eq!(
+            "1.70.0\n",
+            fs::read_to_string(aws.object("static", "dist/latest-stable.txt")).unwrap()
+        );
+        assert!(aws
+            .invocations()
+            .last()
+            .unwrap()
+            .contains("latest-stable.txt"));
+    }
+}
@@ -1,63 +1,171 @@
+use crate::recompress::glob_matches;
 use anyhow::Error;
 use curl::easy::Easy;
+use std::str::FromStr;
+
+/// Surrogate keys used when PROMOTE_RELEASE_FASTLY_SURROGATE_KEYS is not set. They need to match
+/// the keys configured in the Fastly service.
+const DEFAULT_SURROGATE_KEYS: &[(&str, &str)] = &[("/dist/*", "dist"), ("/doc/*", "doc")];
+
+/// Maps the paths matching `pattern` (where `*` matches any sequence of characters) to the Fastly
+/// surrogate key to purge for them. Configured as `pattern=key`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SurrogateKey {
+    pattern: String,
+    key: String,
+}
+
+impl FromStr for SurrogateKey {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.split_once('=') {
+            Some((pattern, key)) if !pattern.is_empty() && !key.is_empty() => Ok(SurrogateKey {
+                pattern: pattern.to_string(),
+                key: key.to_string(),
+            }),
+            _ => anyhow::bail!("invalid surrogate key (expected pattern=key): {}", input),
+        }
+    }
+}
 
 pub struct Fastly {
     api_token: String,
     service_id: String,
+    surrogate_keys: Vec<SurrogateKey>,
     client: Easy,
 }
 
 impl Fastly {
-    pub fn new(api_token: String, service_id: String) -> Self {
+    pub fn new(api_token: String, service_id: String, surrogate_keys: &[SurrogateKey]) -> Self {
+        let surrogate_keys = if surrogate_keys.is_empty() {
+            DEFAULT_SURROGATE_KEYS
+                .iter()
+                .map(|(pattern, key)| SurrogateKey {
+                    pattern: pattern.to_string(),
+                    key: key.to_string(),
+                })
+                .collect()
+        } else {
+            surrogate_keys.to_vec()
+        };
         Self {
             api_token,
             service_id,
+            surrogate_keys,
             client: Easy::new(),
         }
     }
 
-    pub fn purge(&mut self, path: &str) -> Result<(), Error> {
-        let surrogate_key = path_to_surrogate_key(path);
+    /// Purges the surrogate key of `path`. A soft purge marks the content as stale instead of
+    /// evicting it, so it can still be served while it's revalidated with the origin. This avoids
+    /// a spike of requests to the origin for the most requested paths.
+    pub fn purge(&mut self, path: &str, soft: bool) -> Result<(), Error> {
+        let surrogate_key = path_to_surrogate_key(&self.surrogate_keys, path)?;
         let url = format!(
             "https://api.fastly.com/service/{}/purge/{}",
             self.service_id, surrogate_key
         );
 
-        self.start_new_request()?;
+        self.start_new_request(soft)?;
 
         self.client.post(true)?;
         self.client.url(&url)?;
 
-        println!("invalidating Fastly cache with POST '{}'", url);
+        println!(
+            "invalidating Fastly cache with POST '{}'{}",
+            url,
+            if soft { " (soft purge)" } else { "" }
+        );
 
         self.client.perform().map_err(|error| error.into())
     }
 
-    fn start_new_request(&mut self) -> anyhow::Result<()> {
+    fn start_new_request(&mut self, soft: bool) -> anyhow::Result<()> {
         self.client.reset();
-        self.client.useragent("rust-lang/promote-release")?;
         let mut headers = curl::easy::List::new();
-        headers.append(&format!("Fastly-Key: {}", self.api_token))?;
-        headers.append("Content-Type: application/json")?;
+        crate::curl_helper::identify(&mut self.client, &mut headers)?;
+        for header in request_headers(&self.api_token, soft) {
+            headers.append(&header)?;
+        }
         self.client.http_headers(headers)?;
         Ok(())
     }
 }
 
-fn path_to_surrogate_key(path: &str) -> String {
-    path.chars().filter(|c| c.is_alphanumeric()).collect()
+fn request_headers(api_token: &str, soft: bool) -> Vec<String> {
+    let mut headers = vec![
+        format!("Fastly-Key: {}", api_token),
+        "Content-Type: application/json".to_string(),
+    ];
+    if soft {
+        headers.push("Fastly-Soft-Purge: 1".to_string());
+    }
+    headers
+}
+
+/// Returns the surrogate key of the first rule matching `path`.
+fn path_to_surrogate_key<'a>(rules: &'a [SurrogateKey], path: &str) -> Result<&'a str, Error> {
+    rules
+        .iter()
+        .find(|rule| glob_matches(&rule.pattern, path))
+        .map(|rule| rule.key.as_str())
+        .ok_or_else(|| anyhow::anyhow!("no Fastly surrogate key configured for {}", path))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn default_rules() -> Vec<SurrogateKey> {
+        Fastly::new(String::new(), String::new(), &[]).surrogate_keys
+    }
+
     #[test]
     fn path_to_surrogate_key_dist() {
         let path = "/dist/*";
 
-        let surrogate_key = path_to_surrogate_key(path);
+        let rules = default_rules();
+        let surrogate_key = path_to_surrogate_key(&rules, path).unwrap();
 
         assert_eq!("dist", surrogate_key);
     }
+
+    #[test]
+    fn path_to_surrogate_key_doc_version() {
+        let rules = default_rules();
+        assert_eq!("doc", path_to_surrogate_key(&rules, "/doc/*").unwrap());
+        assert_eq!(
+            "doc",
+            path_to_surrogate_key(&rules, "/doc/1.75.0/*").unwrap()
+        );
+        assert!(path_to_surrogate_key(&rules, "/rustup/*").is_err());
+    }
+
+    #[test]
+    fn soft_purge_header() {
+        assert!(!request_headers("token", false)
+            .iter()
+            .any(|header| header.starts_with("Fastly-Soft-Purge")));
+        assert!(request_headers("token", true).contains(&"Fastly-Soft-Purge: 1".to_string()));
+    }
+
+    #[test]
+    fn configured_surrogate_keys() {
+        let rules = ["/doc/1.*=doc-stable-versions", "/doc/*=doc"]
+            .iter()
+            .map(|rule| rule.parse().unwrap())
+            .collect::<Vec<SurrogateKey>>();
+        assert_eq!(
+            "doc-stable-versions",
+            path_to_surrogate_key(&rules, "/doc/1.75.0/*").unwrap()
+        );
+        assert_eq!(
+            "doc",
+            path_to_surrogate_key(&rules, "/doc/nightly/*").unwrap()
+        );
+
+        assert!("/doc/*".parse::<SurrogateKey>().is_err());
+        assert!("=doc".parse::<SurrogateKey>().is_err());
+    }
 }
@@ -1,28 +1,53 @@
 use crate::curl_helper::BodyExt;
 use anyhow::Context;
+use chrono::{DateTime, Duration, Utc};
 use curl::easy::Easy;
 use rsa::pkcs1::DecodeRsaPrivateKey;
 use sha2::Digest;
+use std::collections::HashMap;
 use std::time::SystemTime;
 
+/// Installation tokens are reused until this close to their expiry, so that they don't expire
+/// in the middle of a sequence of requests.
+const TOKEN_EXPIRY_MARGIN_MINUTES: i64 = 5;
+
 pub(crate) struct Github {
     key: rsa::RsaPrivateKey,
     id: u32,
+    api_base: String,
     client: Easy,
+    /// Installation tokens minted so far, keyed by repository.
+    tokens: HashMap<String, CachedToken>,
+}
+
+struct CachedToken {
+    token: String,
+    expires_at: DateTime<Utc>,
+}
+
+impl CachedToken {
+    fn is_fresh(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at - now > Duration::minutes(TOKEN_EXPIRY_MARGIN_MINUTES)
+    }
 }
 
 pub(crate) struct RepositoryClient<'a> {
     client: &'a mut Easy,
+    api_base: &'a str,
     repo: String,
     token: String,
 }
 
 impl Github {
-    pub(crate) fn new(key: &str, id: u32) -> Github {
+    /// `api_base` is the root of the GitHub API, without a trailing slash (usually
+    /// `https://api.github.com`).
+    pub(crate) fn new(key: &str, id: u32, api_base: &str) -> Github {
         Github {
             key: rsa::RsaPrivateKey::from_pkcs1_pem(key).unwrap(),
             id,
+            api_base: api_base.trim_end_matches('/').to_owned(),
             client: Easy::new(),
+            tokens: HashMap::new(),
         }
     }
 
@@ -63,19 +88,39 @@ impl Github {
 
     fn start_jwt_request(&mut self) -> anyhow::Result<()> {
         self.client.reset();
-        self.client.useragent("rust-lang/promote-release").unwrap();
         let mut headers = curl::easy::List::new();
+        crate::curl_helper::identify(&mut self.client, &mut headers)?;
         headers.append(&format!("Authorization: Bearer {}", self.jwt()))?;
         self.client.http_headers(headers)?;
         Ok(())
     }
 
+    /// Returns a client authenticated with an installation token for `repository`. Tokens are
+    /// valid for an hour, so they're minted once and reused until they're about to expire.
     pub(crate) fn token(&mut self, repository: &str) -> anyhow::Result<RepositoryClient<'_>> {
+        let token = match self.tokens.get(repository) {
+            Some(cached) if cached.is_fresh(Utc::now()) => cached.token.clone(),
+            _ => {
+                let cached = self.mint_token(repository)?;
+                let token = cached.token.clone();
+                self.tokens.insert(repository.to_owned(), cached);
+                token
+            }
+        };
+        Ok(RepositoryClient {
+            client: &mut self.client,
+            api_base: &self.api_base,
+            repo: repository.to_owned(),
+            token,
+        })
+    }
+
+    fn mint_token(&mut self, repository: &str) -> anyhow::Result<CachedToken> {
         self.start_jwt_request()?;
         self.client.get(true)?;
         self.client.url(&format!(
-            "https://api.github.com/repos/{}/installation",
-            repository
+            "{}/repos/{}/installation",
+            self.api_base, repository
         ))?;
         #[derive(serde::Deserialize)]
         struct InstallationResponse {
@@ -90,21 +135,24 @@ impl Github {
         self.start_jwt_request()?;
         self.client.post(true)?;
         self.client.url(&format!(
-            "https://api.github.com/app/installations/{installation_id}/access_tokens"
+            "{}/app/installations/{installation_id}/access_tokens",
+            self.api_base
         ))?;
         #[derive(serde::Deserialize)]
         struct TokenResponse {
             token: String,
+            expires_at: String,
         }
-        let token = self
+        let response = self
             .client
             .without_body()
-            .send_with_response::<TokenResponse>()?
-            .token;
-        Ok(RepositoryClient {
-            client: &mut self.client,
-            repo: repository.to_owned(),
-            token,
+            .send_with_response::<TokenResponse>()?;
+        let expires_at = DateTime::parse_from_rfc3339(&response.expires_at)
+            .with_context(|| format!("invalid token expiry: {}", response.expires_at))?
+            .with_timezone(&Utc);
+        Ok(CachedToken {
+            token: response.token,
+            expires_at,
         })
     }
 }
@@ -113,11 +161,13 @@ impl RepositoryClient<'_> {
     #[cfg(test)]
     pub(crate) fn from_pat<'a>(
         client: &'a mut Easy,
+        api_base: &'a str,
         token: &str,
         repository: &str,
     ) -> RepositoryClient<'a> {
         RepositoryClient {
             client,
+            api_base,
             token: token.to_owned(),
             repo: repository.to_owned(),
         }
@@ -125,14 +175,18 @@ impl RepositoryClient<'_> {
 
     fn start_new_request(&mut self) -> anyhow::Result<()> {
         self.client.reset();
-        self.client.useragent("rust-lang/promote-release")?;
         let mut headers = curl::easy::List::new();
+        crate::curl_helper::identify(self.client, &mut headers)?;
         headers.append(&format!("Authorization: token {}", self.token))?;
         self.client.http_headers(headers)?;
         Ok(())
     }
 
     pub(crate) fn tag(&mut self, tag: CreateTag<'_>) -> anyhow::Result<()> {
+        // GitHub would otherwise happily create a tag object pointing to a commit that doesn't
+        // exist, for example because of a typo in PROMOTE_RELEASE_OVERRIDE_COMMIT.
+        let commit = self.get_commit(tag.commit)?.sha;
+
         #[derive(Debug, serde::Serialize)]
         struct CreateTagInternal<'a> {
             tag: &'a str,
@@ -157,13 +211,14 @@ impl RepositoryClient<'_> {
         self.start_new_request()?;
         self.client.post(true)?;
         self.client.url(&format!(
-            "https://api.github.com/repos/{repository}/git/tags",
+            "{api_base}/repos/{repository}/git/tags",
+            api_base = self.api_base,
             repository = self.repo,
         ))?;
         let request = CreateTagInternal {
             tag: tag.tag_name,
             message: tag.message,
-            object: tag.commit,
+            object: &commit,
             type_: "commit",
             tagger: CreateTagTaggerInternal {
                 name: tag.tagger_name,
@@ -181,6 +236,27 @@ impl RepositoryClient<'_> {
         Ok(())
     }
 
+    /// Returns the details of `sha`, failing with a clear error if it's not in the repository.
+    pub(crate) fn get_commit(&mut self, sha: &str) -> anyhow::Result<FullCommitData> {
+        self.start_new_request()?;
+        self.client.get(true)?;
+        self.client.url(&format!(
+            "{api_base}/repos/{repository}/commits/{sha}",
+            api_base = self.api_base,
+            repository = self.repo,
+        ))?;
+        let commit = self
+            .client
+            .without_body()
+            .send_with_response::<FullCommitData>();
+        match self.client.response_code()? {
+            200 => commit,
+            // 422 is returned for strings that can't be a commit SHA.
+            404 | 422 => anyhow::bail!("commit {} doesn't exist in {}", sha, self.repo),
+            status => commit.with_context(|| format!("unexpected status code {}", status)),
+        }
+    }
+
     /// Returns the SHA of the tip of this ref, if it exists.
     pub(crate) fn get_ref(&mut self, name: &str) -> anyhow::Result<String> {
         // This mostly exists to make sure the request is successful rather than
@@ -197,7 +273,8 @@ impl RepositoryClient<'_> {
         self.start_new_request()?;
         self.client.get(true)?;
         self.client.url(&format!(
-            "https://api.github.com/repos/{repository}/git/ref/{name}",
+            "{api_base}/repos/{repository}/git/ref/{name}",
+            api_base = self.api_base,
             repository = self.repo,
         ))?;
         Ok(self
@@ -227,7 +304,8 @@ impl RepositoryClient<'_> {
         self.start_new_request()?;
         self.client.post(true)?;
         self.client.url(&format!(
-            "https://api.github.com/repos/{repository}/git/refs",
+            "{api_base}/repos/{repository}/git/refs",
+            api_base = self.api_base,
             repository = self.repo,
         ))?;
         self.client
@@ -258,7 +336,8 @@ impl RepositoryClient<'_> {
         // However, the actual request should be a PATCH request.
         self.client.custom_request("PATCH")?;
         self.client.url(&format!(
-            "https://api.github.com/repos/{repository}/git/refs/{name}",
+            "{api_base}/repos/{repository}/git/refs/{name}",
+            api_base = self.api_base,
             repository = self.repo,
         ))?;
         self.client
@@ -277,7 +356,8 @@ impl RepositoryClient<'_> {
         self.start_new_request()?;
         self.client.post(true)?;
         self.client.url(&format!(
-            "https://api.github.com/repos/{repository}/actions/workflows/{workflow}/dispatches",
+            "{api_base}/repos/{repository}/actions/workflows/{workflow}/dispatches",
+            api_base = self.api_base,
             repository = self.repo,
         ))?;
 
@@ -303,7 +383,8 @@ impl RepositoryClient<'_> {
         self.start_new_request()?;
         self.client.put(true)?;
         self.client.url(&format!(
-            "https://api.github.com/repos/{repository}/contents/{path}",
+            "{api_base}/repos/{repository}/contents/{path}",
+            api_base = self.api_base,
             repository = self.repo,
         ))?;
         self.client
@@ -335,7 +416,8 @@ impl RepositoryClient<'_> {
         self.start_new_request()?;
         self.client.post(true)?;
         self.client.url(&format!(
-            "https://api.github.com/repos/{repository}/pulls",
+            "{api_base}/repos/{repository}/pulls",
+            api_base = self.api_base,
             repository = self.repo,
         ))?;
         self.client
@@ -366,7 +448,8 @@ impl RepositoryClient<'_> {
             self.start_new_request()?;
             self.client.get(true)?;
             self.client.url(&format!(
-                "https://api.github.com/repos/{repo}/commits/{commit}",
+                "{api_base}/repos/{repo}/commits/{commit}",
+                api_base = self.api_base,
                 repo = self.repo
             ))?;
 
@@ -405,18 +488,33 @@ impl RepositoryClient<'_> {
         self.start_new_request()?;
         self.client.get(true)?;
         self.client.url(&format!(
-            "https://api.github.com/repos/{repo}/contents/{path}{maybe_ref}",
+            "{api_base}/repos/{repo}/contents/{path}{maybe_ref}",
+            api_base = self.api_base,
             repo = self.repo,
             maybe_ref = sha.map(|s| format!("?ref={}", s)).unwrap_or_default()
         ))?;
         self.client.without_body().send_with_response::<GitFile>()
     }
 
+    pub(crate) fn pull_request(&mut self, pr: u32) -> anyhow::Result<PullRequest> {
+        self.start_new_request()?;
+        self.client.get(true)?;
+        self.client.url(&format!(
+            "{api_base}/repos/{repo}/pulls/{pr}",
+            api_base = self.api_base,
+            repo = self.repo,
+        ))?;
+        self.client
+            .without_body()
+            .send_with_response::<PullRequest>()
+    }
+
     pub(crate) fn merge_pr(&mut self, pr: u32) -> anyhow::Result<()> {
         self.start_new_request()?;
         self.client.put(true)?;
         self.client.url(&format!(
-            "https://api.github.com/repos/{repo}/pulls/{pr}/merge",
+            "{api_base}/repos/{repo}/pulls/{pr}/merge",
+            api_base = self.api_base,
             repo = self.repo,
         ))?;
 
@@ -448,7 +546,8 @@ impl RepositoryClient<'_> {
         self.start_new_request()?;
         self.client.get(true)?;
         self.client.url(&format!(
-            "https://api.github.com/repos/{repo}/pages/builds/latest",
+            "{api_base}/repos/{repo}/pages/builds/latest",
+            api_base = self.api_base,
             repo = self.repo,
         ))?;
 
@@ -471,6 +570,41 @@ impl RepositoryClient<'_> {
     }
 }
 
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct PullRequest {
+    state: String,
+    #[serde(default)]
+    merged: bool,
+    #[serde(default)]
+    draft: bool,
+    /// Computed asynchronously by GitHub, so it's missing right after the PR changed.
+    mergeable_state: Option<String>,
+}
+
+impl PullRequest {
+    /// Returns why the PR can't be merged, if we know it can't.
+    pub(crate) fn merge_blocker(&self) -> Option<&'static str> {
+        if self.merged {
+            return Some("it was already merged");
+        }
+        if self.state != "open" {
+            return Some("it is closed");
+        }
+        if self.draft {
+            return Some("it is a draft");
+        }
+        match self.mergeable_state.as_deref() {
+            Some("dirty") => Some("it has merge conflicts"),
+            Some("behind") => Some("its branch is out of date with the base branch"),
+            Some("blocked") => {
+                Some("the branch protection requires approving reviews or passing checks")
+            }
+            // "clean", "unstable" (non-required checks failed), "has_hooks", or still computing.
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, serde::Deserialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub(crate) enum GitFile {
@@ -508,7 +642,6 @@ pub(crate) struct CreateTag<'a> {
 
 #[derive(serde::Deserialize)]
 pub(crate) struct FullCommitData {
-    #[cfg_attr(not(test), allow(unused))]
     pub(crate) sha: String,
     pub(crate) parents: Vec<CommitParent>,
     pub(crate) commit: CommitCommit,
@@ -534,3 +667,83 @@ pub(crate) struct CommitFile {
 pub(crate) struct CommitParent {
     pub(crate) sha: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_freshness() {
+        let now = Utc::now();
+        let token = |minutes| CachedToken {
+            token: "token".into(),
+            expires_at: now + Duration::minutes(minutes),
+        };
+        assert!(token(60).is_fresh(now));
+        assert!(!token(2).is_fresh(now));
+        assert!(!token(-1).is_fresh(now));
+    }
+
+    #[test]
+    fn pull_request_blockers() {
+        let pr = |json: &str| serde_json::from_str::<PullRequest>(json).unwrap();
+        let open = |state: &str| {
+            pr(&format!(
+                r#"{{"state": "open", "merged": false, "draft": false, "mergeable_state": "{state}"}}"#
+            ))
+        };
+        assert_eq!(None, open("clean").merge_blocker());
+        assert_eq!(None, open("unstable").merge_blocker());
+        assert_eq!(
+            None,
+            pr(r#"{"state": "open", "mergeable_state": null}"#).merge_blocker()
+        );
+        assert_eq!(
+            Some("it has merge conflicts"),
+            open("dirty").merge_blocker()
+        );
+        assert_eq!(
+            Some("the branch protection requires approving reviews or passing checks"),
+            open("blocked").merge_blocker()
+        );
+        assert_eq!(
+            Some("it was already merged"),
+            pr(r#"{"state": "closed", "merged": true, "mergeable_state": "unknown"}"#)
+                .merge_blocker()
+        );
+        assert_eq!(
+            Some("it is a draft"),
+            pr(r#"{"state": "open", "draft": true, "mergeable_state": "draft"}"#).merge_blocker()
+        );
+    }
+
+    #[test]
+    fn missing_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let sha = "90c541806f23a127002de5b4038be731ba1458ca";
+        std::fs::write(
+            dir.path().join(sha),
+            format!(
+                r#"{{"sha": "{sha}", "parents": [], "files": [],
+                    "commit": {{"author": {{"email": "bors@rust-lang.org"}}}}}}"#
+            ),
+        )
+        .unwrap();
+        let server = crate::smoke_test::SmokeTester::new(&[dir.path().to_path_buf()]).unwrap();
+        let api_base = format!("http://{}", server.server_addr());
+
+        let mut easy = Easy::new();
+        let mut client =
+            RepositoryClient::from_pat(&mut easy, &api_base, "token", "rust-lang/rust");
+        assert_eq!(sha, client.get_commit(sha).unwrap().sha);
+        let Err(err) = client.get_commit("90c541806") else {
+            panic!("fetching a missing commit should fail");
+        };
+        assert_eq!(
+            "commit 90c541806 doesn't exist in rust-lang/rust",
+            err.to_string()
+        );
+
+        server.shutdown();
+    }
+}
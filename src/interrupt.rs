@@ -0,0 +1,65 @@
+//! Handling of SIGTERM and SIGINT, for example when the container running a release is stopped.
+//!
+//! Instead of dying silently, we log which phase of the release was interrupted and exit with the
+//! conventional `128 + signal` code, so that monitoring can tell an interrupted run apart from a
+//! crash. Nothing is cleaned up, so the partial work stays around for inspection.
+
+use anyhow::Error;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+
+/// Write end of the pipe the signal handler notifies the watcher thread through.
+static PIPE_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+static PHASE: Mutex<&str> = Mutex::new("starting up");
+
+/// Records what the release is currently doing, to be logged if it's interrupted.
+pub(crate) fn set_phase(phase: &'static str) {
+    *PHASE.lock().unwrap() = phase;
+}
+
+/// Installs the signal handlers. Only async-signal-safe functions can be called from a signal
+/// handler, so it just forwards the signal to a thread doing the actual work.
+pub(crate) fn install() -> Result<(), Error> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    PIPE_WRITE.store(fds[1], Ordering::SeqCst);
+    let mut reader = unsafe { File::from_raw_fd(fds[0]) };
+
+    std::thread::spawn(move || {
+        let mut signal = [0u8];
+        if reader.read_exact(&mut signal).is_ok() {
+            eprintln!();
+            eprintln!(
+                "Interrupted by signal {} while {}, exiting without cleaning up",
+                signal[0],
+                PHASE.lock().unwrap()
+            );
+            std::process::exit(128 + i32::from(signal[0]));
+        }
+    });
+
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        let handler = handle_signal as extern "C" fn(libc::c_int);
+        if unsafe { libc::signal(signal, handler as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+extern "C" fn handle_signal(signal: libc::c_int) {
+    let byte = signal as u8;
+    unsafe {
+        libc::write(
+            PIPE_WRITE.load(Ordering::SeqCst),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        );
+    }
+}
@@ -1,28 +1,45 @@
 #![allow(clippy::rc_buffer)]
 
+mod archives;
+mod audit;
 mod branching;
 mod build_manifest;
 mod config;
 mod curl_helper;
+mod delta;
 mod discourse;
+mod docs;
+mod exit;
+#[cfg(test)]
+mod fake_aws;
 mod fastly;
 mod github;
+mod interrupt;
+mod memory;
+mod notify;
 mod recompress;
+mod release_info;
+mod resign;
+mod selftest;
 mod sign;
 mod smoke_test;
+mod validate;
 
 use std::fs::{self, File, OpenOptions};
 use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::time::Duration;
+use std::process::{Command, ExitCode};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{collections::HashSet, env};
 
 use crate::build_manifest::BuildManifest;
-use crate::config::{Channel, Config};
+use crate::config::{Channel, Config, RecompressStage, Tagger};
+use crate::exit::{Outcome, ReleaseOutcome};
+use crate::memory::MemoryBudget;
 use crate::sign::Signer;
 use crate::smoke_test::SmokeTester;
-use anyhow::Error;
+use anyhow::{Context as _, Error};
 use chrono::Utc;
 use curl::easy::Easy;
 use fs2::FileExt;
@@ -32,6 +49,63 @@ const TARGET: &str = env!("TARGET");
 
 const BLOG_PRIMARY_BRANCH: &str = "master";
 
+/// Limits of the downloads of manifests and other small files, so that a misconfigured URL or a
+/// stalled connection fails the release instead of hanging it or exhausting the memory.
+const MAX_DOWNLOAD_SIZE: usize = 16 * 1024 * 1024;
+const DOWNLOAD_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+const CACHE_CONTROL_IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// How a group of files uploaded to S3 should be served, selected by `aws s3` glob patterns.
+struct UploadClass {
+    patterns: &'static [&'static str],
+    content_type: Option<&'static str>,
+    cache_control: &'static str,
+    /// Tarballs (and their signatures) are cached forever when their location is immutable.
+    tarball: bool,
+}
+
+const UPLOAD_CLASSES: &[UploadClass] = &[
+    UploadClass {
+        patterns: &["*.tar.gz", "*.tar.xz"],
+        content_type: None,
+        cache_control: "public",
+        tarball: true,
+    },
+    UploadClass {
+        patterns: &["*.tar.gz.asc", "*.tar.xz.asc"],
+        content_type: Some("application/pgp-signature"),
+        cache_control: "public",
+        tarball: true,
+    },
+    UploadClass {
+        patterns: &["*.tar.gz.sha256", "*.tar.xz.sha256"],
+        content_type: Some("text/plain; charset=utf-8"),
+        cache_control: "public",
+        tarball: true,
+    },
+    // Channel manifests are replaced by every release, so keep them short-lived in caches.
+    UploadClass {
+        patterns: &["*.toml"],
+        content_type: Some("text/plain; charset=utf-8"),
+        cache_control: "public, max-age=300",
+        tarball: false,
+    },
+    UploadClass {
+        patterns: &["*.toml.asc"],
+        content_type: Some("application/pgp-signature"),
+        cache_control: "public, max-age=300",
+        tarball: false,
+    },
+    UploadClass {
+        patterns: &["*.toml.sha256"],
+        content_type: Some("text/plain; charset=utf-8"),
+        cache_control: "public, max-age=300",
+        tarball: false,
+    },
+];
+
 struct Context {
     work: PathBuf,
     handle: Easy,
@@ -39,22 +113,43 @@ struct Context {
     date: String,
     current_version: Option<String>,
     current_cargo_version: Option<String>,
+    memory: Arc<MemoryBudget>,
 }
 
 // Called as:
 //
 //  $prog work/dir
-fn main() -> Result<(), Error> {
+fn main() -> ExitCode {
+    let result = try_main();
+    if let Err(err) = &result {
+        eprintln!("Error: {:?}", err);
+    }
+    exit::exit_code(&result)
+}
+
+fn try_main() -> Result<Outcome, Error> {
+    interrupt::install()?;
+    let config = Config::from_env()?;
+    config::remove_secret_vars();
+    curl_helper::set_user_agent(&config.user_agent)?;
+    println!("run id: {}", curl_helper::run_id());
     let mut context = Context::new(
         env::current_dir()?.join(env::args_os().nth(1).unwrap()),
-        Config::from_env()?,
+        config,
     )?;
     context.run()
 }
 
 impl Context {
     fn new(work: PathBuf, config: Config) -> Result<Self, Error> {
-        let date = Utc::now().format("%Y-%m-%d").to_string();
+        // The date of the archive directory the release is published to. It's today unless it's
+        // explicitly configured, for example to republish a dev-static release whose scheduled
+        // date slipped without clobbering the previous archive.
+        let date = config
+            .archive_date
+            .unwrap_or_else(|| Utc::now().date_naive())
+            .format("%Y-%m-%d")
+            .to_string();
 
         // Configure the right amount of Rayon threads.
         rayon::ThreadPoolBuilder::new()
@@ -63,6 +158,7 @@ impl Context {
 
         Ok(Context {
             work,
+            memory: Arc::new(MemoryBudget::new(config.memory_budget_mb)),
             config,
             date,
             handle: Easy::new(),
@@ -71,13 +167,66 @@ impl Context {
         })
     }
 
-    fn run(&mut self) -> Result<(), Error> {
+    fn run(&mut self) -> Result<Outcome, Error> {
         let _lock = self.lock()?;
-        match self.config.action {
-            config::Action::PromoteRelease => self.do_release()?,
-            config::Action::PromoteBranches => self.do_branching()?,
+        self.configure_aws_cli()?;
+        Ok(match self.config.action {
+            config::Action::PromoteRelease => self.do_release_channels()?,
+            config::Action::PromoteBranches => {
+                self.do_branching()?;
+                Outcome::Done
+            }
+            config::Action::Invalidate => {
+                self.do_invalidate()?;
+                Outcome::Done
+            }
+            config::Action::PublishDocs => {
+                self.do_publish_docs()?;
+                Outcome::Done
+            }
+            config::Action::SelfTest => {
+                self.do_self_test()?;
+                Outcome::Done
+            }
+            config::Action::PromoteArchive => self.do_promote_archive()?,
+            config::Action::PruneArchives => {
+                self.do_prune_archives()?;
+                Outcome::Done
+            }
+            config::Action::Resign => {
+                self.do_resign()?;
+                Outcome::Done
+            }
+        })
+    }
+
+    /// Releases each of the configured channels in order, stopping at the first failure. Every
+    /// channel goes through its own startup checks, so the run is only skipped if all of them
+    /// were.
+    fn do_release_channels(&mut self) -> Result<Outcome, Error> {
+        let mut outcome = Outcome::Skipped;
+        for channel in self.config.channels.clone() {
+            self.config.channel = channel;
+            self.current_version = None;
+            self.current_cargo_version = None;
+            if self.config.channels.len() > 1 {
+                println!("releasing the {} channel", channel);
+            }
+
+            let result = self.do_release();
+            if let Err(err) = &result {
+                if self.config.notify_on_failure {
+                    self.notify_webhook(Some(err), &[]);
+                }
+            }
+            let result =
+                result.with_context(|| format!("failed to release the {} channel", channel))?;
+            println!("{} channel: {:?}", channel, result);
+            if result.outcome() == Outcome::Done {
+                outcome = Outcome::Done;
+            }
         }
-        Ok(())
+        Ok(outcome)
     }
 
     /// Locks execution of concurrent invocations of this script in case one
@@ -100,39 +249,48 @@ impl Context {
             return Ok(commit);
         }
 
-        let git_ref = match self.config.channel {
-            Channel::Nightly => "refs/heads/master",
-            Channel::Beta => "refs/heads/beta",
-            Channel::Stable => "refs/heads/stable",
+        let git_ref = match &self.config.override_ref {
+            Some(git_ref) if git_ref.starts_with("refs/") => git_ref.clone(),
+            Some(branch) => format!("refs/heads/{}", branch),
+            None => match self.config.channel {
+                Channel::Nightly => "refs/heads/master",
+                Channel::Beta => "refs/heads/beta",
+                Channel::Stable => "refs/heads/stable",
+            }
+            .to_string(),
         };
 
-        // git2 requires a git repository to be able to connect to a remote and fetch metadata, so
-        // this creates an empty repository in a temporary directory. It will be deleted once the
-        // function returns.
-        let temp = tempfile::tempdir()?;
-        let repo = git2::Repository::init(temp.path())?;
-
-        let mut remote = repo.remote("origin", &self.config.repository)?;
-        remote.connect(git2::Direction::Fetch)?;
-
-        for head in remote.list()? {
-            if head.name() == git_ref {
-                return Ok(hex::encode(head.oid().as_bytes()));
+        // The mirrors are only queried when the main repository is unreachable or doesn't have
+        // the ref, so that an outage of the main repository doesn't prevent the release.
+        let mut errors = Vec::new();
+        let repositories =
+            std::iter::once(&self.config.repository).chain(&self.config.repository_mirrors);
+        for repository in repositories {
+            match find_git_ref(repository, &git_ref) {
+                Ok(Some(commit)) => return Ok(commit),
+                Ok(None) => errors.push(format!("missing git ref in {}: {}", repository, git_ref)),
+                Err(err) => {
+                    println!("failed to list the refs of {}: {}", repository, err);
+                    errors.push(format!(
+                        "failed to list the refs of {}: {}",
+                        repository, err
+                    ));
+                }
             }
         }
-        anyhow::bail!("missing git ref in {}: {}", self.config.repository, git_ref);
+        anyhow::bail!("{}", errors.join("\n"));
     }
 
-    fn do_release(&mut self) -> Result<(), Error> {
+    fn do_release(&mut self) -> Result<ReleaseOutcome, Error> {
+        interrupt::set_phase("running the startup checks");
         let rev = self.get_commit_sha()?;
         println!("{} rev is {}", self.config.channel, rev);
 
         // Download the current live manifest for the channel we're releasing.
         // Through that we learn the current version of the release.
         let manifest = self.download_top_level_manifest()?;
-        let previous_version = manifest["pkg"]["rust"]["version"]
-            .as_str()
-            .expect("rust version not a string");
+        let previous_version = validate::rust_version(&manifest)
+            .context("failed to find the previous release version in the live manifest")?;
         println!("previous version: {}", previous_version);
 
         // If the previously released version is the same rev, then there's
@@ -140,7 +298,7 @@ impl Context {
         if !self.config.bypass_startup_checks && previous_version.contains(&rev[..7]) {
             println!("found rev in previous version, skipping");
             println!("set PROMOTE_RELEASE_BYPASS_STARTUP_CHECKS=1 to bypass the check");
-            return Ok(());
+            return self.skip_release(&rev, previous_version, ReleaseOutcome::SkippedSameCommit);
         }
 
         // During normal operations we don't want multiple releases to happen on the same channel
@@ -148,11 +306,11 @@ impl Context {
         // environment variable if the person doing the release really wants that.
         if !self.config.bypass_startup_checks && self.dated_manifest_exists()? {
             println!(
-                "another release on the {} channel was done today ({}), skipping",
+                "another release on the {} channel was already archived on {}, skipping",
                 self.config.channel, self.date
             );
             println!("set PROMOTE_RELEASE_BYPASS_STARTUP_CHECKS=1 to bypass the check");
-            return Ok(());
+            return self.skip_release(&rev, previous_version, ReleaseOutcome::SkippedAlreadyToday);
         }
 
         // We may still not do a release if the version number hasn't changed.
@@ -163,59 +321,94 @@ impl Context {
         // different and the versions are the same then there's nothing for us
         // to do. This represents a scenario where changes have been merged to
         // the stable/beta branch but the version bump hasn't happened yet.
-        self.download_artifacts(&rev)?;
+        check_free_space(
+            &self.work,
+            self.config.min_free_space_mb * 1024 * 1024,
+            "download the artifacts",
+        )?;
+        interrupt::set_phase("downloading the artifacts");
+        if !self.download_artifacts(&rev)? {
+            println!(
+                "no artifacts found for rev {}, skipping (is this a stable/beta branch awaiting a PR?)",
+                rev
+            );
+            return self.skip_release(&rev, previous_version, ReleaseOutcome::SkippedNoArtifacts);
+        }
+        self.check_artifacts_channel()?;
+
         // The bypass_startup_checks condition is after the function call since we need that
         // function to run even if we wan to discard its output (it fetches and stores the current
         // version we're about to release).
         if self.current_version_same(previous_version)? && !self.config.bypass_startup_checks {
             println!("version hasn't changed, skipping");
             println!("set PROMOTE_RELEASE_BYPASS_STARTUP_CHECKS=1 to bypass the check");
-            return Ok(());
+            return self.skip_release(&rev, previous_version, ReleaseOutcome::SkippedSameVersion);
         }
+        self.record_version_decision(&rev, previous_version, ReleaseOutcome::Released)?;
 
+        self.remove_excluded_targets()?;
         self.assert_all_components_present()?;
+        self.check_components_channel()?;
+        self.check_tarball_sizes()?;
 
         // Produce a full set of artifacts so that pruning works correctly.
         //
         // Nightly (1.71+) supports this upstream without the extra recompression, see
         // https://github.com/rust-lang/rust/pull/110436. We expect that this snippet can be fully
         // dropped once that PR hits stable.
-        if self.config.channel != Channel::Nightly {
+        if self.config.recompress_stage() == RecompressStage::BeforePruning {
+            interrupt::set_phase("recompressing the artifacts");
             self.recompress(&self.dl_dir())?;
         }
 
         // Ok we've now determined that a release needs to be done.
 
-        let mut signer = Signer::new(&self.config)?;
+        let mut signer = Signer::new(&self.config, self.memory.clone())?;
 
         let build_manifest = BuildManifest::new(self)?;
         let smoke_test = SmokeTester::new(&[self.smoke_manifest_dir(), self.dl_dir()])?;
 
         // This step is just a discovery of unused files so we can prune them prior to
         // recompression...
-        let execution = build_manifest.run(
-            &format!("{}/{}", self.config.upload_addr, self.config.upload_dir),
-            &self.real_manifest_dir(),
-        )?;
+        interrupt::set_phase("generating the manifests");
+        let execution = build_manifest.run(&self.upload_base(), &self.real_manifest_dir())?;
+
+        // When debugging why files are missing from a release, only list what would be pruned
+        // and stop before anything is modified or published.
+        if self.config.prune_dry_run {
+            let unused = self.unused_files(&execution.shipped_files)?;
+            for name in &unused {
+                println!("would prune unused file {}", name.display());
+            }
+            println!(
+                "{} files would be pruned, stopping as PROMOTE_RELEASE_PRUNE_DRY_RUN is set",
+                unused.len()
+            );
+            return Ok(ReleaseOutcome::PruneDryRun);
+        }
 
         // Removes files that we are not shipping from the files we're about to upload.
         self.prune_unused_files(&execution.shipped_files)?;
 
         // Generate recompressed artifacts from the input set. This invalidates signatures etc
         // produced in the earlier step so we'll need to re-run the manifest building.
-        if self.config.channel == Channel::Nightly {
+        if self.config.recompress_stage() == RecompressStage::AfterPruning {
+            interrupt::set_phase("recompressing the artifacts");
             self.recompress(&self.dl_dir())?;
         }
 
+        self.audit_tarballs()?;
+
         // Since we recompressed, need to clear out the checksum cache.
         build_manifest.clear_checksum_cache()?;
 
+        interrupt::set_phase("generating the manifests");
         // Now generate the real manifests, pointing to the public download endpoint. This will
         // also generate a cache of all the checksums generated by build-manifest.
-        let execution = build_manifest.run(
-            &format!("{}/{}", self.config.upload_addr, self.config.upload_dir),
-            &self.real_manifest_dir(),
-        )?;
+        let execution = build_manifest.run(&self.upload_base(), &self.real_manifest_dir())?;
+        self.validate_manifest(&self.real_manifest_dir(), &manifest)?;
+        self.check_total_size(&self.real_manifest_dir(), &manifest)?;
+        self.write_delta_manifest(&self.real_manifest_dir(), &manifest)?;
 
         // Then another set of manifests is generated pointing to the smoke test server. These
         // manifests will be discarded later.
@@ -227,13 +420,38 @@ impl Context {
         // Sign both the downloaded artifacts and all the generated manifests. The signatures
         // of the downloaded files and the real manifests are permanent, while the signatures
         // for the smoke test manifests will be discarded later.
+        interrupt::set_phase("signing the artifacts");
         signer.override_checksum_cache(execution.checksum_cache);
-        signer.sign_directory(&self.dl_dir())?;
-        signer.sign_directory(&self.real_manifest_dir())?;
+        let mut signatures = signer.sign_directory(&self.dl_dir())?;
+        signatures.extend(signer.sign_directory(&self.real_manifest_dir())?);
         signer.sign_directory(&self.smoke_manifest_dir())?;
+        signer.append_signatures_log(&signatures)?;
+        signer.verify_signatures(&self.real_manifest_dir(), &self.dl_dir())?;
+
+        // Make sure the manifest doesn't point to any file we're not about to upload.
+        self.verify_manifest_artifacts(&self.real_manifest_dir())?;
 
         // Ensure the release is downloadable from rustup and can execute a basic binary.
-        smoke_test.test(&self.config.channel)?;
+        interrupt::set_phase("smoke testing the release");
+        if self.config.skip_smoke_test {
+            println!();
+            println!(
+                "WARNING! Skipped the smoke test, the release was never installed with rustup"
+            );
+            println!("Unset PROMOTE_RELEASE_SKIP_SMOKE_TEST if you're in production");
+            println!();
+            smoke_test.shutdown();
+        } else {
+            smoke_test.set_xz_only(self.config.smoke_test_xz_only);
+            smoke_test.test(
+                &self.config.channel,
+                Duration::from_secs(self.config.smoke_test_timeout),
+                self.config.smoke_test_edition.as_deref(),
+                self.config.smoke_test_dependency.as_deref(),
+                self.config.smoke_test_offline || self.config.smoke_test_vendor_dir.is_some(),
+                self.config.smoke_test_vendor_dir.as_deref(),
+            )?;
+        }
 
         // Merge the generated manifests with the downloaded artifacts.
         for entry in std::fs::read_dir(self.real_manifest_dir())? {
@@ -243,30 +461,267 @@ impl Context {
             }
         }
 
+        self.check_release_date();
+        interrupt::set_phase("publishing the archive");
         self.publish_archive()?;
-        self.publish_docs()?;
-        self.publish_release()?;
+        if self.config.archive_only {
+            self.publish_docs(false)?;
+            self.clean_up_artifacts();
+            println!(
+                "the release was only archived in {}/{}/{}/, make it live with \
+                 PROMOTE_RELEASE_ACTION=promote-archive",
+                self.config.upload_bucket, self.config.upload_dir, self.date
+            );
+            return Ok(ReleaseOutcome::Archived);
+        }
 
-        self.invalidate_releases()?;
+        self.go_live(&rev, &mut signer, &manifest)?;
+        Ok(ReleaseOutcome::Released)
+    }
+
+    /// Records why the release of the channel was skipped, see `record_version_decision`.
+    fn skip_release(
+        &self,
+        rev: &str,
+        previous_version: &str,
+        outcome: ReleaseOutcome,
+    ) -> Result<ReleaseOutcome, Error> {
+        self.record_version_decision(rev, previous_version, outcome)?;
+        Ok(outcome)
+    }
 
-        // Clean up after ourselves to avoid leaving gigabytes of artifacts
-        // around.
-        let _ = fs::remove_dir_all(self.dl_dir());
+    /// Makes the release in `dl_dir` live, after it was published to the dated archive: this
+    /// publishes the artifacts and the docs, announces the release and tags it. `previous` is the
+    /// manifest of the release being replaced.
+    fn go_live(
+        &mut self,
+        rev: &str,
+        signer: &mut Signer,
+        previous: &toml::Value,
+    ) -> Result<(), Error> {
+        // The announcements mention the notable version bumps, which we need the manifest for
+        // before the artifacts are cleaned up.
+        let manifest: toml::Value = fs::read_to_string(
+            self.dl_dir()
+                .join(format!("channel-rust-{}.toml", self.config.channel)),
+        )?
+        .parse()?;
+        let changes = delta::version_changes(previous, &manifest);
+
+        interrupt::set_phase("publishing the release");
+        self.publish_docs(true)?;
+        self.publish_release(rev)?;
+
+        self.invalidate_releases()?;
+        self.clean_up_artifacts();
 
         // This takes care of announcing stable releases (whether dev-static or not) on the blog
-        // and internals.
-        self.blog_and_discourse()?;
+        // and internals. The release is already published at this point, so failing to announce
+        // it must not fail the whole run: re-running would try to publish it again.
+        interrupt::set_phase("announcing the release");
+        let mut warnings = Vec::new();
+        if let Err(err) = self.blog_and_discourse(&changes) {
+            let warning = format!(
+                "failed to announce the release, it needs to be announced manually: {:?}",
+                err
+            );
+            println!();
+            println!("WARNING! {}", warning);
+            println!();
+            warnings.push(warning);
+        }
 
         // We do this last, since it triggers triagebot posting the GitHub
         // release announcement (and since this is not actually really
         // important).
-        self.tag_release(&rev, &mut signer)?;
+        interrupt::set_phase("tagging the release");
+        self.tag_release(rev, signer)?;
+
+        self.notify_webhook(None, &warnings);
+
+        Ok(())
+    }
+
+    /// Clean up after ourselves to avoid leaving gigabytes of artifacts around.
+    fn clean_up_artifacts(&self) {
+        if self.config.keep_artifacts {
+            println!(
+                "keeping the release artifacts in {} for inspection",
+                self.dl_dir().display()
+            );
+        } else {
+            let _ = fs::remove_dir_all(self.dl_dir());
+        }
+        let _ = fs::remove_dir_all(self.recompress_cache_dir());
+    }
+
+    fn do_promote_archive(&mut self) -> Result<Outcome, Error> {
+        println!(
+            "promoting the {} release archived on {}",
+            self.config.channel, self.date
+        );
+        let rev = self.download_archive()?;
+        println!("{} rev is {}", self.config.channel, rev);
+        let previous = self.download_top_level_manifest()?;
+
+        let mut signer = Signer::new(&self.config, self.memory.clone())?;
+        self.go_live(&rev, &mut signer, &previous)?;
+        Ok(Outcome::Done)
+    }
+
+    /// Downloads the release archived on `self.date` into `dl_dir`, returning the commit it was
+    /// built from. The versions of rustc and cargo are read from its manifest.
+    ///
+    /// Every channel released on the same day shares the archive directory, so only the channel
+    /// manifest and the files it references are downloaded, along with their signatures and
+    /// checksums.
+    fn download_archive(&mut self) -> Result<String, Error> {
+        let dl = self.dl_dir();
+        let _ = fs::remove_dir_all(&dl);
+        fs::create_dir_all(&dl)?;
+        let archive = format!(
+            "s3://{}/{}/{}/",
+            self.config.upload_bucket, self.config.upload_dir, self.date
+        );
+        let manifest_name = format!("channel-rust-{}.toml", self.config.channel);
+        let manifest_path = dl.join(&manifest_name);
+        let status = self
+            .aws_s3()
+            .arg("cp")
+            .arg("--only-show-errors")
+            .arg(format!("{}{}", archive, manifest_name))
+            .arg(&manifest_path)
+            .status()?;
+        if !status.success() {
+            anyhow::bail!(
+                "no {} release was archived on {}",
+                self.config.channel,
+                self.date
+            );
+        }
+        let manifest: toml::Value = fs::read_to_string(&manifest_path)?.parse()?;
+
+        let mut cmd = self.aws_s3();
+        cmd.arg("cp")
+            .arg("--recursive")
+            .arg("--only-show-errors")
+            .arg("--exclude")
+            .arg("*")
+            .arg("--include")
+            .arg(format!("{}.asc", manifest_name))
+            .arg("--include")
+            .arg(format!("{}.sha256", manifest_name));
+        for file in validate::referenced_files(&manifest) {
+            for suffix in ["", ".asc", ".sha256"] {
+                cmd.arg("--include").arg(format!("{}{}", file, suffix));
+            }
+        }
+        run(cmd.arg(&archive).arg(format!("{}/", dl.display())))?;
+
+        let rev = manifest
+            .get("pkg")
+            .and_then(|pkg| pkg.get("rust"))
+            .and_then(|rust| rust.get("git_commit_hash"))
+            .and_then(|hash| hash.as_str())
+            .ok_or_else(|| anyhow::anyhow!("missing the commit of the archived release"))?;
+
+        // The versions are only detected on stable and beta during a normal release.
+        if self.config.channel != Channel::Nightly {
+            let version = validate::rust_version(&manifest)?;
+            self.current_version = version.split(' ').next().map(String::from);
+            self.current_cargo_version = validate::package_version(&manifest, "cargo")
+                .and_then(|version| version.split(' ').next())
+                .map(String::from);
+        }
+        Ok(rev.to_string())
+    }
+
+    fn do_publish_docs(&mut self) -> Result<(), Error> {
+        let version = match self.config.channel {
+            Channel::Stable => {
+                let Some(version) = self.config.docs_version.clone() else {
+                    anyhow::bail!(
+                        "PROMOTE_RELEASE_DOCS_VERSION is required to publish stable docs"
+                    );
+                };
+                self.current_version = Some(version.clone());
+                version
+            }
+            Channel::Beta | Channel::Nightly => self.config.channel.to_string(),
+        };
+        println!("publishing the {} docs", version);
+
+        // Only the docs tarballs are needed, and they're all kept in the release directory.
+        let dl = self.dl_dir();
+        let _ = fs::remove_dir_all(&dl);
+        fs::create_dir_all(&dl)?;
+        let target = "x86_64-unknown-linux-gnu";
+        run(self
+            .aws_s3()
+            .arg("cp")
+            .arg("--recursive")
+            .arg("--only-show-errors")
+            .arg("--exclude")
+            .arg("*")
+            .arg("--include")
+            .arg(format!("rust-docs-{}-{}.tar.gz", version, target))
+            .arg("--include")
+            .arg(format!("rustc-docs-{}-{}.tar.gz", version, target))
+            .arg(format!(
+                "s3://{}/{}/",
+                self.config.upload_bucket, self.config.upload_dir
+            ))
+            .arg(format!("{}/", dl.display())))?;
+        let tarball = format!("rust-docs-{}-{}.tar.gz", version, target);
+        if !dl.join(&tarball).is_file() {
+            anyhow::bail!("{} is missing from the release directory", tarball);
+        }
+
+        self.publish_docs(true)?;
+
+        let _ = fs::remove_dir_all(&dl);
+        Ok(())
+    }
+
+    fn do_invalidate(&mut self) -> Result<(), Error> {
+        println!(
+            "invalidating the caches of the {} channel",
+            self.config.channel
+        );
+        self.invalidate_releases()?;
+        self.invalidate_docs(&self.config.channel.to_string(), None)?;
+        Ok(())
+    }
 
+    /// Writes what we detected and decided to do in a machine-readable file in the work
+    /// directory, if PROMOTE_RELEASE_VERSION_REPORT is set. Automation around the release process
+    /// can use it to check we're shipping the expected version.
+    fn record_version_decision(
+        &self,
+        rev: &str,
+        previous_version: &str,
+        outcome: ReleaseOutcome,
+    ) -> Result<(), Error> {
+        if !self.config.version_report {
+            return Ok(());
+        }
+        let report = serde_json::json!({
+            "channel": self.config.channel.to_string(),
+            "date": self.date,
+            "rev": rev,
+            "previous_version": previous_version,
+            "version": self.current_version,
+            "cargo_version": self.current_cargo_version,
+            "decision": outcome.decision(),
+        });
+        let path = self.work.join("version-report.json");
+        fs::write(&path, serde_json::to_vec_pretty(&report)?)?;
+        println!("wrote version report to {}", path.display());
         Ok(())
     }
 
     fn load_version(&mut self, mut filter: impl FnMut(&str) -> bool) -> Result<String, Error> {
-        let mut current = None;
+        let mut inspected = Vec::new();
         for e in self.dl_dir().read_dir()? {
             let e = e?;
             let filename = e.file_name().into_string().unwrap();
@@ -276,29 +731,18 @@ impl Context {
             println!("looking inside {} for a version", filename);
 
             let file = File::open(e.path())?;
-            let reader = xz2::read::XzDecoder::new(file);
-            let mut archive = tar::Archive::new(reader);
-
-            let mut version_file = None;
-            for entry in archive.entries()? {
-                let entry = entry?;
-                let path = entry.path()?;
-                if let Some(path) = path.iter().nth(1) {
-                    if path == Path::new("version") {
-                        version_file = Some(entry);
-                        break;
-                    }
-                }
-            }
-            if let Some(mut entry) = version_file {
-                let mut contents = String::new();
-                entry.read_to_string(&mut contents)?;
-                current = Some(contents);
-
-                break;
+            if let Some(version) = find_version(xz2::read::XzDecoder::new(file))? {
+                return Ok(version);
             }
+            inspected.push(filename);
+        }
+        if inspected.is_empty() {
+            anyhow::bail!("no archives with a version: no matching archives were found");
         }
-        current.ok_or_else(|| anyhow::anyhow!("no archives with a version"))
+        anyhow::bail!(
+            "no archives with a version, none of these contains a version file: {}",
+            inspected.join(", ")
+        )
     }
 
     fn current_version_same(&mut self, prev: &str) -> Result<bool, Error> {
@@ -337,38 +781,77 @@ impl Context {
             );
         }
 
-        Ok(prev_version == current_rustc)
+        Ok(same_release(prev_version, current_rustc))
     }
 
     /// Make sure this release comes with a minimum of components.
     ///
-    /// Note that we already don't merge PRs in rust-lang/rust that don't
-    /// build cargo, so this cannot realistically fail.
+    /// The list of components can be configured with PROMOTE_RELEASE_REQUIRED_COMPONENTS, and it
+    /// defaults to rustc, rust-std and cargo on nightly (and nothing on other channels). Note that
+    /// we already don't merge PRs in rust-lang/rust that don't build cargo, so the default cannot
+    /// realistically fail.
     fn assert_all_components_present(&self) -> Result<(), Error> {
-        if self.config.channel != Channel::Nightly {
+        let defaults;
+        let required = if !self.config.required_components.is_empty() {
+            &self.config.required_components
+        } else if self.config.channel == Channel::Nightly {
+            // For now, produce nightlies even if rustfmt, rls or clippy are missing.
+            defaults = ["rustc", "rust-std", "cargo"].map(String::from).to_vec();
+            &defaults
+        } else {
             return Ok(());
-        }
+        };
 
-        let mut components = Vec::new();
+        let target = "x86_64-unknown-linux-gnu";
+        let release = self.config.channel.release_name(self);
+        let mut files = Vec::new();
         for entry in self.dl_dir().read_dir()? {
-            let name = entry?.file_name().into_string().unwrap();
-            if name.contains("x86_64-unknown-linux-gnu") {
-                components.push(name);
-            }
+            files.push(entry?.file_name().into_string().unwrap());
+        }
+
+        let missing = required
+            .iter()
+            .filter(|component| {
+                let prefix = format!("{}-{}-{}.", component, release, target);
+                !files.iter().any(|file| file.starts_with(&prefix))
+            })
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            anyhow::bail!("required components missing for {}: {:?}", target, missing);
         }
 
-        assert!(components.iter().any(|s| s.starts_with("rustc-")));
-        assert!(components.iter().any(|s| s.starts_with("rust-std-")));
-        assert!(components.iter().any(|s| s.starts_with("cargo-")));
-        // For now, produce nightlies even if these are missing.
-        // assert!(components.iter().any(|s| s.starts_with("rustfmt-")));
-        // assert!(components.iter().any(|s| s.starts_with("rls-")));
-        // assert!(components.iter().any(|s| s.starts_with("clippy-")));
+        Ok(())
+    }
 
+    /// Removes the artifacts of the targets in PROMOTE_RELEASE_EXCLUDE_TARGETS from `dl_dir`, so
+    /// that build-manifest marks those targets as unavailable in the manifests.
+    fn remove_excluded_targets(&self) -> Result<(), Error> {
+        for target in &self.config.exclude_targets {
+            let marker = format!("-{}.tar.", target);
+            let mut removed = 0;
+            for entry in self.dl_dir().read_dir()? {
+                let entry = entry?;
+                if entry.file_name().to_string_lossy().contains(&marker) {
+                    fs::remove_file(entry.path())?;
+                    removed += 1;
+                }
+            }
+            if removed == 0 {
+                println!();
+                println!(
+                    "WARNING! No artifacts found for the excluded target {}",
+                    target
+                );
+                println!();
+            } else {
+                println!("excluded {} files of target {}", removed, target);
+            }
+        }
         Ok(())
     }
 
-    fn download_artifacts(&mut self, rev: &str) -> Result<(), Error> {
+    /// Downloads the CI artifacts of `rev` into `dl_dir`, returning whether there were any.
+    fn download_artifacts(&mut self, rev: &str) -> Result<bool, Error> {
         let dl = self.dl_dir();
         let _ = fs::remove_dir_all(&dl);
         fs::create_dir_all(&dl)?;
@@ -378,15 +861,16 @@ impl Context {
             .arg("cp")
             .arg("--recursive")
             .arg("--only-show-errors")
-            .arg(&self.s3_artifacts_url(&format!("{}/", rev)))
+            .arg(self.s3_artifacts_url(&format!("{}/", rev)))
             .arg(format!("{}/", dl.display())))?;
 
         let mut files = dl.read_dir()?;
         if files.next().is_none() {
-            panic!(
-                "appears that this rev doesn't have any artifacts, \
-                    is this a stable/beta branch awaiting a PR?"
-            );
+            return Ok(false);
+        }
+
+        if self.config.build_manifest_path.is_none() {
+            build_manifest::verify_tarball_checksums(&dl)?;
         }
 
         // Delete residue signature/hash files. These may come around for a few
@@ -399,56 +883,79 @@ impl Context {
         // 2. We're making a stable release. The stable release is first signed
         //    with the dev key and then it's signed with the prod key later. We
         //    want the prod key to overwrite the dev key signatures.
+        //
+        // When reusing checksums the hash files are kept around, and the signer will verify them
+        // instead of generating new ones.
         for file in dl.read_dir()? {
             let file = file?;
             let path = file.path();
             match path.extension().and_then(|s| s.to_str()) {
                 // Delete signature/hash files...
-                Some("asc") | Some("sha256") => {
+                Some("asc") => {
+                    fs::remove_file(&path)?;
+                }
+                Some("sha256") if !self.config.reuse_checksums => {
                     fs::remove_file(&path)?;
                 }
                 _ => {}
             }
         }
 
-        Ok(())
+        Ok(true)
     }
 
     fn prune_unused_files(&self, shipped_files: &HashSet<PathBuf>) -> Result<(), Error> {
+        for name in self.unused_files(shipped_files)? {
+            std::fs::remove_file(self.dl_dir().join(&name))?;
+            println!("pruned unused file {}", name.display());
+        }
+
+        Ok(())
+    }
+
+    /// Returns the names of the files in `dl_dir` which are not part of the release.
+    fn unused_files(&self, shipped_files: &HashSet<PathBuf>) -> Result<Vec<PathBuf>, Error> {
+        let mut unused = Vec::new();
         for entry in std::fs::read_dir(self.dl_dir())? {
             let entry = entry?;
             if let Some(name) = entry.path().file_name() {
                 let name = Path::new(name);
+                // Checksums carried over from the previous release are kept as long as the file
+                // they refer to is shipped.
+                if self.config.reuse_checksums
+                    && name.extension().and_then(|s| s.to_str()) == Some("sha256")
+                    && shipped_files.contains(Path::new(name.file_stem().unwrap()))
+                {
+                    continue;
+                }
                 if !shipped_files.contains(name) {
-                    std::fs::remove_file(entry.path())?;
-                    println!("pruned unused file {}", name.display());
+                    unused.push(name.to_path_buf());
                 }
             }
         }
+        unused.sort();
 
-        Ok(())
+        Ok(unused)
     }
 
     fn publish_archive(&mut self) -> Result<(), Error> {
-        let bucket = &self.config.upload_bucket;
-        let dir = &self.config.upload_dir;
-        let dst = format!("s3://{}/{}/{}/", bucket, dir, self.date);
-        run(self
-            .aws_s3()
-            .arg("cp")
-            .arg("--recursive")
-            .arg("--only-show-errors")
-            .arg("--metadata-directive")
-            .arg("REPLACE")
-            .arg("--cache-control")
-            .arg("public")
-            .arg("--storage-class")
-            .arg(&self.config.storage_class)
-            .arg(format!("{}/", self.dl_dir().display()))
-            .arg(&dst))
+        self.upload_to_all_buckets(|endpoint_url, bucket, dir| {
+            let dst = format!("s3://{}/{}/{}/", bucket, dir, self.date);
+            // Everything in the dated archive is never changed after the release.
+            self.upload_dl_dir(
+                endpoint_url,
+                &dst,
+                true,
+                &["--metadata-directive", "REPLACE"],
+            )?;
+            self.log_upload_stats(&dst)
+        })
     }
 
-    fn publish_docs(&mut self) -> Result<(), Error> {
+    /// Publishes the documentation of the release. Unless `live` is set, only the docs that are not
+    /// visible until the release is live are published, which is `/doc/$version/` on stable and
+    /// nothing on the other channels.
+    fn publish_docs(&mut self, live: bool) -> Result<(), Error> {
         let (version, upload_dir) = match self.config.channel {
             Channel::Stable => {
                 let vers = &self.current_version.as_ref().unwrap()[..];
@@ -457,17 +964,25 @@ impl Context {
             Channel::Beta => ("beta", "beta"),
             Channel::Nightly => ("nightly", "nightly"),
         };
+        if !live && upload_dir != "stable" {
+            return Ok(());
+        }
 
         // Pull out HTML documentation from one of the `rust-docs-*` tarballs.
         // For now we just arbitrarily pick x86_64-unknown-linux-gnu.
+        //
+        // The documentation is first extracted in a separate directory, which is moved into place
+        // only once everything is extracted. This ensures an interrupted extraction is never
+        // uploaded, and that retrying always starts from a clean directory.
         let docs = self.work.join("docs");
-        drop(fs::remove_dir_all(&docs));
-        fs::create_dir_all(&docs)?;
+        let extracting = self.work.join("docs-extracting");
+        drop(fs::remove_dir_all(&extracting));
+        fs::create_dir_all(&extracting)?;
         let target = "x86_64-unknown-linux-gnu";
 
         // Unpack the regular documentation tarball.
         let tarball_prefix = format!("rust-docs-{}-{}", version, target);
-        let tarball = format!("{}.tar.gz", self.dl_dir().join(&tarball_prefix).display());
+        let docs_tarball = self.dl_dir().join(format!("{}.tar.gz", tarball_prefix));
         let tarball_dir = format!("{}/rust-docs/share/doc/rust/html", tarball_prefix);
 
         // The `m` flag touches all extracted files, therefore setting their modification time
@@ -475,10 +990,10 @@ impl Context {
         // local files.
         run(Command::new("tar")
             .arg("xfm")
-            .arg(&tarball)
+            .arg(&docs_tarball)
             .arg("--strip-components=6")
             .arg(&tarball_dir)
-            .current_dir(&docs))?;
+            .current_dir(&extracting))?;
 
         // Construct path to rustc documentation.
         let tarball_prefix = format!("rustc-docs-{}-{}", version, target);
@@ -486,7 +1001,7 @@ impl Context {
 
         // Only create and unpack rustc docs if artefacts include tarball.
         if Path::new(&tarball).exists() {
-            let rustc_docs = docs.join("nightly-rustc");
+            let rustc_docs = extracting.join("nightly-rustc");
             fs::create_dir_all(&rustc_docs)?;
 
             // Construct the path that contains the documentation inside the tarball.
@@ -522,62 +1037,221 @@ impl Context {
             }
         }
 
+        drop(fs::remove_dir_all(&docs));
+        fs::rename(&extracting, &docs)?;
+
         // Upload this to `/doc/$channel`
-        let bucket = &self.config.upload_bucket;
-        let dst = format!("s3://{}/doc/{}/", bucket, upload_dir);
-        run(self
-            .aws_s3()
-            .arg("sync")
-            .arg("--storage-class")
-            .arg(&self.config.storage_class)
-            .arg("--delete")
-            .arg("--only-show-errors")
-            .arg(format!("{}/", docs.display()))
-            .arg(&dst))?;
-        self.invalidate_docs(upload_dir)?;
+        if live {
+            let mut changed = self.upload_docs(&docs, upload_dir)?;
+            if self.config.docs_tarball {
+                self.upload_docs_tarball(&docs_tarball, upload_dir)?;
+                changed
+                    .iter_mut()
+                    .for_each(|c| c.push(docs::TARBALL_NAME.into()));
+            }
+            self.invalidate_docs(upload_dir, changed.as_deref())?;
+        }
 
-        // Stable artifacts also go to `/doc/$version/
+        // Stable artifacts also go to `/doc/$version/. Those never change once published, so
+        // they're skipped when re-running the same release.
         if upload_dir == "stable" {
-            let dst = format!("s3://{}/doc/{}/", bucket, version);
-            run(self
-                .aws_s3()
-                .arg("sync")
-                .arg("--storage-class")
-                .arg(&self.config.storage_class)
-                .arg("--delete")
-                .arg("--only-show-errors")
-                .arg(format!("{}/", docs.display()))
-                .arg(&dst))?;
-            self.invalidate_docs(version)?;
+            if self.docs_already_published(&docs, version)? {
+                println!("the {} docs were already published, skipping them", version);
+            } else {
+                let mut changed = self.upload_docs(&docs, version)?;
+                if self.config.docs_tarball {
+                    self.upload_docs_tarball(&docs_tarball, version)?;
+                    changed
+                        .iter_mut()
+                        .for_each(|c| c.push(docs::TARBALL_NAME.into()));
+                }
+                self.invalidate_docs(version, changed.as_deref())?;
+            }
         }
 
         Ok(())
     }
 
-    fn invalidate_docs(&self, dir: &str) -> Result<(), Error> {
-        self.invalidate_cloudfront(
-            &self.config.cloudfront_doc_id,
-            &[if dir == "stable" {
-                "/*".into()
+    /// Invalidates the docs in `/doc/$dir/`, only for the `changed` files if they are known.
+    fn invalidate_docs(&self, dir: &str, changed: Option<&[String]>) -> Result<(), Error> {
+        let paths = docs::invalidation_paths(dir, changed);
+        if paths.is_empty() {
+            println!("no docs changed in {}, skipping the invalidation", dir);
+            return Ok(());
+        }
+        self.invalidate_cloudfront(&self.config.cloudfront_doc_id, &paths)
+    }
+
+    fn publish_release(&mut self, rev: &str) -> Result<(), Error> {
+        let mut pointers = Vec::new();
+        if let Some(latest) = self.write_latest_file()? {
+            pointers.push((latest, "text/plain; charset=utf-8"));
+        }
+        if let Some(info) = self.write_release_info(rev)? {
+            pointers.push((info, "application/json"));
+        }
+        self.upload_to_all_buckets(|endpoint_url, bucket, dir| {
+            let dst = format!("s3://{}/{}/", bucket, dir);
+            // Stable tarballs include the version number in their name, while the beta and
+            // nightly ones are overwritten by every release.
+            let immutable = self.config.channel == Channel::Stable;
+            self.upload_dl_dir(endpoint_url, &dst, immutable, &[])?;
+            self.log_upload_stats(&dst)?;
+
+            // The pointer files are uploaded last, so that they only ever point to a release that
+            // was fully published.
+            for (pointer, content_type) in &pointers {
+                run(self
+                    .aws_command(endpoint_url, "s3")
+                    .arg("cp")
+                    .arg("--only-show-errors")
+                    .arg("--storage-class")
+                    .arg(&self.config.storage_class)
+                    .arg("--cache-control")
+                    .arg("public, max-age=300")
+                    .arg("--content-type")
+                    .arg(content_type)
+                    .arg(pointer)
+                    .arg(&dst))?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Writes the `latest-$channel.txt` file containing the version being released, if enabled
+    /// with PROMOTE_RELEASE_UPLOAD_LATEST_FILE. Mirrors can poll it to cheaply detect new releases.
+    fn write_latest_file(&self) -> Result<Option<PathBuf>, Error> {
+        if !self.config.upload_latest_file {
+            return Ok(None);
+        }
+
+        // The version is only detected from the tarballs on stable and beta, so the nightly one
+        // is read from the manifest we're about to publish.
+        let version = match &self.current_version {
+            Some(version) => version.clone(),
+            None => {
+                let manifest = self
+                    .dl_dir()
+                    .join(format!("channel-rust-{}.toml", self.config.channel));
+                let manifest: toml::Value = fs::read_to_string(manifest)?.parse()?;
+                validate::rust_version(&manifest)?.to_string()
+            }
+        };
+
+        let path = self
+            .work
+            .join(format!("latest-{}.txt", self.config.channel));
+        fs::write(&path, format!("{}\n", version))?;
+        Ok(Some(path))
+    }
+
+    /// Uploads the contents of `dl_dir` to `dst`, setting the Content-Type and Cache-Control
+    /// headers according to the `UPLOAD_CLASSES` table. `immutable` controls whether tarballs can
+    /// be cached forever, as they'll never change at that location.
+    fn upload_dl_dir(
+        &self,
+        endpoint_url: Option<&str>,
+        dst: &str,
+        immutable: bool,
+        extra_args: &[&str],
+    ) -> Result<(), Error> {
+        let upload = |patterns: &[&str], exclude_all: bool, class: Option<&UploadClass>| {
+            let mut cmd = self.aws_command(endpoint_url, "s3");
+            cmd.arg("cp")
+                .arg("--recursive")
+                .arg("--only-show-errors")
+                .args(extra_args)
+                .arg("--storage-class")
+                .arg(&self.config.storage_class);
+
+            let cache_control = match class {
+                Some(class) if class.tarball && immutable => CACHE_CONTROL_IMMUTABLE,
+                Some(class) => class.cache_control,
+                None => "public",
+            };
+            cmd.arg("--cache-control").arg(cache_control);
+            if let Some(content_type) = class.and_then(|class| class.content_type) {
+                cmd.arg("--content-type").arg(content_type);
+            }
+
+            if exclude_all {
+                cmd.arg("--exclude").arg("*");
+                for pattern in patterns {
+                    cmd.arg("--include").arg(pattern);
+                }
             } else {
-                format!("/{}/*", dir)
-            }],
-        )
+                for pattern in patterns {
+                    cmd.arg("--exclude").arg(pattern);
+                }
+            }
+
+            run(cmd.arg(format!("{}/", self.dl_dir().display())).arg(dst))
+        };
+
+        for class in UPLOAD_CLASSES {
+            upload(class.patterns, true, Some(class))?;
+        }
+        // Finally upload everything that didn't match any of the classes.
+        let all_patterns = UPLOAD_CLASSES
+            .iter()
+            .flat_map(|class| class.patterns.iter().copied())
+            .collect::<Vec<_>>();
+        upload(&all_patterns, false, None)
     }
 
-    fn publish_release(&mut self) -> Result<(), Error> {
-        let bucket = &self.config.upload_bucket;
-        let dir = &self.config.upload_dir;
-        let dst = format!("s3://{}/{}/", bucket, dir);
-        run(self
-            .aws_s3()
-            .arg("cp")
-            .arg("--recursive")
-            .arg("--only-show-errors")
-            .arg("--storage-class")
-            .arg(&self.config.storage_class)
-            .arg(format!("{}/", self.dl_dir().display()))
-            .arg(&dst))
+    /// Calls `upload` with the S3 endpoint, bucket and directory for the primary upload bucket
+    /// first, and then for each of the configured mirrors. Failures to upload to a mirror are only
+    /// logged unless PROMOTE_RELEASE_EXTRA_UPLOAD_FAILURES_FATAL is set.
+    fn upload_to_all_buckets(
+        &self,
+        upload: impl Fn(Option<&str>, &str, &str) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        upload(
+            self.config.s3_endpoint_url.as_deref(),
+            &self.config.upload_bucket,
+            &self.config.upload_dir,
+        )?;
+
+        for mirror in &self.config.extra_upload_buckets {
+            let endpoint_url = mirror
+                .endpoint_url
+                .as_deref()
+                .or(self.config.s3_endpoint_url.as_deref());
+            let result = upload(endpoint_url, &mirror.bucket, &mirror.dir);
+            match result {
+                Ok(()) => {}
+                Err(err) if self.config.extra_upload_failures_fatal => return Err(err),
+                Err(err) => {
+                    println!();
+                    println!("WARNING! Failed to upload to mirror {}: {:?}", mirror, err);
+                    println!();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Logs how many files and bytes were uploaded from `dl_dir` to `dst`. We have all the files
+    /// locally already, so there is no need to ask S3 about it.
+    fn log_upload_stats(&self, dst: &str) -> Result<(), Error> {
+        let mut count = 0;
+        let mut size = 0;
+        for entry in self.dl_dir().read_dir()? {
+            let metadata = entry?.metadata()?;
+            if metadata.is_file() {
+                count += 1;
+                size += metadata.len();
+            }
+        }
+        println!(
+            "uploaded {} files ({:.2} GiB, {} bytes) to {}",
+            count,
+            size as f64 / (1024.0 * 1024.0 * 1024.0),
+            size,
+            dst
+        );
+        Ok(())
     }
 
     fn invalidate_releases(&self) -> Result<(), Error> {
@@ -612,7 +1286,7 @@ impl Context {
         let dst = self.work.join("payload.json");
         std::fs::write(&dst, json.as_bytes())?;
 
-        let mut cmd = Command::new("aws");
+        let mut cmd = Command::new(&self.config.aws_cli);
         run(cmd
             .arg("cloudfront")
             .arg("create-invalidation")
@@ -643,7 +1317,12 @@ impl Context {
         };
 
         for path in paths {
-            fastly.purge(path)?;
+            let soft = self
+                .config
+                .fastly_soft_purge_paths
+                .iter()
+                .any(|pattern| recompress::glob_matches(pattern, path));
+            fastly.purge(path, soft)?;
         }
 
         Ok(())
@@ -664,39 +1343,66 @@ impl Context {
 
         if let Some(rustc_repo) = self.config.rustc_tag_repository.clone() {
             let rustc_version = self.current_version.clone().expect("has current version");
-            self.tag_repository(
+
+            // The cargo commit is needed before tagging rustc, as it might be included in the
+            // message of both tags.
+            let cargo_commit = if self.config.cargo_tag_repository.is_some() {
+                match github
+                    .token(&rustc_repo)?
+                    .read_file(Some(rustc_commit), "src/tools/cargo")?
+                {
+                    github::GitFile::Submodule { sha } => Some(sha),
+                    github::GitFile::File { .. } => {
+                        anyhow::bail!("src/tools/cargo is expected to be a submodule")
+                    }
+                }
+            } else {
+                None
+            };
+            let mut commits = vec![("rustc", rustc_commit)];
+            if let Some(cargo_commit) = &cargo_commit {
+                commits.push(("cargo", cargo_commit));
+            }
+            if !self.config.tag_include_commits {
+                commits.clear();
+            }
+
+            tag_repository(
                 signer,
                 &mut github,
                 &rustc_repo,
                 rustc_commit,
                 &rustc_version,
+                &self.config.rustc_tag_format,
+                &commits,
+                &self.config.rustc_tagger,
             )?;
 
             // Once we've tagged rustc, kick off a thanks workflow run.
-            github
-                .token("rust-lang/thanks")?
-                .workflow_dispatch("ci.yml", "master")?;
+            if self.config.thanks_repository.is_empty() {
+                eprintln!("Skipping thanks workflow dispatch - repository not configured");
+            } else {
+                github
+                    .token(&self.config.thanks_repository)?
+                    .workflow_dispatch(&self.config.thanks_workflow, &self.config.thanks_branch)?;
+            }
 
-            if let Some(cargo_repo) = self.config.cargo_tag_repository.clone() {
+            if let (Some(cargo_repo), Some(cargo_commit)) =
+                (self.config.cargo_tag_repository.clone(), &cargo_commit)
+            {
                 let cargo_version = self
                     .current_cargo_version
                     .clone()
                     .expect("has current cargo version");
-                let cargo_commit = match github
-                    .token(&rustc_repo)?
-                    .read_file(Some(rustc_commit), "src/tools/cargo")?
-                {
-                    github::GitFile::Submodule { sha } => sha,
-                    github::GitFile::File { .. } => {
-                        anyhow::bail!("src/tools/cargo is expected to be a submodule")
-                    }
-                };
-                self.tag_repository(
+                tag_repository(
                     signer,
                     &mut github,
                     &cargo_repo,
-                    &cargo_commit,
+                    cargo_commit,
                     &cargo_version,
+                    &self.config.cargo_tag_format,
+                    &commits,
+                    &self.config.cargo_tagger,
                 )?;
             }
         }
@@ -704,37 +1410,7 @@ impl Context {
         Ok(())
     }
 
-    fn tag_repository(
-        &mut self,
-        signer: &mut Signer,
-        github: &mut Github,
-        repository: &str,
-        commit: &str,
-        version: &str,
-    ) -> Result<(), Error> {
-        let tag_name = version.to_owned();
-        let username = "rust-lang/promote-release";
-        let email = "release-team@rust-lang.org";
-        let message = signer.git_signed_tag(
-            commit,
-            &tag_name,
-            username,
-            email,
-            &format!("{} release", version),
-        )?;
-
-        github.token(repository)?.tag(CreateTag {
-            commit,
-            tag_name: &tag_name,
-            message: &message,
-            tagger_name: username,
-            tagger_email: email,
-        })?;
-
-        Ok(())
-    }
-
-    fn blog_and_discourse(&mut self) -> Result<(), Error> {
+    fn blog_and_discourse(&mut self, changes: &[delta::VersionChange]) -> Result<(), Error> {
         if self.config.channel != Channel::Stable {
             eprintln!("Skipping blogging -- not on stable");
             return Ok(());
@@ -759,13 +1435,30 @@ impl Context {
             return Ok(());
         };
 
-        if self.config.scheduled_release_date.is_some() {
+        if let Some(scheduled) = self.config.scheduled_release_date {
             // If the release is scheduled for some date, then we treat it as dev-static stable and
             // call the relevant functions...
             let version = self.current_version.as_ref().expect("has current version");
+            if self.config.verify_release_notes_url {
+                warn_if_missing(&Config::dev_static_release_notes_url(version))?;
+            }
+            if self.config.check_releases_md {
+                let releases_md = github
+                    .token("rust-lang/rust")?
+                    .read_file(Some("stable"), "RELEASES.md")?
+                    .content()?;
+                if !releases_md_has_heading(&releases_md, version, scheduled)? {
+                    println!();
+                    println!(
+                        "WARNING! RELEASES.md on the stable branch has no heading for {}",
+                        version
+                    );
+                    println!();
+                }
+            }
             let internals_contents = if let Some(contents) = self
                 .config
-                .stable_dev_static_blog_contents(version, &self.date, false, None)
+                .stable_dev_static_blog_contents(version, &self.date, false, None, changes)
             {
                 contents
             } else {
@@ -784,6 +1477,7 @@ impl Context {
                 &self.date,
                 true,
                 Some(&internals_url),
+                changes,
             ) {
                 contents
             } else {
@@ -794,10 +1488,9 @@ impl Context {
             let mut token = github.token(repository_for_blog)?;
             token.create_file(
                 BLOG_PRIMARY_BRANCH,
-                &format!(
-                    "posts/inside-rust/{}-{}-prerelease.md",
-                    chrono::Utc::now().date_naive().format("%Y-%m-%d"),
-                    version,
+                &config::render_template(
+                    &self.config.blog_post_path,
+                    &[("date", &self.date), ("version", version)],
                 ),
                 &blog_contents,
             )?;
@@ -812,18 +1505,34 @@ impl Context {
             let version = self.current_version.as_ref().expect("has current version");
             if let Err(e) = token.merge_pr(pr) {
                 eprintln!("Failed to merge PR: {:?}", e);
+                // The state of the PR can only be trusted to explain a failure: GitHub reports
+                // the PR as blocked even when we're allowed to bypass the branch protection.
+                if let Ok(pull) = token.pull_request(pr) {
+                    if let Some(blocker) = pull.merge_blocker() {
+                        eprintln!("Can't merge blog PR #{}: {}", pr, blocker);
+                    }
+                }
                 return Ok(());
             }
 
             // Wait for a new deployment of the PR.
+            let wait_start = Instant::now();
+            let timeout = Duration::from_secs(self.config.blog_pages_timeout);
             loop {
                 let now = token.latest_github_pages()?;
                 // If no such build exists, then we also need to wait -- we only provide complete
                 // builds here.
                 if now.is_none() || before_merge == now {
+                    if wait_start.elapsed() >= timeout {
+                        anyhow::bail!(
+                            "blog PR #{pr} was merged, but GitHub pages didn't deploy it within \
+                             {timeout:?} (latest: {now:?}); the Discourse announcement for \
+                             Rust {version} needs to be posted manually"
+                        );
+                    }
                     println!("Waiting for GitHub pages deployment of blog (latest: {now:?})");
                     // Wait for a new deployment after merging the blog.
-                    std::thread::sleep(Duration::from_secs(33));
+                    std::thread::sleep(Duration::from_secs(self.config.blog_pages_poll_interval));
                 } else {
                     break;
                 }
@@ -833,7 +1542,7 @@ impl Context {
             //
             // https://users.rust-lang.org/c/announcements/6
             let announcements_category = 6;
-            let date = chrono::Utc::now().date_naive().format("%Y/%m/%d");
+            let date = self.date.replace('-', "/");
             discourse.create_topic(
                 announcements_category,
                 &format!("Rust {version}"),
@@ -856,6 +1565,11 @@ impl Context {
         self.work.join("manifests-smoke")
     }
 
+    /// The public URL release artifacts are served from, used as the base for all manifest URLs.
+    fn upload_base(&self) -> String {
+        join_url(&self.config.upload_addr, &self.config.upload_dir)
+    }
+
     fn s3_artifacts_url(&self, path: &str) -> String {
         format!(
             "s3://{}/{}/{}",
@@ -864,35 +1578,121 @@ impl Context {
     }
 
     fn aws_s3(&self) -> Command {
-        let mut cmd = Command::new("aws");
+        self.aws_command(self.config.s3_endpoint_url.as_deref(), "s3")
+    }
+
+    /// The S3 transfer settings of the AWS CLI we override, if any.
+    fn aws_s3_settings(&self) -> Vec<(&'static str, String)> {
+        let mut settings = Vec::new();
+        if let Some(requests) = self.config.s3_max_concurrent_requests {
+            settings.push(("max_concurrent_requests", requests.to_string()));
+        }
+        if let Some(chunksize) = &self.config.s3_multipart_chunksize {
+            settings.push(("multipart_chunksize", chunksize.clone()));
+        }
+        settings
+    }
+
+    fn aws_config_file(&self) -> PathBuf {
+        self.work.join("aws-config")
+    }
+
+    /// The S3 transfer settings can only be changed through the AWS CLI configuration file, so
+    /// we make a copy of the existing one with our settings applied. That copy is then used by
+    /// all the AWS CLI commands we execute.
+    fn configure_aws_cli(&self) -> Result<(), Error> {
+        let settings = self.aws_s3_settings();
+        if settings.is_empty() {
+            return Ok(());
+        }
+
+        let original = env::var_os("AWS_CONFIG_FILE")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".aws/config")))
+            .filter(|path| path.is_file());
+        match original {
+            Some(original) => drop(fs::copy(original, self.aws_config_file())?),
+            None => fs::write(self.aws_config_file(), "")?,
+        }
+
+        for (name, value) in settings {
+            run(Command::new(&self.config.aws_cli)
+                .env("AWS_CONFIG_FILE", self.aws_config_file())
+                .arg("configure")
+                .arg("set")
+                .arg(format!("default.s3.{}", name))
+                .arg(value))?;
+        }
+        Ok(())
+    }
+
+    /// Builds an AWS CLI command for `service`, optionally pointing it at a custom endpoint.
+    fn aws_command(&self, endpoint_url: Option<&str>, service: &str) -> Command {
+        let mut cmd = Command::new(&self.config.aws_cli);
+        if !self.aws_s3_settings().is_empty() {
+            cmd.env("AWS_CONFIG_FILE", self.aws_config_file());
+        }
 
         // Allow using non-S3 backends with the AWS CLI.
-        if let Some(url) = &self.config.s3_endpoint_url {
+        if let Some(url) = endpoint_url {
             cmd.arg("--endpoint-url");
             cmd.arg(url);
         }
 
-        cmd.arg("s3");
+        cmd.arg(service);
         cmd
     }
 
     fn download_top_level_manifest(&mut self) -> Result<toml::Value, Error> {
-        let url = format!(
-            "{}/{}/channel-rust-{}.toml",
-            self.config.upload_addr, self.config.upload_dir, self.config.channel
+        let url = join_url(
+            &self.upload_base(),
+            &format!("channel-rust-{}.toml", self.config.channel),
         );
         println!("downloading manifest from: {}", url);
 
-        Ok(self
+        let contents = self
             .download_file(&url)?
-            .expect("manifest not found")
-            .parse()?)
+            .ok_or_else(|| anyhow::anyhow!("manifest not found at {}", url))?;
+        contents.parse().with_context(|| {
+            format!(
+                "failed to parse the manifest at {}:\n{}",
+                url,
+                validate::snippet(&contents)
+            )
+        })
+    }
+
+    /// Returns the manifest of `channel` currently live in the upload bucket, if there's one.
+    fn live_manifest(&self, channel: Channel) -> Result<Option<toml::Value>, Error> {
+        let name = format!("channel-rust-{}.toml", channel);
+        let local = self.work.join(format!("live-{}", name));
+        let _ = fs::remove_file(&local);
+        let status = self
+            .aws_s3()
+            .arg("cp")
+            .arg("--only-show-errors")
+            .arg(format!(
+                "s3://{}/{}/{}",
+                self.config.upload_bucket, self.config.upload_dir, name
+            ))
+            .arg(&local)
+            .output()?
+            .status;
+        if !status.success() {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(&local)?;
+        let _ = fs::remove_file(&local);
+        let manifest = contents
+            .parse()
+            .with_context(|| format!("failed to parse the live {}", name))?;
+        Ok(Some(manifest))
     }
 
     fn dated_manifest_exists(&mut self) -> Result<bool, Error> {
-        let url = format!(
-            "{}/{}/{}/channel-rust-{}.toml",
-            self.config.upload_addr, self.config.upload_dir, self.date, self.config.channel,
+        let url = join_url(
+            &self.upload_base(),
+            &format!("{}/channel-rust-{}.toml", self.date, self.config.channel),
         );
         println!("checking if manifest exists: {}", url);
 
@@ -900,32 +1700,577 @@ impl Context {
     }
 
     fn download_file(&mut self, url: &str) -> Result<Option<String>, Error> {
-        self.handle.reset();
-        self.handle.get(true)?;
-        self.handle.url(url)?;
-        let mut result = Vec::new();
-        {
-            let mut t = self.handle.transfer();
+        download(&mut self.handle, url, MAX_DOWNLOAD_SIZE)
+    }
+}
+
+/// Downloads the (small) text file at `url`, returning `None` if it doesn't exist. The download
+/// fails if the file is larger than `max_size` bytes, or if the server stops responding.
+fn download(handle: &mut Easy, url: &str, max_size: usize) -> Result<Option<String>, Error> {
+    handle.reset();
+    let mut headers = curl::easy::List::new();
+    curl_helper::identify(handle, &mut headers)?;
+    handle.http_headers(headers)?;
+    handle.get(true)?;
+    handle.url(url)?;
+    handle.connect_timeout(DOWNLOAD_CONNECT_TIMEOUT)?;
+    handle.timeout(DOWNLOAD_TIMEOUT)?;
+    let mut result = Vec::new();
+    let mut too_large = false;
+    let performed = {
+        let mut t = handle.transfer();
+
+        t.write_function(|data| {
+            if result.len() + data.len() > max_size {
+                too_large = true;
+                // Returning a different length than the one we received aborts the transfer.
+                return Ok(0);
+            }
+            result.extend_from_slice(data);
+            Ok(data.len())
+        })?;
+        t.perform()
+    };
+    if too_large {
+        anyhow::bail!("{} is larger than the limit of {} bytes", url, max_size);
+    }
+    performed.with_context(|| format!("failed to download {}", url))?;
+    match handle.response_code()? {
+        200 => Ok(Some(String::from_utf8(result)?)),
+        404 => Ok(None),
+        other => anyhow::bail!("unexpected status code while fetching {}: {}", url, other),
+    }
+}
 
-            t.write_function(|data| {
-                result.extend_from_slice(data);
-                Ok(data.len())
-            })?;
-            t.perform()?;
+/// Warns if `url` doesn't resolve, so that broken links are noticed before announcing them.
+fn warn_if_missing(url: &str) -> Result<(), Error> {
+    let mut handle = Easy::new();
+    let mut headers = curl::easy::List::new();
+    curl_helper::identify(&mut handle, &mut headers)?;
+    handle.http_headers(headers)?;
+    handle.nobody(true)?;
+    handle.follow_location(true)?;
+    handle.url(url)?;
+    handle.connect_timeout(DOWNLOAD_CONNECT_TIMEOUT)?;
+    handle.timeout(DOWNLOAD_TIMEOUT)?;
+    if let Err(err) = handle.perform() {
+        println!();
+        println!("WARNING! failed to check whether {} exists: {}", url, err);
+        println!();
+        return Ok(());
+    }
+    match handle.response_code()? {
+        200 => println!("{} exists", url),
+        other => {
+            println!();
+            println!("WARNING! {} returned status code {}", url, other);
+            println!();
         }
-        match self.handle.response_code()? {
-            200 => Ok(Some(String::from_utf8(result)?)),
-            404 => Ok(None),
-            other => anyhow::bail!("unexpected status code while fetching {}: {}", url, other),
+    }
+    Ok(())
+}
+
+/// Returns whether `RELEASES.md` has the `Version $version ($date)` heading the release notes
+/// link points to, failing if the heading has a different date than the scheduled one.
+fn releases_md_has_heading(
+    releases_md: &str,
+    version: &str,
+    scheduled: chrono::NaiveDate,
+) -> Result<bool, Error> {
+    let prefix = format!("Version {} (", version);
+    let Some(heading) = releases_md
+        .lines()
+        .find(|line| line.trim().starts_with(&prefix))
+    else {
+        return Ok(false);
+    };
+    let date = heading.trim()[prefix.len()..].trim_end_matches(')');
+    if date != scheduled.format("%Y-%m-%d").to_string() {
+        anyhow::bail!(
+            "RELEASES.md schedules {} for {}, but the scheduled release date is {}",
+            version,
+            date,
+            scheduled
+        );
+    }
+    Ok(true)
+}
+
+/// Fails if the filesystem containing `dir` has less than `required` bytes available, explaining
+/// what the space is needed for.
+pub(crate) fn check_free_space(dir: &Path, required: u64, what: &str) -> Result<(), Error> {
+    let available = fs2::available_space(dir)
+        .with_context(|| format!("failed to query the free space of {}", dir.display()))?;
+    if available < required {
+        anyhow::bail!(
+            "not enough free space in {} to {}: {} MiB available, {} MiB needed",
+            dir.display(),
+            what,
+            available / (1024 * 1024),
+            required.div_ceil(1024 * 1024),
+        );
+    }
+    Ok(())
+}
+
+/// Returns the commit `git_ref` points to in the remote `repository`, if it exists.
+fn find_git_ref(repository: &str, git_ref: &str) -> Result<Option<String>, Error> {
+    // git2 requires a git repository to be able to connect to a remote and fetch metadata, so
+    // this creates an empty repository in a temporary directory. It will be deleted once the
+    // function returns.
+    let temp = tempfile::tempdir()?;
+    let repo = git2::Repository::init(temp.path())?;
+
+    let mut remote = repo.remote("origin", repository)?;
+    remote.connect(git2::Direction::Fetch)?;
+
+    for head in remote.list()? {
+        if head.name() == git_ref {
+            return Ok(Some(hex::encode(head.oid().as_bytes())));
         }
     }
+    Ok(None)
 }
 
 fn run(cmd: &mut Command) -> Result<(), Error> {
     println!("running {:?}", cmd);
-    let status = cmd.status()?;
+    let (mut child, stderr) = spawn_capturing_stderr(cmd)?;
+    let status = child.wait()?;
+    if !status.success() {
+        anyhow::bail!(
+            "failed command:{:?}\n:{}{}",
+            cmd,
+            status,
+            stderr_tail(stderr)
+        );
+    }
+    Ok(())
+}
+
+/// Number of lines of the standard error of failed commands included in the error.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Spawns the command, forwarding its standard error to ours while keeping its last lines, so
+/// that they can be included in the error if the command fails.
+fn spawn_capturing_stderr(
+    cmd: &mut Command,
+) -> Result<(std::process::Child, std::thread::JoinHandle<Vec<String>>), Error> {
+    let mut child = cmd.stderr(std::process::Stdio::piped()).spawn()?;
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let reader = std::thread::spawn(move || {
+        use std::io::BufRead;
+
+        let mut tail = std::collections::VecDeque::new();
+        for line in std::io::BufReader::new(stderr).split(b'\n') {
+            let Ok(line) = line else { break };
+            let line = String::from_utf8_lossy(&line).into_owned();
+            eprintln!("{}", line);
+            if tail.len() == STDERR_TAIL_LINES {
+                tail.pop_front();
+            }
+            tail.push_back(line);
+        }
+        tail.into()
+    });
+    Ok((child, reader))
+}
+
+fn stderr_tail(reader: std::thread::JoinHandle<Vec<String>>) -> String {
+    match reader.join() {
+        Ok(lines) if !lines.is_empty() => format!("\nstderr:\n{}", lines.join("\n")),
+        _ => String::new(),
+    }
+}
+
+/// Like `run`, but kills the command and fails if it doesn't finish within `timeout`.
+fn run_with_timeout(cmd: &mut Command, timeout: Duration) -> Result<(), Error> {
+    println!("running {:?} (timeout: {:?})", cmd, timeout);
+    let start = Instant::now();
+    let (mut child, stderr) = spawn_capturing_stderr(cmd)?;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            // The stderr isn't waited for, as it might be kept open by the children of the
+            // command we killed.
+            anyhow::bail!("command timed out after {:?}: {:?}", timeout, cmd);
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
     if !status.success() {
-        anyhow::bail!("failed command:{:?}\n:{}", cmd, status);
+        anyhow::bail!(
+            "failed command:{:?}\n:{}{}",
+            cmd,
+            status,
+            stderr_tail(stderr)
+        );
     }
     Ok(())
 }
+
+/// Creates a signed tag for `version` pointing to `commit`, named after `tag_format` and listing
+/// the source `commits` of the release in the tag message.
+#[allow(clippy::too_many_arguments)]
+fn tag_repository(
+    signer: &Signer,
+    github: &mut Github,
+    repository: &str,
+    commit: &str,
+    version: &str,
+    tag_format: &str,
+    commits: &[(&str, &str)],
+    tagger: &Tagger,
+) -> Result<(), Error> {
+    let tag_name = config::tag_name(tag_format, version)?;
+    let message = signer.git_signed_tag(
+        commit,
+        &tag_name,
+        &tagger.name,
+        &tagger.email,
+        &tag_message(version, commits),
+    )?;
+
+    github.token(repository)?.tag(CreateTag {
+        commit,
+        tag_name: &tag_name,
+        message: &message,
+        tagger_name: &tagger.name,
+        tagger_email: &tagger.email,
+    })?;
+
+    Ok(())
+}
+
+/// Returns the contents of the `version` file in the tarball, which is either at the root or
+/// inside the top-level directory (`rustc-1.70.0-x86_64-unknown-linux-gnu/version`).
+pub(crate) fn find_version(tarball: impl Read) -> Result<Option<String>, Error> {
+    let mut archive = tar::Archive::new(tarball);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?;
+        let components = path
+            .components()
+            .filter(|c| *c != std::path::Component::CurDir)
+            .collect::<Vec<_>>();
+        if components.len() <= 2 && path.file_name() == Some("version".as_ref()) {
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents)?;
+            return Ok(Some(contents));
+        }
+    }
+    Ok(None)
+}
+
+/// A rustc version number, with the beta number split out: `1.75.0-beta.3` has `1.75.0-beta` as
+/// its base and `3` as its beta number.
+#[derive(Debug, PartialEq, Eq)]
+struct ReleaseVersion<'a> {
+    base: &'a str,
+    beta: Option<u32>,
+}
+
+impl<'a> ReleaseVersion<'a> {
+    fn parse(version: &'a str) -> Self {
+        let number = version.split(' ').next().unwrap_or("");
+        if let Some((base, beta)) = number.split_once("-beta.") {
+            if let Ok(beta) = beta.parse() {
+                return ReleaseVersion {
+                    base: &number[..base.len() + "-beta".len()],
+                    beta: Some(beta),
+                };
+            }
+        }
+        ReleaseVersion {
+            base: number,
+            beta: None,
+        }
+    }
+}
+
+/// Returns whether `current` is the same release as `previous`, in which case there is nothing to
+/// release.
+///
+/// Every beta number is a separate release: the number counts the PRs merged into the beta branch,
+/// and each of those (mostly backports) has to reach users. A beta number going backwards can only
+/// happen if the beta branch was force-pushed, so it's still released, but with a warning.
+fn same_release(previous: &str, current: &str) -> bool {
+    let previous = ReleaseVersion::parse(previous);
+    let current = ReleaseVersion::parse(current);
+    if let (Some(prev_beta), Some(current_beta)) = (previous.beta, current.beta) {
+        if previous.base == current.base && current_beta < prev_beta {
+            println!();
+            println!(
+                "WARNING! The beta number went backwards ({} -> {}), was the beta branch force-pushed?",
+                prev_beta, current_beta
+            );
+            println!();
+        }
+    }
+    previous == current
+}
+
+/// Returns the message of the release tags, optionally followed by the source commits.
+fn tag_message(version: &str, commits: &[(&str, &str)]) -> String {
+    let mut message = format!("{} release", version);
+    if !commits.is_empty() {
+        message.push('\n');
+        for (name, commit) in commits {
+            message.push_str(&format!("\n{} commit: {}", name, commit));
+        }
+    }
+    message
+}
+
+/// Joins two URL segments with a single slash, regardless of whether either of them already has a
+/// slash at the boundary. A stray trailing slash in the configuration would otherwise produce `//`
+/// in the manifest URLs, which some clients reject.
+fn join_url(base: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        base.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn free_space() {
+        let dir = tempfile::tempdir().unwrap();
+        check_free_space(dir.path(), 0, "do nothing").unwrap();
+        let err = check_free_space(dir.path(), u64::MAX, "store everything").unwrap_err();
+        assert!(err.to_string().contains("to store everything"));
+    }
+
+    #[test]
+    fn beta_versions() {
+        assert_eq!(
+            ReleaseVersion {
+                base: "1.75.0-beta",
+                beta: Some(3)
+            },
+            ReleaseVersion::parse("1.75.0-beta.3 (b66b7951b 2023-11-20)")
+        );
+        assert_eq!(
+            ReleaseVersion {
+                base: "1.75.0",
+                beta: None
+            },
+            ReleaseVersion::parse("1.75.0")
+        );
+        assert_eq!(
+            ReleaseVersion {
+                base: "1.75.0-beta",
+                beta: None
+            },
+            ReleaseVersion::parse("1.75.0-beta")
+        );
+
+        assert!(same_release(
+            "1.75.0-beta.3 (b66b7951b 2023-11-20)",
+            "1.75.0-beta.3"
+        ));
+        assert!(!same_release("1.75.0-beta.3", "1.75.0-beta.4"));
+        assert!(!same_release("1.75.0-beta.4", "1.75.0-beta.3"));
+        assert!(!same_release("1.75.0-beta.3", "1.76.0-beta.3"));
+        assert!(!same_release("1.75.0-beta", "1.75.0-beta.1"));
+        assert!(same_release("1.75.0", "1.75.0"));
+        assert!(!same_release("1.75.0", "1.75.1"));
+    }
+
+    #[test]
+    fn releases_md_heading() {
+        let releases_md = "Version 1.75.0 (2023-12-28)\n==========================\n\n\
+                           Version 1.74.1 (2023-12-07)\n===========================\n";
+        let date = |d| chrono::NaiveDate::parse_from_str(d, "%Y-%m-%d").unwrap();
+        assert!(releases_md_has_heading(releases_md, "1.75.0", date("2023-12-28")).unwrap());
+        assert!(releases_md_has_heading(releases_md, "1.74.1", date("2023-12-07")).unwrap());
+        assert!(!releases_md_has_heading(releases_md, "1.76.0", date("2024-02-08")).unwrap());
+        let err = releases_md_has_heading(releases_md, "1.75.0", date("2023-12-21")).unwrap_err();
+        assert_eq!(
+            "RELEASES.md schedules 1.75.0 for 2023-12-28, but the scheduled release date is \
+             2023-12-21",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn join_url_normalizes_slashes() {
+        let expected = "https://static.rust-lang.org/dist";
+        assert_eq!(expected, join_url("https://static.rust-lang.org", "dist"));
+        assert_eq!(expected, join_url("https://static.rust-lang.org/", "dist"));
+        assert_eq!(expected, join_url("https://static.rust-lang.org", "/dist"));
+        assert_eq!(
+            expected,
+            join_url("https://static.rust-lang.org//", "//dist")
+        );
+    }
+
+    #[test]
+    fn unused_files() {
+        let aws = crate::fake_aws::FakeAws::new();
+        let ctx = aws.context_with_dl(
+            &[],
+            &[
+                "rustc-nightly.tar.xz",
+                "rls-nightly.tar.xz",
+                "rls-nightly.tar.xz.sha256",
+            ],
+        );
+
+        let shipped = HashSet::from([PathBuf::from("rustc-nightly.tar.xz")]);
+        assert_eq!(
+            ctx.unused_files(&shipped).unwrap(),
+            vec![
+                PathBuf::from("rls-nightly.tar.xz"),
+                PathBuf::from("rls-nightly.tar.xz.sha256")
+            ]
+        );
+        // Listing the files doesn't remove them.
+        assert!(ctx.dl_dir().join("rls-nightly.tar.xz").is_file());
+    }
+
+    #[test]
+    fn excluded_targets() {
+        let aws = crate::fake_aws::FakeAws::new();
+        let ctx = aws.context_with_dl(
+            &[("EXCLUDE_TARGETS", "aarch64-apple-darwin")],
+            &[
+                "rustc-nightly-aarch64-apple-darwin.tar.xz",
+                "rustc-nightly-aarch64-apple-darwin.tar.xz.sha256",
+                "rust-std-nightly-aarch64-apple-darwin.tar.gz",
+                "rustc-nightly-x86_64-apple-darwin.tar.xz",
+                "rust-src-nightly.tar.xz",
+            ],
+        );
+
+        ctx.remove_excluded_targets().unwrap();
+        let mut remaining = fs::read_dir(ctx.dl_dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            [
+                "rust-src-nightly.tar.xz",
+                "rustc-nightly-x86_64-apple-darwin.tar.xz"
+            ]
+        );
+    }
+
+    #[test]
+    fn commit_sha_from_mirror() {
+        let mirror = tempfile::tempdir().unwrap();
+        let repo = git2::Repository::init(mirror.path()).unwrap();
+        let signature = git2::Signature::now("promote-release", "test@example.com").unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let commit = repo
+            .commit(
+                Some("refs/heads/beta"),
+                &signature,
+                &signature,
+                "init",
+                &tree,
+                &[],
+            )
+            .unwrap();
+
+        let aws = crate::fake_aws::FakeAws::new();
+        let missing = mirror.path().join("missing");
+        let mirror_path = mirror.path().to_str().unwrap();
+        let ctx = aws.context_with_dl(
+            &[
+                ("CHANNEL", "beta"),
+                ("REPOSITORY", missing.to_str().unwrap()),
+                ("REPOSITORY_MIRRORS", mirror_path),
+            ],
+            &[],
+        );
+        assert_eq!(commit.to_string(), ctx.get_commit_sha().unwrap());
+
+        let ctx = aws.context_with_dl(&[("CHANNEL", "stable"), ("REPOSITORY", mirror_path)], &[]);
+        assert!(ctx.get_commit_sha().is_err());
+    }
+
+    fn tarball(files: &[&str]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for file in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(file.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, file, file.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn version_file_location() {
+        let find = |files: &[&str]| find_version(&tarball(files)[..]).unwrap();
+        assert_eq!(
+            Some("rustc-1.70.0/version".into()),
+            find(&["rustc-1.70.0/rustc/lib/version", "rustc-1.70.0/version"])
+        );
+        assert_eq!(
+            Some("./rustc-1.70.0/version".into()),
+            find(&["./rustc-1.70.0/version"])
+        );
+        assert_eq!(Some("version".into()), find(&["version"]));
+        assert_eq!(None, find(&["rustc-1.70.0/rustc/version"]));
+    }
+
+    #[test]
+    fn download_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("channel-rust-nightly.toml"),
+            "a".repeat(1024),
+        )
+        .unwrap();
+        let server = SmokeTester::new(&[dir.path().to_path_buf()]).unwrap();
+        let url = |file: &str| format!("http://{}/dist/{}", server.server_addr(), file);
+
+        let mut handle = Easy::new();
+        let manifest = download(&mut handle, &url("channel-rust-nightly.toml"), 1024).unwrap();
+        assert_eq!(Some(1024), manifest.map(|m| m.len()));
+        assert!(download(&mut handle, &url("channel-rust-nightly.toml"), 1023).is_err());
+        assert_eq!(
+            None,
+            download(&mut handle, &url("missing.toml"), 1024).unwrap()
+        );
+        server.shutdown();
+    }
+
+    #[test]
+    fn failed_command_stderr() {
+        let err = run(Command::new("sh").args(["-c", "echo first >&2; echo oops >&2; exit 1"]))
+            .unwrap_err()
+            .to_string();
+        assert!(err.ends_with("stderr:\nfirst\noops"), "{}", err);
+
+        let err = run(Command::new("sh").args(["-c", "exit 1"]))
+            .unwrap_err()
+            .to_string();
+        assert!(!err.contains("stderr"));
+        run(&mut Command::new("true")).unwrap();
+    }
+
+    #[test]
+    fn tag_message_with_commits() {
+        assert_eq!("1.70.0 release", tag_message("1.70.0", &[]));
+        assert_eq!(
+            "1.70.0 release\n\nrustc commit: 90c5418\ncargo commit: ec8a8a0",
+            tag_message("1.70.0", &[("rustc", "90c5418"), ("cargo", "ec8a8a0")])
+        );
+    }
+}
@@ -0,0 +1,105 @@
+//! Bounds the memory used by the parallel phases of the release (recompression and signing), so
+//! that the peak usage doesn't depend on how many threads are running at the same time.
+//!
+//! Each unit of work acquires an estimate of the memory it needs before starting, waiting until
+//! enough of the budget is released by the other threads.
+
+use std::sync::{Condvar, Mutex};
+
+pub(crate) struct MemoryBudget {
+    /// Total budget in bytes, or `None` if memory usage is not bounded.
+    total: Option<u64>,
+    available: Mutex<u64>,
+    released: Condvar,
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        MemoryBudget::new(None)
+    }
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(megabytes: Option<u64>) -> Self {
+        let total = megabytes.map(|mb| mb * 1024 * 1024);
+        MemoryBudget {
+            total,
+            available: Mutex::new(total.unwrap_or(0)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until `bytes` are available, returning a guard releasing them once dropped. Requests
+    /// bigger than the whole budget wait for all the budget to be available, and then run alone.
+    pub(crate) fn acquire(&self, bytes: u64) -> MemoryGuard<'_> {
+        let Some(total) = self.total else {
+            return MemoryGuard {
+                budget: self,
+                bytes: 0,
+            };
+        };
+        let bytes = bytes.min(total);
+
+        let mut available = self.available.lock().unwrap();
+        while *available < bytes {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= bytes;
+
+        MemoryGuard {
+            budget: self,
+            bytes,
+        }
+    }
+}
+
+pub(crate) struct MemoryGuard<'a> {
+    budget: &'a MemoryBudget,
+    bytes: u64,
+}
+
+impl Drop for MemoryGuard<'_> {
+    fn drop(&mut self) {
+        if self.bytes > 0 {
+            *self.budget.available.lock().unwrap() += self.bytes;
+            self.budget.released.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn acquire_and_release() {
+        let budget = MemoryBudget::new(Some(10));
+        let mb = 1024 * 1024;
+
+        let first = budget.acquire(6 * mb);
+        let second = budget.acquire(4 * mb);
+        assert_eq!(0, *budget.available.lock().unwrap());
+        drop(first);
+        assert_eq!(6 * mb, *budget.available.lock().unwrap());
+        drop(second);
+
+        // Oversized requests are clamped to the whole budget.
+        let huge = budget.acquire(100 * mb);
+        assert_eq!(0, *budget.available.lock().unwrap());
+        drop(huge);
+        assert_eq!(10 * mb, *budget.available.lock().unwrap());
+    }
+
+    #[test]
+    fn waits_for_release() {
+        let budget = MemoryBudget::new(Some(1));
+        let guard = budget.acquire(1024 * 1024);
+        std::thread::scope(|s| {
+            let waiter = s.spawn(|| drop(budget.acquire(1024 * 1024)));
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+            drop(guard);
+            waiter.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,58 @@
+//! Notification of the release outcome to a generic webhook (Slack, Zulip...), so that the release
+//! team doesn't have to watch the CI logs.
+
+use crate::curl_helper::BodyExt;
+use crate::Context;
+use anyhow::Error;
+
+#[derive(serde::Serialize)]
+struct Payload<'a> {
+    status: &'a str,
+    channel: String,
+    version: Option<&'a str>,
+    date: &'a str,
+    error: Option<String>,
+    /// Non-fatal problems that need manual intervention.
+    warnings: &'a [String],
+}
+
+impl Context {
+    /// Sends the outcome of the release to PROMOTE_RELEASE_NOTIFY_WEBHOOK_URL, if configured.
+    /// Failing to notify is only logged, as it must not change the outcome of the release.
+    pub(crate) fn notify_webhook(&mut self, error: Option<&Error>, warnings: &[String]) {
+        let Some(url) = self.config.notify_webhook_url.clone() else {
+            return;
+        };
+        let payload = Payload {
+            status: if error.is_some() {
+                "failure"
+            } else {
+                "success"
+            },
+            channel: self.config.channel.to_string(),
+            version: self.current_version.as_deref(),
+            date: &self.date,
+            error: error.map(|err| format!("{:?}", err)),
+            warnings,
+        };
+        let body = serde_json::to_value(&payload).unwrap();
+
+        if let Err(err) = self.send_webhook(&url, body) {
+            println!();
+            println!("WARNING! Failed to notify the webhook: {:?}", err);
+            println!();
+        }
+    }
+
+    fn send_webhook(&mut self, url: &str, body: serde_json::Value) -> Result<(), Error> {
+        self.handle.reset();
+        let mut headers = curl::easy::List::new();
+        crate::curl_helper::identify(&mut self.handle, &mut headers)?;
+        headers.append("Content-Type: application/json")?;
+        self.handle.http_headers(headers)?;
+        self.handle.post(true)?;
+        self.handle.url(url)?;
+        self.handle.fail_on_error(true)?;
+        self.handle.with_body(body).send()
+    }
+}
@@ -9,30 +9,75 @@
 //! We have ~500 tarballs as of March 2023, and this recompression takes a considerable amount of
 //! time, particularly for the xz outputs. In our infrastructure this runs on a 72 vCPU container to
 //! finish in a reasonable amount of time.
+//!
+//! To avoid redoing all of that work when a release is retried, the outputs of every recompressed
+//! tarball are also kept in a cache inside the work directory (hard linked when possible), along
+//! with a stamp identifying the input and the settings they were produced from.
 
+use crate::config::{Config, XzMatchFinder};
 use crate::Context;
+use sha2::Digest;
+use std::collections::HashSet;
 use std::fmt::Write as FmtWrite;
 use std::fs::{self, File};
 use std::io::{self, Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 use xz2::read::XzDecoder;
 
+type Decoder = fn(File) -> Box<dyn Read>;
+
+/// Tuning of the xz encoder, configured with the PROMOTE_RELEASE_XZ_* variables.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct XzTuning {
+    preset: u32,
+    depth: u32,
+    nice_len: u32,
+    match_finder: XzMatchFinder,
+}
+
+impl XzTuning {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        XzTuning {
+            preset: config.xz_preset,
+            depth: config.xz_depth,
+            nice_len: config.xz_nice_len,
+            match_finder: config.xz_match_finder,
+        }
+    }
+}
+
+impl std::fmt::Display for XzTuning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "preset={} depth={} nice-len={} mf={}",
+            self.preset, self.depth, self.nice_len, self.match_finder
+        )
+    }
+}
+
 pub(crate) fn recompress_file(
     xz_path: &Path,
     recompress_gz: bool,
     gz_compression_level: flate2::Compression,
     recompress_xz: bool,
+    xz_tuning: XzTuning,
+    xz_threads: u32,
+    verify: bool,
 ) -> anyhow::Result<()> {
     println!("recompressing {}...", xz_path.display());
     let file_start = Instant::now();
     let gz_path = xz_path.with_extension("gz");
 
     let mut destinations: Vec<(&str, Box<dyn io::Write>)> = Vec::new();
+    // The recompressed files, and how to decompress them to verify them.
+    let mut outputs: Vec<(PathBuf, Decoder)> = Vec::new();
 
     // Produce gzip if explicitly enabled or the destination file doesn't exist.
     if recompress_gz || !gz_path.is_file() {
-        let gz = File::create(gz_path)?;
+        let gz = File::create(&gz_path)?;
+        outputs.push((gz_path, |f| Box::new(flate2::read::GzDecoder::new(f))));
         destinations.push((
             "gz",
             Box::new(flate2::write::GzEncoder::new(gz, gz_compression_level)),
@@ -49,21 +94,24 @@ pub(crate) fn recompress_file(
     // <192MB uncompressed tarballs. In promote-release since we're recompressing
     // 100s of tarballs there's no need for each individual compression to be
     // parallel.
+    //
+    // The exception are the few largest tarballs (configured with
+    // PROMOTE_RELEASE_XZ_PARALLEL_FILES), which would otherwise be compressed by a
+    // single thread while the others are idle. Those use an explicit block size equal
+    // to the dictionary size, so every thread gets some work.
     let xz_recompressed = xz_path.with_extension("xz_recompressed");
     if recompress_xz {
         let mut filters = xz2::stream::Filters::new();
-        let mut lzma_ops = xz2::stream::LzmaOptions::new_preset(9).unwrap();
-        // This sets the overall dictionary size, which is also how much memory (baseline)
-        // is needed for decompression.
-        lzma_ops.dict_size(64 * 1024 * 1024);
-        // Use the best match finder for compression ratio.
-        lzma_ops.match_finder(xz2::stream::MatchFinder::BinaryTree4);
+        let mut lzma_ops = xz2::stream::LzmaOptions::new_preset(xz_tuning.preset)?;
+        lzma_ops.dict_size(XZ_DICT_SIZE);
+        // By default, use the best match finder for compression ratio.
+        lzma_ops.match_finder(xz_tuning.match_finder.to_xz2());
         lzma_ops.mode(xz2::stream::Mode::Normal);
-        // Set nice len to the maximum for best compression ratio
-        lzma_ops.nice_len(273);
-        // Set depth to a reasonable value, 0 means auto, 1000 is somwhat high but gives
-        // good results.
-        lzma_ops.depth(1000);
+        // By default, set nice len to the maximum for best compression ratio
+        lzma_ops.nice_len(xz_tuning.nice_len);
+        // By default, set depth to a reasonable value, 0 means auto, 1000 is somwhat high but
+        // gives good results.
+        lzma_ops.depth(xz_tuning.depth);
         // 2 is the default and does well for most files
         lzma_ops.position_bits(2);
         // 0 is the default and does well for most files
@@ -74,9 +122,19 @@ pub(crate) fn recompress_file(
         filters.lzma2(&lzma_ops);
 
         // FIXME: Do we want a checksum as part of compression?
-        let stream =
-            xz2::stream::Stream::new_stream_encoder(&filters, xz2::stream::Check::None).unwrap();
+        let stream = if xz_threads > 1 {
+            xz2::stream::MtStreamBuilder::new()
+                .threads(xz_threads)
+                .block_size(XZ_PARALLEL_BLOCK_SIZE)
+                .filters(filters)
+                .check(xz2::stream::Check::None)
+                .encoder()
+                .unwrap()
+        } else {
+            xz2::stream::Stream::new_stream_encoder(&filters, xz2::stream::Check::None).unwrap()
+        };
         let xz_out = File::create(&xz_recompressed)?;
+        outputs.push((xz_recompressed.clone(), |f| Box::new(XzDecoder::new(f))));
         destinations.push((
             "xz",
             Box::new(xz2::write::XzEncoder::new_stream(
@@ -93,9 +151,10 @@ pub(crate) fn recompress_file(
     // can take arbitrary amounts of data as input). That seems like a reasonable
     // assumption though.
     let mut decompressor = XzDecoder::new(File::open(xz_path)?);
-    let mut buffer = vec![0u8; 4 * 1024 * 1024];
+    let mut buffer = vec![0u8; BUFFER_SIZE];
     let mut decompress_time = Duration::ZERO;
     let mut time_by_dest = vec![Duration::ZERO; destinations.len()];
+    let mut original = sha2::Sha256::default();
     loop {
         let start = Instant::now();
         let length = decompressor.read(&mut buffer)?;
@@ -103,6 +162,9 @@ pub(crate) fn recompress_file(
         if length == 0 {
             break;
         }
+        if verify {
+            original.update(&buffer[..length]);
+        }
         for (idx, (_, destination)) in destinations.iter_mut().enumerate() {
             let start = std::time::Instant::now();
             destination.write_all(&buffer[..length])?;
@@ -119,13 +181,40 @@ pub(crate) fn recompress_file(
         )?;
     }
     println!(
-        "recompressed {}: {:.2?} total, {:.2?} decompression{}",
+        "recompressed {}: {:.2?} total, {:.2?} decompression{} ({} xz threads)",
         xz_path.display(),
         file_start.elapsed(),
         decompress_time,
-        compression_times
+        compression_times,
+        xz_threads,
     );
 
+    // The compressors only finish writing their output when dropped.
+    drop(destinations);
+
+    // Make sure the new tarballs contain exactly the same data as the original one. This is
+    // expensive, but worth it when changing the compression settings.
+    if verify {
+        let verify_start = Instant::now();
+        let expected = original.finalize();
+        for (path, decoder) in outputs {
+            let mut digest = sha2::Sha256::default();
+            io::copy(&mut decoder(File::open(&path)?), &mut digest)?;
+            if digest.finalize() != expected {
+                anyhow::bail!(
+                    "{} doesn't decompress to the contents of {}",
+                    path.display(),
+                    xz_path.display()
+                );
+            }
+        }
+        println!(
+            "verified the recompressed {} in {:.2?}",
+            xz_path.display(),
+            verify_start.elapsed()
+        );
+    }
+
     if recompress_xz {
         fs::rename(&xz_recompressed, xz_path)?;
     }
@@ -133,12 +222,112 @@ pub(crate) fn recompress_file(
     Ok(())
 }
 
+/// Size of the buffer holding the decompressed data before it's fed to the compressors.
+const BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+/// Dictionary size of the xz compressor, which is also how much memory (baseline) is needed for
+/// decompression.
+const XZ_DICT_SIZE: u32 = 64 * 1024 * 1024;
+
+/// Size of the blocks compressed independently by the multi-threaded xz encoder.
+const XZ_PARALLEL_BLOCK_SIZE: u64 = XZ_DICT_SIZE as u64;
+
+/// Estimate of the memory needed to recompress a single file, used to stay within the memory
+/// budget. The xz encoder with the BT4 match finder needs about 11.5 times its dictionary size
+/// (the other match finders need less, so this is an upper bound),
+/// and we leave room for the decoder of the input tarball, whose dictionary we don't control.
+/// Each thread of a multi-threaded encoder has its own encoder, plus input and output buffers
+/// of a block each.
+fn recompress_memory(recompress_xz: bool, xz_threads: u32) -> u64 {
+    let mut bytes = BUFFER_SIZE as u64 + 2 * u64::from(XZ_DICT_SIZE);
+    if recompress_xz {
+        let mut encoder = u64::from(XZ_DICT_SIZE) * 23 / 2;
+        if xz_threads > 1 {
+            encoder += 2 * XZ_PARALLEL_BLOCK_SIZE;
+        }
+        bytes += encoder * u64::from(xz_threads);
+    }
+    bytes
+}
+
+/// Name of the file, inside each cache entry, identifying what the cached outputs were produced
+/// from. It's written last, so an entry without a matching stamp is never used.
+const STAMP_NAME: &str = ".recompressed";
+
+/// Outputs of previous recompressions, with one directory per input tarball.
+struct RecompressCache {
+    dir: PathBuf,
+    settings: String,
+}
+
+impl RecompressCache {
+    /// Returns the stamp of the input file, which changes whenever the input or the settings do.
+    fn stamp(&self, xz_path: &Path) -> anyhow::Result<String> {
+        let mut digest = sha2::Sha256::default();
+        io::copy(&mut File::open(xz_path)?, &mut digest)?;
+        Ok(format!(
+            "{} {}",
+            hex::encode(digest.finalize()),
+            self.settings
+        ))
+    }
+
+    fn entry(&self, xz_path: &Path) -> PathBuf {
+        self.dir
+            .join(xz_path.file_name().expect("missing file name"))
+    }
+
+    /// Copies the cached outputs next to the input file, if the cache has them for this stamp.
+    fn restore(&self, xz_path: &Path, stamp: &str) -> anyhow::Result<bool> {
+        let entry = self.entry(xz_path);
+        if fs::read_to_string(entry.join(STAMP_NAME)).ok().as_deref() != Some(stamp) {
+            return Ok(false);
+        }
+        for file in entry.read_dir()? {
+            let file = file?;
+            if file.file_name() == STAMP_NAME {
+                continue;
+            }
+            let dest = xz_path.with_file_name(file.file_name());
+            let _ = fs::remove_file(&dest);
+            link_or_copy(&file.path(), &dest)?;
+        }
+        Ok(true)
+    }
+
+    fn store(&self, xz_path: &Path, outputs: &[PathBuf], stamp: &str) -> anyhow::Result<()> {
+        let entry = self.entry(xz_path);
+        let _ = fs::remove_dir_all(&entry);
+        fs::create_dir_all(&entry)?;
+        for output in outputs {
+            link_or_copy(output, &entry.join(output.file_name().unwrap()))?;
+        }
+        fs::write(entry.join(STAMP_NAME), stamp)?;
+        Ok(())
+    }
+}
+
+/// Hard links are safe here, as the files in the download directory are always replaced rather
+/// than modified in place.
+fn link_or_copy(src: &Path, dest: &Path) -> anyhow::Result<()> {
+    if fs::hard_link(src, dest).is_err() {
+        fs::copy(src, dest)?;
+    }
+    Ok(())
+}
+
 impl Context {
     pub fn recompress(&self, directory: &Path) -> anyhow::Result<()> {
         let mut to_recompress = Vec::new();
         for file in directory.read_dir()? {
             let file = file?;
             let path = file.path();
+            if let Some(filter) = &self.config.recompress_filter {
+                let name = file.file_name();
+                if !glob_matches(filter, &name.to_string_lossy()) {
+                    continue;
+                }
+            }
             match path.extension().and_then(|s| s.to_str()) {
                 // Store off the input files for potential recompression.
                 Some("xz") => {
@@ -161,19 +350,60 @@ impl Context {
             self.config.recompress_gz
         );
         println!("xz recompression enabled: {}", self.config.recompress_xz);
+        if let Some(filter) = &self.config.recompress_filter {
+            println!("only recompressing files matching {}", filter);
+        }
         let recompress_start = Instant::now();
 
         let recompress_gz = self.config.recompress_gz;
         let recompress_xz = self.config.recompress_xz;
         let compression_level = flate2::Compression::new(self.config.gzip_compression_level);
 
+        // Each tarball is recompressed next to the original, which is only replaced at the end,
+        // and the gz tarballs are usually less than twice as large as the xz ones.
+        let mut required = 0;
+        for path in &to_recompress {
+            let length = fs::metadata(path)?.len();
+            if recompress_xz {
+                required += length;
+            }
+            if recompress_gz || !path.with_extension("gz").is_file() {
+                required += 2 * length;
+            }
+        }
+        crate::check_free_space(directory, required, "recompress the tarballs")?;
+
         // Query the length of each file, and sort by length. This puts the smallest files
         // toward the start of the array, which will make us pop them last. Smaller units of work
         // are less likely to lead to a long tail of a single thread doing work while others are
         // idle, so we want to schedule them last (i.e., in the tail of the build).
         to_recompress.sort_by_cached_key(|path| fs::metadata(path).map(|m| m.len()).unwrap_or(0));
 
+        // The largest files are at the end of the array, and they're popped first.
+        let parallel_xz = to_recompress
+            .iter()
+            .rev()
+            .take(self.config.xz_parallel_files)
+            .cloned()
+            .collect::<HashSet<_>>();
+        let xz_parallel_threads = self.config.xz_parallel_threads;
+        let verify = self.config.verify_recompression;
+        let xz_tuning = XzTuning::from_config(&self.config);
+
         let total_length = to_recompress.len();
+        let memory = &self.memory;
+        let cache = RecompressCache {
+            dir: self.recompress_cache_dir(),
+            settings: format!(
+                "gz={} xz={} level={} xz-parallel={}x{} {}",
+                recompress_gz,
+                recompress_xz,
+                compression_level.level(),
+                self.config.xz_parallel_files,
+                xz_parallel_threads,
+                xz_tuning,
+            ),
+        };
 
         // Manually parallelize across freshly spawned worker threads. rayon is nice, but since we
         // care about the scheduling order and have very large units of work (>500ms, typically 10s
@@ -192,7 +422,36 @@ impl Context {
                         let path = to_recompress.lock().unwrap().pop();
                         path
                     } {
-                        recompress_file(&xz_path, recompress_gz, compression_level, recompress_xz)?;
+                        let stamp = cache.stamp(&xz_path)?;
+                        if cache.restore(&xz_path, &stamp)? {
+                            println!("reusing the recompressed {}", xz_path.display());
+                            continue;
+                        }
+
+                        let gz_path = xz_path.with_extension("gz");
+                        let mut outputs = Vec::new();
+                        if recompress_gz || !gz_path.is_file() {
+                            outputs.push(gz_path);
+                        }
+                        if recompress_xz {
+                            outputs.push(xz_path.clone());
+                        }
+                        let xz_threads = if parallel_xz.contains(&xz_path) {
+                            xz_parallel_threads
+                        } else {
+                            1
+                        };
+                        let _memory = memory.acquire(recompress_memory(recompress_xz, xz_threads));
+                        recompress_file(
+                            &xz_path,
+                            recompress_gz,
+                            compression_level,
+                            recompress_xz,
+                            xz_tuning,
+                            xz_threads,
+                            verify,
+                        )?;
+                        cache.store(&xz_path, &outputs, &stamp)?;
                     }
 
                     Ok::<_, anyhow::Error>(())
@@ -211,6 +470,183 @@ impl Context {
             total_length,
             recompress_start.elapsed(),
         );
-        Ok(())
+        check_formats(directory)
+    }
+}
+
+/// Ensures every tarball in `directory` is available both as `.tar.gz` and `.tar.xz`. The
+/// manifests reference both formats, so a missing one would be a 404 for the rustup clients
+/// preferring it.
+fn check_formats(directory: &Path) -> anyhow::Result<()> {
+    let mut names = HashSet::new();
+    for file in directory.read_dir()? {
+        names.insert(file?.file_name().to_string_lossy().into_owned());
+    }
+
+    let mut missing = names
+        .iter()
+        .filter_map(|name| {
+            let counterpart = if let Some(stem) = name.strip_suffix(".tar.xz") {
+                format!("{}.tar.gz", stem)
+            } else if let Some(stem) = name.strip_suffix(".tar.gz") {
+                format!("{}.tar.xz", stem)
+            } else {
+                return None;
+            };
+            (!names.contains(&counterpart)).then_some(counterpart)
+        })
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        missing.sort();
+        anyhow::bail!(
+            "tarballs missing after recompression: {}",
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+impl Context {
+    pub(crate) fn recompress_cache_dir(&self) -> PathBuf {
+        self.work.join("recompress-cache")
+    }
+}
+
+/// Simple glob matching, where `*` matches any sequence of characters (including none) and `?`
+/// matches exactly one character.
+pub(crate) fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` in the pattern, and of the name when we reached it.
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` consume one more character and try again.
+                Some((star_p, star_n)) => {
+                    backtrack = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "rustc-nightly.tar.gz",
+            "rustc-nightly.tar.xz",
+            "rust-nightly.msi",
+        ] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        check_formats(dir.path()).unwrap();
+
+        fs::write(dir.path().join("cargo-nightly.tar.xz"), "").unwrap();
+        fs::write(dir.path().join("rust-std-nightly.tar.gz"), "").unwrap();
+        assert_eq!(
+            "tarballs missing after recompression: cargo-nightly.tar.gz, rust-std-nightly.tar.xz",
+            check_formats(dir.path()).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_matches(
+            "rust-docs-*",
+            "rust-docs-nightly-x86_64-unknown-linux-gnu.tar.xz"
+        ));
+        assert!(glob_matches(
+            "*-linux-gnu.tar.?z",
+            "cargo-beta-x86_64-unknown-linux-gnu.tar.xz"
+        ));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("cargo*beta*", "cargo-beta"));
+        assert!(!glob_matches("rust-docs-*", "rust-std-nightly.tar.xz"));
+        assert!(!glob_matches("*.tar.gz", "rustc-nightly.tar.xz"));
+        assert!(!glob_matches("rustc-?", "rustc-"));
+    }
+
+    #[test]
+    fn verify_recompressed_gz() {
+        let dir = tempfile::tempdir().unwrap();
+        let xz = dir.path().join("cargo-nightly.tar.xz");
+        let mut encoder = xz2::write::XzEncoder::new(File::create(&xz).unwrap(), 1);
+        encoder.write_all(&[42; 100_000]).unwrap();
+        encoder.finish().unwrap();
+
+        let xz_tuning = XzTuning::from_config(&Config::for_tests(&[]));
+        recompress_file(
+            &xz,
+            true,
+            flate2::Compression::fast(),
+            false,
+            xz_tuning,
+            1,
+            true,
+        )
+        .unwrap();
+        let mut contents = Vec::new();
+        flate2::read::GzDecoder::new(File::open(xz.with_extension("gz")).unwrap())
+            .read_to_end(&mut contents)
+            .unwrap();
+        assert_eq!(vec![42; 100_000], contents);
+    }
+
+    #[test]
+    fn cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = RecompressCache {
+            dir: dir.path().join("cache"),
+            settings: "gz=true xz=true level=9".into(),
+        };
+        let dl = dir.path().join("dl");
+        fs::create_dir_all(&dl).unwrap();
+        let xz = dl.join("cargo-nightly.tar.xz");
+        let gz = dl.join("cargo-nightly.tar.gz");
+
+        fs::write(&xz, "original").unwrap();
+        let stamp = cache.stamp(&xz).unwrap();
+        assert!(!cache.restore(&xz, &stamp).unwrap());
+
+        // Simulate the recompression and store its outputs.
+        fs::write(&xz, "recompressed xz").unwrap();
+        fs::write(&gz, "recompressed gz").unwrap();
+        cache.store(&xz, &[gz.clone(), xz.clone()], &stamp).unwrap();
+
+        // The retry starts again from freshly downloaded files.
+        fs::remove_file(&xz).unwrap();
+        fs::remove_file(&gz).unwrap();
+        fs::write(&xz, "original").unwrap();
+        assert_eq!(stamp, cache.stamp(&xz).unwrap());
+        assert!(cache.restore(&xz, &stamp).unwrap());
+        assert_eq!("recompressed xz", fs::read_to_string(&xz).unwrap());
+        assert_eq!("recompressed gz", fs::read_to_string(&gz).unwrap());
+
+        // Changing the input invalidates the entry.
+        fs::remove_file(&xz).unwrap();
+        fs::write(&xz, "changed").unwrap();
+        let new_stamp = cache.stamp(&xz).unwrap();
+        assert_ne!(stamp, new_stamp);
+        assert!(!cache.restore(&xz, &new_stamp).unwrap());
     }
 }
@@ -0,0 +1,111 @@
+//! Machine-readable summary of the release, published next to the channel manifest so that
+//! downstream automation (docs.rs, the website...) doesn't have to parse the manifest.
+
+use crate::validate::{package_version, rust_version};
+use crate::Context;
+use anyhow::Error;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Debug, PartialEq, Eq, serde::Serialize)]
+struct ReleaseInfo<'a> {
+    version: &'a str,
+    cargo_version: Option<&'a str>,
+    commit: &'a str,
+    date: &'a str,
+    channel: String,
+    /// Components shipped in this release.
+    components: Vec<&'a str>,
+}
+
+impl Context {
+    /// Writes `release-info-$channel.json`, if enabled with PROMOTE_RELEASE_UPLOAD_RELEASE_INFO.
+    /// The channel is part of the name, as every channel is published to the same directory.
+    pub(crate) fn write_release_info(&self, commit: &str) -> Result<Option<PathBuf>, Error> {
+        if !self.config.upload_release_info {
+            return Ok(None);
+        }
+
+        let manifest = self
+            .dl_dir()
+            .join(format!("channel-rust-{}.toml", self.config.channel));
+        let manifest: toml::Value = fs::read_to_string(manifest)?.parse()?;
+        let info = release_info(
+            &manifest,
+            commit,
+            &self.date,
+            self.config.channel.to_string(),
+        )?;
+
+        let path = self
+            .work
+            .join(format!("release-info-{}.json", self.config.channel));
+        fs::write(&path, serde_json::to_vec_pretty(&info)?)?;
+        Ok(Some(path))
+    }
+}
+
+fn release_info<'a>(
+    manifest: &'a toml::Value,
+    commit: &'a str,
+    date: &'a str,
+    channel: String,
+) -> Result<ReleaseInfo<'a>, Error> {
+    let components = manifest
+        .get("pkg")
+        .and_then(|pkg| pkg.as_table())
+        .map(|pkg| {
+            pkg.keys()
+                .map(|name| name.as_str())
+                .filter(|name| package_version(manifest, name).is_some())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(ReleaseInfo {
+        version: rust_version(manifest)?,
+        cargo_version: package_version(manifest, "cargo"),
+        commit,
+        date,
+        channel,
+        components,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn info_from_manifest() {
+        let manifest: toml::Value = r#"
+            date = "2023-06-01"
+            [pkg.rust]
+            version = "1.70.0 (90c541806 2023-05-31)"
+            [pkg.cargo]
+            version = "1.70.0 (ec8a8a0ca 2023-04-25)"
+            [pkg.rls-preview]
+            version = ""
+        "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            ReleaseInfo {
+                version: "1.70.0 (90c541806 2023-05-31)",
+                cargo_version: Some("1.70.0 (ec8a8a0ca 2023-04-25)"),
+                commit: "90c541806f23a127002de5b4038be731ba1458ca",
+                date: "2023-06-01",
+                channel: "stable".into(),
+                components: vec!["cargo", "rust"],
+            },
+            release_info(
+                &manifest,
+                "90c541806f23a127002de5b4038be731ba1458ca",
+                "2023-06-01",
+                "stable".into()
+            )
+            .unwrap()
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! Re-signing of an already published release, for example after rotating the signing key.
+//!
+//! The release is downloaded from its dated archive directory, and only its signatures are
+//! regenerated and uploaded: the tarballs, manifests and checksums are left untouched, and the
+//! existing checksums are verified against the downloaded files before signing them.
+
+use crate::sign::Signer;
+use crate::Context;
+use anyhow::Error;
+use std::fs;
+
+impl Context {
+    pub(crate) fn do_resign(&mut self) -> Result<(), Error> {
+        println!(
+            "re-signing the {} release archived on {}",
+            self.config.channel, self.date
+        );
+        let rev = self.download_archive()?;
+        println!("{} rev is {}", self.config.channel, rev);
+
+        let dl = self.dl_dir();
+        for entry in dl.read_dir()? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some("asc") {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        // The checksums don't change, only the signatures do.
+        self.config.reuse_checksums = true;
+        let signer = Signer::new(&self.config, self.memory.clone())?;
+        let signed = signer.sign_directory(&dl)?.len();
+        println!("re-signed {} files", signed);
+
+        self.upload_signatures()?;
+        self.invalidate_releases()?;
+        self.clean_up_artifacts();
+        Ok(())
+    }
+
+    /// Uploads the signatures in `dl_dir` to the dated archive directory of the release, and to
+    /// the live directory if the release is still the live one.
+    fn upload_signatures(&self) -> Result<(), Error> {
+        let live = self.is_live()?;
+        if !live {
+            println!(
+                "the release archived on {} is not live anymore, only re-signing the archive",
+                self.date
+            );
+        }
+
+        // Everything else is already published, and must not change.
+        for entry in self.dl_dir().read_dir()? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("asc") {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        self.upload_to_all_buckets(|endpoint_url, bucket, dir| {
+            // The signatures are replaced in place, so they can't be cached forever.
+            self.upload_dl_dir(
+                endpoint_url,
+                &format!("s3://{}/{}/{}/", bucket, dir, self.date),
+                false,
+                &[],
+            )?;
+            if live {
+                self.upload_dl_dir(
+                    endpoint_url,
+                    &format!("s3://{}/{}/", bucket, dir),
+                    false,
+                    &[],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns whether the live manifest of the channel is the one archived in `dl_dir`.
+    fn is_live(&self) -> Result<bool, Error> {
+        let Some(live) = self.live_manifest(self.config.channel)? else {
+            return Ok(false);
+        };
+        let name = format!("channel-rust-{}.toml", self.config.channel);
+        let archived: toml::Value = fs::read_to_string(self.dl_dir().join(name))?.parse()?;
+        Ok(archived == live)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fake_aws::FakeAws;
+    use std::fs;
+
+    #[test]
+    fn upload_only_signatures() {
+        let aws = FakeAws::new();
+        let work = tempfile::tempdir().unwrap();
+        let ctx = aws.context(work.path(), &[]);
+        let manifest = "date = \"2023-06-01\"\n";
+        aws.put("static", "dist/channel-rust-nightly.toml", manifest);
+
+        let dl = ctx.dl_dir();
+        fs::create_dir_all(&dl).unwrap();
+        for (name, contents) in [
+            ("channel-rust-nightly.toml", manifest),
+            ("channel-rust-nightly.toml.asc", "new signature"),
+            ("rustc-nightly.tar.xz", "rustc"),
+            ("rustc-nightly.tar.xz.asc", "new signature"),
+        ] {
+            fs::write(dl.join(name), contents).unwrap();
+        }
+
+        ctx.upload_signatures().unwrap();
+        for dir in ["dist/2023-06-01", "dist"] {
+            let object = |name: &str| aws.object("static", &format!("{}/{}", dir, name));
+            assert_eq!(
+                "new signature",
+                fs::read_to_string(object("rustc-nightly.tar.xz.asc")).unwrap()
+            );
+            assert!(!object("rustc-nightly.tar.xz").exists());
+        }
+    }
+
+    #[test]
+    fn archive_not_live() {
+        let aws = FakeAws::new();
+        let work = tempfile::tempdir().unwrap();
+        let ctx = aws.context(work.path(), &[]);
+        aws.put(
+            "static",
+            "dist/channel-rust-nightly.toml",
+            "date = \"2023-06-02\"\n",
+        );
+
+        let dl = ctx.dl_dir();
+        fs::create_dir_all(&dl).unwrap();
+        fs::write(
+            dl.join("channel-rust-nightly.toml"),
+            "date = \"2023-06-01\"\n",
+        )
+        .unwrap();
+        fs::write(dl.join("channel-rust-nightly.toml.asc"), "new signature").unwrap();
+
+        ctx.upload_signatures().unwrap();
+        assert!(aws
+            .object("static", "dist/2023-06-01/channel-rust-nightly.toml.asc")
+            .is_file());
+        assert!(!aws
+            .object("static", "dist/channel-rust-nightly.toml.asc")
+            .exists());
+    }
+}
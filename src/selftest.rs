@@ -0,0 +1,132 @@
+//! Hermetic end-to-end test of the release pipeline, meant to run in the CI of promote-release.
+//!
+//! A tiny set of synthetic tarballs is generated and run through the same steps as a release
+//! (recompression, manifest generation, signing, serving the files from the smoke test server),
+//! without interacting with S3, GitHub or Discourse. The synthetic toolchain can't actually be
+//! installed with rustup, so instead of building a crate we check the smoke test server serves
+//! back every file we produced. build-manifest can't be extracted from the synthetic tarballs
+//! either, so that step only runs when PROMOTE_RELEASE_BUILD_MANIFEST_PATH is set.
+
+use crate::build_manifest::BuildManifest;
+use crate::config::Channel;
+use crate::sign::Signer;
+use crate::smoke_test::SmokeTester;
+use crate::Context;
+use anyhow::Error;
+use curl::easy::Easy;
+use std::fs;
+use std::path::Path;
+
+const TARGET: &str = "x86_64-unknown-linux-gnu";
+const COMPONENTS: &[&str] = &["rustc", "rust-std", "cargo"];
+
+impl Context {
+    pub(crate) fn do_self_test(&mut self) -> Result<(), Error> {
+        if self.config.channel == Channel::Stable {
+            self.current_version = Some("1.0.0".into());
+        }
+        let release = self.config.channel.release_name(self);
+
+        let dl = self.dl_dir();
+        let _ = fs::remove_dir_all(&dl);
+        fs::create_dir_all(&dl)?;
+        for component in COMPONENTS {
+            write_synthetic_tarball(&dl, component, &release)?;
+        }
+
+        self.assert_all_components_present()?;
+        self.check_tarball_sizes()?;
+        self.recompress(&dl)?;
+
+        let manifests = self.smoke_manifest_dir();
+        let _ = fs::remove_dir_all(&manifests);
+        fs::create_dir_all(&manifests)?;
+        let smoke_test = SmokeTester::new(&[manifests.clone(), dl.clone()])?;
+        if self.config.build_manifest_path.is_some() {
+            BuildManifest::new(self)?.run(
+                &format!("http://{}/dist", smoke_test.server_addr()),
+                &manifests,
+            )?;
+        } else {
+            println!("skipping build-manifest, PROMOTE_RELEASE_BUILD_MANIFEST_PATH is not set");
+        }
+
+        let signer = Signer::new(&self.config, self.memory.clone())?;
+        let mut signed = signer.sign_directory(&dl)?.len();
+        signed += signer.sign_directory(&manifests)?.len();
+        println!("signed {} files", signed);
+
+        let mut served = 0;
+        for dir in [&dl, &manifests] {
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                let is_signature = name.ends_with(".asc") || name.ends_with(".sha256");
+                let is_signed = dir.join(format!("{}.asc", name)).is_file()
+                    && dir.join(format!("{}.sha256", name)).is_file();
+                if !is_signature && !is_signed {
+                    anyhow::bail!("{} was not signed", path.display());
+                }
+
+                let url = format!("http://{}/dist/{}", smoke_test.server_addr(), name);
+                if fetch(&url)? != fs::read(&path)? {
+                    anyhow::bail!("the smoke test server served a different {}", name);
+                }
+                served += 1;
+            }
+        }
+        smoke_test.shutdown();
+        println!("the smoke test server served all the {} files", served);
+
+        let _ = fs::remove_dir_all(&dl);
+        let _ = fs::remove_dir_all(&manifests);
+        println!("self-test passed");
+        Ok(())
+    }
+}
+
+/// Writes a tarball with the layout produced by rust-installer, containing a dummy binary.
+fn write_synthetic_tarball(dir: &Path, component: &str, release: &str) -> Result<(), Error> {
+    let name = format!("{}-{}-{}", component, release, TARGET);
+    let file = fs::File::create(dir.join(format!("{}.tar.xz", name)))?;
+    let mut builder = tar::Builder::new(xz2::write::XzEncoder::new(file, 1));
+
+    // Random contents, so that the tarball isn't compressed below the minimum size.
+    let binary = (0..8192).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+    let files: &[(String, &[u8])] = &[
+        (
+            format!("{}/version", name),
+            b"0.0.0 (000000000 1970-01-01)\n",
+        ),
+        (format!("{}/components", name), component.as_bytes()),
+        (format!("{}/rust-installer-version", name), b"3\n"),
+        (format!("{}/{}/bin/{}", name, component, component), &binary),
+    ];
+    for (path, contents) in files {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o755);
+        header.set_cksum();
+        builder.append_data(&mut header, path, *contents)?;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+fn fetch(url: &str) -> Result<Vec<u8>, Error> {
+    let mut handle = Easy::new();
+    handle.url(url)?;
+    let mut body = Vec::new();
+    {
+        let mut transfer = handle.transfer();
+        transfer.write_function(|data| {
+            body.extend_from_slice(data);
+            Ok(data.len())
+        })?;
+        transfer.perform()?;
+    }
+    match handle.response_code()? {
+        200 => Ok(body),
+        other => anyhow::bail!("unexpected status code while fetching {}: {}", url, other),
+    }
+}
@@ -1,38 +1,130 @@
 use anyhow::Error;
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
 use pgp::{
     armor::BlockType,
     crypto::hash::HashAlgorithm,
     packet::{self, Packet, SignatureConfig, SignatureType, SignatureVersion},
-    types::{KeyTrait, SecretKeyTrait},
-    Deserializable, SignedSecretKey,
+    types::{KeyTrait, PublicKeyTrait, SecretKeyTrait},
+    Deserializable, SignedPublicKey, SignedSecretKey, StandaloneSignature,
 };
+use rand::seq::SliceRandom;
 use rayon::prelude::*;
 use sha2::Digest;
 use std::{
     collections::HashMap,
     fmt::Write,
     fs::File,
+    io::Write as _,
     path::{Path, PathBuf},
+    process::Command,
+    sync::mpsc::{sync_channel, Receiver},
+    sync::Mutex,
     time::Instant,
 };
 
-use crate::config::Config;
+use crate::config::{BucketKey, Config};
+use crate::memory::MemoryBudget;
+use std::sync::Arc;
+
+/// Number of threads writing the signatures and checksums to disk.
+const WRITER_THREADS: usize = 2;
+
+/// Maximum number of signed files whose outputs are waiting to be written to disk. Producing the
+/// outputs is much slower than writing them, so the queue only needs to absorb I/O hiccups.
+const WRITE_QUEUE_SIZE: usize = 64;
+
+/// Number of artifacts whose signatures are verified once the release is signed, on top of the
+/// manifests. Verifying reads and hashes the whole file again, so it's not done for all of them.
+const VERIFIED_ARTIFACTS: usize = 8;
 
 pub(crate) struct Signer {
     gpg_key: SignedSecretKey,
     gpg_password: String,
+    public_key: Option<SignedPublicKey>,
     sha256_checksum_cache: HashMap<PathBuf, String>,
+    reuse_checksums: bool,
+    signatures_log: Option<PathBuf>,
+    hash_algorithm: HashAlgorithm,
+    pkg_sign_command: Option<String>,
+    pool: rayon::ThreadPool,
+    expected_fingerprint: Option<String>,
+    memory: Arc<MemoryBudget>,
+}
+
+/// Files produced by signing a file, waiting to be written to disk.
+struct PendingWrites {
+    asc_path: PathBuf,
+    asc: Vec<u8>,
+    /// Missing when the existing checksum file is reused.
+    sha256: Option<(PathBuf, String)>,
+}
+
+impl PendingWrites {
+    fn write(&self) -> Result<(), Error> {
+        std::fs::write(&self.asc_path, &self.asc)?;
+        if let Some((path, contents)) = &self.sha256 {
+            std::fs::write(path, contents)?;
+        }
+        Ok(())
+    }
+}
+
+/// Details about a signed file, recorded in the signatures log.
+#[derive(serde::Serialize)]
+pub(crate) struct SignatureRecord {
+    path: String,
+    sha256: String,
+    /// RFC 3339 timestamp of the signature creation.
+    sig_created: String,
+    key_id: String,
 }
 
 impl Signer {
-    pub(crate) fn new(config: &Config) -> Result<Self, Error> {
-        let mut key_file = File::open(&config.gpg_key_file)?;
-        let gpg_password = std::fs::read_to_string(&config.gpg_password_file)?;
+    pub(crate) fn new(config: &Config, memory: Arc<MemoryBudget>) -> Result<Self, Error> {
+        let gpg_password = config.gpg_password.read()?;
+        let gpg_key = SignedSecretKey::from_string(&config.gpg_key.read()?)?.0;
+
+        if let Some(message) = check_key_expiry(
+            gpg_key.expires_at(),
+            Utc::now(),
+            Duration::days(config.gpg_key_expiry_days),
+        ) {
+            if config.gpg_key_expiry_fatal {
+                anyhow::bail!("{}", message);
+            }
+            println!();
+            println!("WARNING! {}", message);
+            println!();
+        }
+        check_bucket_key(
+            &hex::encode(gpg_key.fingerprint()),
+            &config.upload_bucket,
+            &config.gpg_bucket_keys,
+        )?;
+
+        let public_key = match &config.gpg_public_key_file {
+            Some(path) => Some(SignedPublicKey::from_armor_single(File::open(path)?)?.0),
+            None => None,
+        };
+
         Ok(Signer {
-            gpg_key: SignedSecretKey::from_armor_single(&mut key_file)?.0,
+            gpg_key,
             gpg_password,
+            public_key,
             sha256_checksum_cache: HashMap::new(),
+            reuse_checksums: config.reuse_checksums,
+            signatures_log: config.signatures_log.as_ref().map(PathBuf::from),
+            hash_algorithm: config.gpg_hash_algorithm.0,
+            pkg_sign_command: config.pkg_sign_command.clone(),
+            memory,
+            expected_fingerprint: config
+                .expected_gpg_fingerprint
+                .as_deref()
+                .map(normalize_fingerprint)
+                .transpose()?,
+            pool: rayon::ThreadPoolBuilder::new()
+                .num_threads(config.sign_threads)
+                .build()?,
         })
     }
 
@@ -40,33 +132,47 @@ impl Signer {
         self.sha256_checksum_cache = new;
     }
 
-    pub(crate) fn sign_directory(&self, path: &Path) -> Result<(), Error> {
-        let mut paths = Vec::new();
-        for entry in std::fs::read_dir(path)? {
-            let entry = entry?;
-            let path = entry.path();
-
-            if !entry.metadata()?.is_file() || should_exclude_path(&path) {
-                continue;
-            }
-            paths.push(path);
-        }
-
-        self.sign_batch(&paths)
+    pub(crate) fn sign_directory(&self, path: &Path) -> Result<Vec<SignatureRecord>, Error> {
+        self.sign_batch(&signed_files(path)?)
     }
 
-    fn sign_batch(&self, paths: &[PathBuf]) -> Result<(), Error> {
+    fn sign_batch(&self, paths: &[PathBuf]) -> Result<Vec<SignatureRecord>, Error> {
         let start = Instant::now();
         println!(
             "hashing and signing {} files across {} threads",
             paths.len(),
-            rayon::current_num_threads().min(paths.len())
+            self.pool.current_num_threads().min(paths.len())
         );
 
-        paths
-            .par_iter()
-            .map(|path| self.sign(path))
-            .collect::<Result<Vec<()>, Error>>()?;
+        // Hashing and signing (CPU bound) happens in the signing pool, while the outputs are
+        // written to disk by a few dedicated threads, so the small writes don't interleave with
+        // the large reads of the signing threads.
+        let (send, recv) = sync_channel::<PendingWrites>(WRITE_QUEUE_SIZE);
+        let queue = Mutex::new(recv);
+        let records = std::thread::scope(|s| {
+            let writers = (0..WRITER_THREADS)
+                .map(|_| s.spawn(|| write_pending(&queue)))
+                .collect::<Vec<_>>();
+
+            let records = self.pool.install(|| {
+                paths
+                    .par_iter()
+                    .map_with(send, |send, path| {
+                        let (record, writes) = self.sign(path)?;
+                        send.send(writes)
+                            .map_err(|_| anyhow::anyhow!("the signature writers stopped"))?;
+                        Ok(record)
+                    })
+                    .collect::<Result<Vec<_>, Error>>()
+            });
+
+            // All the senders are dropped at this point, so the writers exit once they drain
+            // the queue.
+            for writer in writers {
+                writer.join().expect("no panics")?;
+            }
+            records
+        })?;
 
         println!(
             "finished hashing and signing {} files in {:.2?}",
@@ -74,10 +180,91 @@ impl Signer {
             start.elapsed()
         );
 
+        Ok(records)
+    }
+
+    /// Verifies the signatures of every file in `manifests_dir` and of a random sample of the
+    /// files in `artifacts_dir` against the public key, the way rustup does when signature
+    /// checks are enabled.
+    ///
+    /// The signatures must also be made by the key configured with
+    /// PROMOTE_RELEASE_EXPECTED_GPG_FINGERPRINT, if any. rustup verifies signatures against a
+    /// pinned key, so signing with any other key would break every update.
+    pub(crate) fn verify_signatures(
+        &self,
+        manifests_dir: &Path,
+        artifacts_dir: &Path,
+    ) -> Result<(), Error> {
+        let expected = self.expected_fingerprint.as_deref();
+        if let Some(expected) = expected {
+            let fingerprint = hex::encode(self.gpg_key.fingerprint());
+            if fingerprint != expected {
+                anyhow::bail!(
+                    "signing with key {}, but the expected key is {}",
+                    fingerprint,
+                    expected
+                );
+            }
+        }
+
+        let mut paths = signed_files(manifests_dir)?;
+        let artifacts = signed_files(artifacts_dir)?;
+        paths.extend(
+            artifacts
+                .choose_multiple(&mut rand::thread_rng(), VERIFIED_ARTIFACTS)
+                .cloned(),
+        );
+
+        for path in &paths {
+            let _memory = self.memory.acquire(std::fs::metadata(path)?.len());
+            match &self.public_key {
+                Some(key) => verify_detached_signature(key, path, expected)?,
+                None => verify_detached_signature(&self.gpg_key, path, expected)?,
+            }
+        }
+        println!(
+            "verified the signatures of {} manifests and {} of {} artifacts",
+            paths.len() - artifacts.len().min(VERIFIED_ARTIFACTS),
+            artifacts.len().min(VERIFIED_ARTIFACTS),
+            artifacts.len()
+        );
         Ok(())
     }
 
-    fn sign(&self, path: &Path) -> Result<(), Error> {
+    /// Appends the records to the signatures log (one JSON object per line), if one is
+    /// configured with PROMOTE_RELEASE_SIGNATURES_LOG.
+    pub(crate) fn append_signatures_log(&self, records: &[SignatureRecord]) -> Result<(), Error> {
+        let Some(log_path) = &self.signatures_log else {
+            return Ok(());
+        };
+        let mut log = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)?;
+        for record in records {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            log.write_all(&line)?;
+        }
+        println!(
+            "recorded {} signatures in {}",
+            records.len(),
+            log_path.display()
+        );
+        Ok(())
+    }
+
+    fn sign(&self, path: &Path) -> Result<(SignatureRecord, PendingWrites), Error> {
+        // The Apple signature is embedded in the installer, so it has to be applied before the
+        // file is hashed and signed with GPG.
+        if is_apple_installer(path) {
+            if let Some(command) = &self.pkg_sign_command {
+                crate::run(Command::new(command).arg(path))?;
+            }
+        }
+
+        // The whole file is kept in memory while hashing and signing it.
+        let _memory = self.memory.acquire(std::fs::metadata(path)?.len());
         let data = std::fs::read(path)?;
 
         // This is creating a hash of the file two times, one in generate_sha256 and one in
@@ -86,13 +273,34 @@ impl Signer {
         //
         // Eventually we should stop generating signatures for each file, and instead create a
         // SHA256SUMS file with the hashes of all the files we're shipping, and sign that.
-        self.generate_sha256(path, &data)?;
-        self.gpg_sign(path, &data)?;
-
-        Ok(())
+        let (sha256, sha256_file) = self.generate_sha256(path, &data)?;
+        let (sig_created, asc) = self.gpg_sign(&data)?;
+
+        let record = SignatureRecord {
+            path: path
+                .file_name()
+                .expect("missing file name")
+                .to_string_lossy()
+                .into_owned(),
+            sha256,
+            sig_created: sig_created.to_rfc3339(),
+            key_id: hex::encode(self.gpg_key.key_id()),
+        };
+        let writes = PendingWrites {
+            asc_path: add_suffix(path, ".asc"),
+            asc,
+            sha256: sha256_file,
+        };
+        Ok((record, writes))
     }
 
-    fn generate_sha256(&self, path: &Path, data: &[u8]) -> Result<(), Error> {
+    /// Returns the SHA256 of the file, and the checksum file to write unless the existing one is
+    /// reused.
+    fn generate_sha256(
+        &self,
+        path: &Path,
+        data: &[u8],
+    ) -> Result<(String, Option<(PathBuf, String)>), Error> {
         let canonical_path = std::fs::canonicalize(path)?;
 
         let sha256 = if let Some(cached) = self.sha256_checksum_cache.get(&canonical_path) {
@@ -108,16 +316,27 @@ impl Signer {
             .ok_or_else(|| anyhow::anyhow!("missing file name from path"))?
             .to_str()
             .ok_or_else(|| anyhow::anyhow!("the file name is not UTF-8"))?;
+        let contents = format!("{}  {}\n", sha256, file_name);
+
+        let sha256_path = add_suffix(path, ".sha256");
+        if self.reuse_checksums && sha256_path.is_file() {
+            // Only keep the existing checksum if it still matches the file we're shipping.
+            let existing = std::fs::read_to_string(&sha256_path)?;
+            if existing.trim_end() != contents.trim_end() {
+                anyhow::bail!(
+                    "reused checksum {} doesn't match the file (expected {})",
+                    sha256_path.display(),
+                    sha256
+                );
+            }
+            return Ok((sha256, None));
+        }
 
-        std::fs::write(
-            add_suffix(path, ".sha256"),
-            format!("{}  {}\n", sha256, file_name),
-        )?;
-
-        Ok(())
+        Ok((sha256, Some((sha256_path, contents))))
     }
 
-    fn gpg_sign(&self, path: &Path, data: &[u8]) -> Result<(), Error> {
+    /// Returns the creation time and the armored detached signature of the file.
+    fn gpg_sign(&self, data: &[u8]) -> Result<(DateTime<Utc>, Vec<u8>), Error> {
         let key_function = || self.gpg_password.trim().to_string();
         let now = Utc::now();
 
@@ -126,7 +345,7 @@ impl Signer {
             version: SignatureVersion::V4,
             typ: SignatureType::Binary,
             pub_alg: self.gpg_key.algorithm(),
-            hash_alg: HashAlgorithm::SHA2_512,
+            hash_alg: self.hash_algorithm,
             issuer: Some(pubkey.key_id()),
             created: Some(now),
             hashed_subpackets: vec![
@@ -136,12 +355,11 @@ impl Signer {
             unhashed_subpackets: Vec::new(),
         };
 
-        let mut dest = File::create(add_suffix(path, ".asc"))?;
-
+        let mut dest = Vec::new();
         let content = Packet::from(sign_config.sign(&self.gpg_key, key_function, data)?);
         pgp::armor::write(&content, BlockType::Signature, &mut dest, None)?;
 
-        Ok(())
+        Ok((now, dest))
     }
 
     /// Returns a message suitable for passing to `git tag -m` in order to make
@@ -181,7 +399,7 @@ impl Signer {
             version: SignatureVersion::V4,
             typ: SignatureType::Binary,
             pub_alg: self.gpg_key.algorithm(),
-            hash_alg: HashAlgorithm::SHA2_512,
+            hash_alg: self.hash_algorithm,
             issuer: Some(pubkey.key_id()),
             created: Some(now),
             hashed_subpackets: vec![
@@ -206,6 +424,132 @@ impl Signer {
     }
 }
 
+/// Returns the files in `dir` that get a signature.
+fn signed_files(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        if !entry.metadata()?.is_file() || should_exclude_path(&path) {
+            continue;
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+/// Verifies the `.asc` signature next to `path` against `key`, ensuring it was issued by the key
+/// with the `expected` fingerprint if there's one.
+fn verify_detached_signature(
+    key: &impl PublicKeyTrait,
+    path: &Path,
+    expected: Option<&str>,
+) -> Result<(), Error> {
+    let asc_path = add_suffix(path, ".asc");
+    let (signature, _) =
+        StandaloneSignature::from_armor_single(File::open(&asc_path).map_err(|err| {
+            anyhow::anyhow!("missing signature {}: {}", asc_path.display(), err)
+        })?)?;
+    if let Some(expected) = expected {
+        let issuer = signature
+            .signature
+            .issuer()
+            .map(hex::encode)
+            .ok_or_else(|| anyhow::anyhow!("{} has no issuer", asc_path.display()))?;
+        // The key ID is the low 64 bits of the (V4) fingerprint.
+        if !expected.ends_with(&issuer) {
+            anyhow::bail!(
+                "{} was issued by {}, but the expected key is {}",
+                asc_path.display(),
+                issuer,
+                expected
+            );
+        }
+    }
+    signature
+        .verify(key, &std::fs::read(path)?)
+        .map_err(|err| anyhow::anyhow!("invalid signature {}: {}", asc_path.display(), err))?;
+    Ok(())
+}
+
+/// Writes the files received from the queue until all the senders are gone. The queue is always
+/// drained, even after a failed write, so the signing threads never block on a full queue.
+fn write_pending(queue: &Mutex<Receiver<PendingWrites>>) -> Result<(), Error> {
+    let mut result = Ok(());
+    loop {
+        let next = queue.lock().unwrap().recv();
+        let Ok(writes) = next else {
+            return result;
+        };
+        if result.is_ok() {
+            result = writes.write();
+        }
+    }
+}
+
+/// Returns the fingerprint in lowercase hex without spaces, as it's often written in groups.
+fn normalize_fingerprint(fingerprint: &str) -> Result<String, Error> {
+    let normalized = fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_lowercase();
+    if normalized.len() != 40 || !normalized.chars().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("invalid GPG fingerprint: {}", fingerprint);
+    }
+    Ok(normalized)
+}
+
+/// Ensures the key with `fingerprint` is allowed to sign releases uploaded to `bucket`, according
+/// to PROMOTE_RELEASE_GPG_BUCKET_KEYS. Keys and buckets not mentioned there are not restricted.
+fn check_bucket_key(fingerprint: &str, bucket: &str, keys: &[BucketKey]) -> Result<(), Error> {
+    let mut key_buckets = Vec::new();
+    for entry in keys {
+        let expected = normalize_fingerprint(&entry.fingerprint)?;
+        if entry.bucket == bucket && expected != fingerprint {
+            anyhow::bail!(
+                "releases uploaded to bucket {} must be signed with key {}, not {}",
+                bucket,
+                expected,
+                fingerprint
+            );
+        }
+        if expected == fingerprint {
+            key_buckets.push(entry.bucket.as_str());
+        }
+    }
+    if !key_buckets.is_empty() && !key_buckets.contains(&bucket) {
+        anyhow::bail!(
+            "key {} is only allowed to sign releases uploaded to {}, not to bucket {}",
+            fingerprint,
+            key_buckets.join(", "),
+            bucket
+        );
+    }
+    Ok(())
+}
+
+/// Returns a message if the key is expired or expires within `threshold` from `now`.
+fn check_key_expiry(
+    expires_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+    threshold: Duration,
+) -> Option<String> {
+    let expires_at = expires_at?;
+    if expires_at <= now {
+        Some(format!("the GPG signing key expired on {}", expires_at))
+    } else if expires_at - now <= threshold {
+        Some(format!(
+            "the GPG signing key expires on {} (in {} days)",
+            expires_at,
+            (expires_at - now).num_days()
+        ))
+    } else {
+        None
+    }
+}
+
 #[allow(clippy::match_like_matches_macro)]
 fn should_exclude_path(path: &Path) -> bool {
     match path.extension().and_then(|ext| ext.to_str()) {
@@ -215,6 +559,10 @@ fn should_exclude_path(path: &Path) -> bool {
     }
 }
 
+fn is_apple_installer(path: &Path) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some("pkg")
+}
+
 fn add_suffix(path: &Path, suffix: &str) -> PathBuf {
     let mut file_name = path.file_name().expect("missing file name").to_os_string();
     file_name.push(suffix);
@@ -223,3 +571,177 @@ fn add_suffix(path: &Path, suffix: &str) -> PathBuf {
     path.set_file_name(file_name);
     path
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_expiry() {
+        let now = Utc::now();
+        let threshold = Duration::days(30);
+
+        assert_eq!(None, check_key_expiry(None, now, threshold));
+        assert_eq!(
+            None,
+            check_key_expiry(Some(now + Duration::days(60)), now, threshold)
+        );
+        assert!(check_key_expiry(Some(now + Duration::days(7)), now, threshold).is_some());
+        assert!(check_key_expiry(Some(now - Duration::days(1)), now, threshold).is_some());
+    }
+
+    #[test]
+    fn apple_installers() {
+        assert!(is_apple_installer(Path::new(
+            "rust-1.70.0-x86_64-apple-darwin.pkg"
+        )));
+        assert!(!is_apple_installer(Path::new(
+            "rust-1.70.0-x86_64-apple-darwin.tar.xz"
+        )));
+        assert!(!is_apple_installer(Path::new(
+            "rust-1.70.0-x86_64-apple-darwin.pkg.asc"
+        )));
+    }
+
+    #[test]
+    fn write_pending_drains_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let (send, recv) = sync_channel(1);
+        let queue = Mutex::new(recv);
+        let writes = |name: &str| PendingWrites {
+            asc_path: dir.path().join(format!("{}.asc", name)),
+            asc: b"signature".to_vec(),
+            sha256: Some((dir.path().join(format!("{}.sha256", name)), "sha".into())),
+        };
+
+        std::thread::scope(|s| {
+            let writer = s.spawn(|| write_pending(&queue));
+            send.send(writes("missing/cargo")).unwrap();
+            send.send(writes("rustc")).unwrap();
+            drop(send);
+            assert!(writer.join().unwrap().is_err());
+        });
+        // Files after the failure are not written, but they were still received.
+        assert!(!dir.path().join("rustc.asc").exists());
+
+        let (send, recv) = sync_channel(1);
+        let queue = Mutex::new(recv);
+        std::thread::scope(|s| {
+            let writer = s.spawn(|| write_pending(&queue));
+            send.send(writes("rustc")).unwrap();
+            drop(send);
+            writer.join().unwrap().unwrap();
+        });
+        assert_eq!(
+            "sha",
+            std::fs::read_to_string(dir.path().join("rustc.sha256")).unwrap()
+        );
+    }
+
+    fn generate_key(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+        let key = pgp::SecretKeyParamsBuilder::default()
+            .key_type(pgp::KeyType::EdDSA)
+            .can_sign(true)
+            .primary_user_id(format!("{} <{}@example.com>", name, name))
+            .build()
+            .unwrap()
+            .generate()
+            .unwrap()
+            .sign(String::new)
+            .unwrap();
+        let public = key.public_key().sign(&key, String::new).unwrap();
+
+        let secret_path = dir.join(format!("{}.asc", name));
+        let public_path = dir.join(format!("{}.pub.asc", name));
+        std::fs::write(&secret_path, key.to_armored_bytes(None).unwrap()).unwrap();
+        std::fs::write(&public_path, public.to_armored_bytes(None).unwrap()).unwrap();
+        (secret_path, public_path)
+    }
+
+    #[test]
+    fn verify_signatures() {
+        let keys = tempfile::tempdir().unwrap();
+        let (secret, public) = generate_key(keys.path(), "release");
+        let (_, other_public) = generate_key(keys.path(), "other");
+        let secret = secret.to_str().unwrap();
+        let (public, other_public) = (public.to_str().unwrap(), other_public.to_str().unwrap());
+        let fingerprint = |path: &str| {
+            let key = SignedPublicKey::from_armor_single(File::open(path).unwrap()).unwrap();
+            hex::encode(key.0.fingerprint())
+        };
+        let new_signer = |vars: &[(&str, &str)]| {
+            let mut vars = vars.to_vec();
+            vars.push(("GPG_KEY_FILE", secret));
+            Signer::new(&Config::for_tests(&vars), Arc::new(MemoryBudget::new(None))).unwrap()
+        };
+
+        let manifests = tempfile::tempdir().unwrap();
+        let artifacts = tempfile::tempdir().unwrap();
+        std::fs::write(manifests.path().join("channel-rust-nightly.toml"), "").unwrap();
+        for i in 0..VERIFIED_ARTIFACTS * 2 {
+            std::fs::write(
+                artifacts.path().join(format!("rustc-{}.tar.xz", i)),
+                "rustc",
+            )
+            .unwrap();
+        }
+        let signer = new_signer(&[]);
+        signer.sign_directory(manifests.path()).unwrap();
+        signer.sign_directory(artifacts.path()).unwrap();
+
+        signer
+            .verify_signatures(manifests.path(), artifacts.path())
+            .unwrap();
+        new_signer(&[("GPG_PUBLIC_KEY_FILE", public)])
+            .verify_signatures(manifests.path(), artifacts.path())
+            .unwrap();
+        assert!(new_signer(&[("GPG_PUBLIC_KEY_FILE", other_public)])
+            .verify_signatures(manifests.path(), artifacts.path())
+            .is_err());
+        new_signer(&[("EXPECTED_GPG_FINGERPRINT", &fingerprint(public))])
+            .verify_signatures(manifests.path(), artifacts.path())
+            .unwrap();
+        assert!(
+            new_signer(&[("EXPECTED_GPG_FINGERPRINT", &fingerprint(other_public))])
+                .verify_signatures(manifests.path(), artifacts.path())
+                .is_err()
+        );
+
+        std::fs::write(manifests.path().join("channel-rust-nightly.toml"), "x").unwrap();
+        assert!(signer
+            .verify_signatures(manifests.path(), artifacts.path())
+            .is_err());
+    }
+
+    #[test]
+    fn fingerprints() {
+        assert_eq!(
+            "108f66205eaeb0aaa8dd5e1c85ab96e6fa1be5fe",
+            normalize_fingerprint("108F 6620 5EAE B0AA A8DD  5E1C 85AB 96E6 FA1B E5FE").unwrap()
+        );
+        assert!(normalize_fingerprint("85AB96E6FA1BE5FE").is_err());
+        assert!(normalize_fingerprint(&"z".repeat(40)).is_err());
+    }
+
+    #[test]
+    fn bucket_keys() {
+        let prod = "108f66205eaeb0aaa8dd5e1c85ab96e6fa1be5fe";
+        let dev = "0123456789abcdef0123456789abcdef01234567";
+        let keys = [
+            "static=108F 6620 5EAE B0AA A8DD  5E1C 85AB 96E6 FA1B E5FE"
+                .parse::<BucketKey>()
+                .unwrap(),
+            format!("dev-static={}", dev).parse().unwrap(),
+        ];
+
+        check_bucket_key(prod, "static", &keys).unwrap();
+        check_bucket_key(dev, "dev-static", &keys).unwrap();
+        assert!(check_bucket_key(dev, "static", &keys).is_err());
+        assert!(check_bucket_key(prod, "dev-static", &keys).is_err());
+        // Buckets and keys without an entry are unrestricted.
+        check_bucket_key(&"a".repeat(40), "local", &keys).unwrap();
+        assert!(check_bucket_key(dev, "local", &keys).is_err());
+        check_bucket_key(dev, "static", &[]).unwrap();
+        assert!("static".parse::<BucketKey>().is_err());
+    }
+}
@@ -1,6 +1,11 @@
 use anyhow::Error;
-use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::{header, Body, Request, Response, Server, StatusCode};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread::JoinHandle;
+use std::time::Duration;
 use std::{net::SocketAddr, sync::Arc};
 use std::{path::PathBuf, process::Command};
 use tempfile::TempDir;
@@ -12,6 +17,7 @@ pub(crate) struct SmokeTester {
     runtime: JoinHandle<Runtime>,
     server_addr: SocketAddr,
     shutdown_send: Sender<()>,
+    xz_only: Arc<AtomicBool>,
 }
 
 impl SmokeTester {
@@ -19,12 +25,16 @@ impl SmokeTester {
         let addr = SocketAddr::from(([127, 0, 0, 1], 0));
 
         let paths = Arc::new(paths.to_vec());
+        let xz_only = Arc::new(AtomicBool::new(false));
+        let xz_only_service = xz_only.clone();
         let service = hyper::service::make_service_fn(move |_| {
             let paths = paths.clone();
+            let xz_only = xz_only_service.clone();
             async move {
                 Ok::<_, Error>(hyper::service::service_fn(move |req| {
                     let paths = paths.clone();
-                    async move { server_handler(req, paths) }
+                    let xz_only = xz_only.load(Ordering::SeqCst);
+                    async move { server_handler(req, paths, xz_only) }
                 }))
             }
         });
@@ -64,32 +74,60 @@ impl SmokeTester {
             runtime,
             server_addr,
             shutdown_send,
+            xz_only,
         })
     }
 
+    /// Makes the server refuse to serve `.tar.gz` tarballs, so that rustup can only install the
+    /// toolchain from the xz tarballs, validating our recompression of them.
+    pub(crate) fn set_xz_only(&self, xz_only: bool) {
+        self.xz_only.store(xz_only, Ordering::SeqCst);
+    }
+
     pub(crate) fn server_addr(&self) -> SocketAddr {
         self.server_addr
     }
 
-    pub(crate) fn test(self, channel: &Channel) -> Result<(), Error> {
+    /// Installs the toolchain from the local server and builds a sample crate with it. Each of
+    /// the commands is killed if it takes longer than `timeout`: since we control the server, a
+    /// hang most likely means there is a problem with the artifacts.
+    ///
+    /// The sample crate can optionally use a specific `edition` and depend on a crate from
+    /// crates.io (`dependency`, as a `name@version` spec), to exercise more of the toolchain.
+    /// Without internet access, cargo can run `offline` and crates.io can be replaced with a
+    /// `vendor_dir`.
+    pub(crate) fn test(
+        self,
+        channel: &Channel,
+        timeout: Duration,
+        edition: Option<&str>,
+        dependency: Option<&str>,
+        offline: bool,
+        vendor_dir: Option<&str>,
+    ) -> Result<(), Error> {
         let tempdir = TempDir::new()?;
         let cargo_dir = tempdir.path().join("sample-crate");
         std::fs::create_dir_all(&cargo_dir)?;
 
         let cargo = |args: &[&str]| {
-            crate::run(
-                Command::new("cargo")
-                    .arg(format!("+{}", channel))
-                    .args(args)
-                    .env("USER", "root")
-                    .current_dir(&cargo_dir),
-            )
+            let mut command = Command::new("cargo");
+            command
+                .arg(format!("+{}", channel))
+                .args(args)
+                .env("USER", "root")
+                .current_dir(&cargo_dir);
+            // Don't override CARGO_NET_OFFLINE if it's already set in our environment.
+            if offline {
+                command.env("CARGO_NET_OFFLINE", "true");
+            }
+            crate::run_with_timeout(&mut command, timeout)
         };
         let rustup = |args: &[&str]| {
-            crate::run(
+            crate::run_with_timeout(
                 Command::new("rustup")
                     .env("RUSTUP_DIST_SERVER", format!("http://{}", self.server_addr))
                     .args(args),
+                timeout,
             )
         };
 
@@ -101,36 +139,262 @@ impl SmokeTester {
             "--profile",
             "minimal",
         ])?;
-        cargo(&["init", "--bin", "."])?;
+        let mut init = vec!["init", "--bin", "."];
+        if let Some(edition) = edition {
+            init.extend(["--edition", edition]);
+        }
+        cargo(&init)?;
+        if let Some(vendor_dir) = vendor_dir {
+            let config_dir = cargo_dir.join(".cargo");
+            std::fs::create_dir_all(&config_dir)?;
+            std::fs::write(
+                config_dir.join("config.toml"),
+                vendored_sources_config(vendor_dir),
+            )?;
+        }
+        if let Some(dependency) = dependency {
+            cargo(&["add", dependency])?;
+            std::fs::write(cargo_dir.join("src/main.rs"), sample_main(dependency))?;
+        }
         cargo(&["run"])?;
 
-        // Finally shut down the HTTP server and the tokio reactor.
+        self.shutdown();
+        Ok(())
+    }
+
+    /// Shuts down the HTTP server and the tokio reactor.
+    pub(crate) fn shutdown(self) {
         self.shutdown_send
             .send(())
             .expect("failed to send shutdown message to the server");
         self.runtime.join().unwrap().shutdown_background();
-
-        Ok(())
     }
 }
 
-fn server_handler(req: Request<Body>, paths: Arc<Vec<PathBuf>>) -> Result<Response<Body>, Error> {
-    let file_name = match req.uri().path().split('/').last() {
+/// Source of the sample crate binary when it has a dependency. The dependency is explicitly
+/// referenced, so it's linked in even when it has no code we call.
+fn sample_main(dependency: &str) -> String {
+    let name = dependency.split('@').next().unwrap().replace('-', "_");
+    format!(
+        "use {name} as _;\n\nfn main() {{\n    \
+         let words: Vec<String> = vec![\"Hello\".into(), \"world!\".into()];\n    \
+         println!(\"{{}}\", words.join(\", \"));\n}}\n"
+    )
+}
+
+/// Cargo configuration replacing crates.io with the crates vendored in `vendor_dir`.
+fn vendored_sources_config(vendor_dir: &str) -> String {
+    let mut config = toml::value::Table::new();
+    let mut sources = toml::value::Table::new();
+    let mut crates_io = toml::value::Table::new();
+    crates_io.insert("replace-with".into(), "vendored-sources".into());
+    sources.insert("crates-io".into(), crates_io.into());
+    let mut vendored = toml::value::Table::new();
+    vendored.insert("directory".into(), vendor_dir.into());
+    sources.insert("vendored-sources".into(), vendored.into());
+    config.insert("source".into(), sources.into());
+    toml::to_string(&config).unwrap()
+}
+
+fn server_handler(
+    req: Request<Body>,
+    paths: Arc<Vec<PathBuf>>,
+    xz_only: bool,
+) -> Result<Response<Body>, Error> {
+    let file_name = match req.uri().path().split('/').next_back() {
         Some(file_name) => file_name,
         None => return not_found(),
     };
+    if xz_only && file_name.ends_with(".tar.gz") {
+        return not_found();
+    }
     for directory in &*paths {
         let path = directory.join(file_name);
         if path.is_file() {
-            let content = std::fs::read(&path)?;
-            return Ok(Response::new(content.into()));
+            let range = req
+                .headers()
+                .get(header::RANGE)
+                .and_then(|value| value.to_str().ok());
+            return serve_file(&path, range);
         }
     }
     not_found()
 }
 
+/// Serves `path` like the CDN does, including the part requested with a `Range` header. Only the
+/// requested bytes are read in memory.
+fn serve_file(path: &Path, range: Option<&str>) -> Result<Response<Body>, Error> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    let builder = Response::builder().header(header::ACCEPT_RANGES, "bytes");
+    let response = match byte_range(range, len) {
+        ByteRange::Full => {
+            let mut content = Vec::new();
+            file.read_to_end(&mut content)?;
+            builder.body(content.into())?
+        }
+        ByteRange::Partial(start, end) => {
+            let mut content = Vec::new();
+            file.seek(SeekFrom::Start(start))?;
+            file.take(end - start + 1).read_to_end(&mut content)?;
+            builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end, len),
+                )
+                .body(content.into())?
+        }
+        ByteRange::Unsatisfiable => builder
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+            .body(Body::empty())?,
+    };
+    Ok(response)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    /// Inclusive start and end of the range.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses the `Range` header of a request for a file of `len` bytes. Like most servers, we serve
+/// the whole file when the header is malformed or requests multiple ranges.
+fn byte_range(header: Option<&str>, len: u64) -> ByteRange {
+    let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    if end.contains(',') {
+        return ByteRange::Full;
+    }
+    let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+        // bytes=start-end
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+        // bytes=start-
+        (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+        // bytes=-suffix
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return ByteRange::Unsatisfiable;
+            }
+            (len.saturating_sub(suffix), len.saturating_sub(1))
+        }
+        _ => return ByteRange::Full,
+    };
+    if range.0 >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(range.0, range.1)
+    }
+}
+
 fn not_found() -> Result<Response<Body>, Error> {
     let mut response = Response::new("404: Not Found\n".into());
     *response.status_mut() = StatusCode::NOT_FOUND;
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xz_only() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["rustc-nightly.tar.gz", "rustc-nightly.tar.xz"] {
+            std::fs::write(dir.path().join(name), name).unwrap();
+        }
+        let paths = Arc::new(vec![dir.path().to_path_buf()]);
+        let status = |name: &str, xz_only: bool| {
+            let req = Request::get(format!("/dist/{}", name))
+                .body(Body::empty())
+                .unwrap();
+            server_handler(req, paths.clone(), xz_only)
+                .unwrap()
+                .status()
+        };
+
+        assert_eq!(StatusCode::OK, status("rustc-nightly.tar.gz", false));
+        assert_eq!(StatusCode::NOT_FOUND, status("rustc-nightly.tar.gz", true));
+        assert_eq!(StatusCode::OK, status("rustc-nightly.tar.xz", true));
+    }
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(ByteRange::Full, byte_range(None, 10));
+        assert_eq!(ByteRange::Partial(2, 5), byte_range(Some("bytes=2-5"), 10));
+        assert_eq!(
+            ByteRange::Partial(2, 9),
+            byte_range(Some("bytes=2-100"), 10)
+        );
+        assert_eq!(ByteRange::Partial(4, 9), byte_range(Some("bytes=4-"), 10));
+        assert_eq!(ByteRange::Partial(7, 9), byte_range(Some("bytes=-3"), 10));
+        assert_eq!(ByteRange::Partial(0, 9), byte_range(Some("bytes=-30"), 10));
+        assert_eq!(ByteRange::Unsatisfiable, byte_range(Some("bytes=10-"), 10));
+        assert_eq!(ByteRange::Unsatisfiable, byte_range(Some("bytes=-0"), 10));
+        assert_eq!(ByteRange::Full, byte_range(Some("bytes=5-2"), 10));
+        assert_eq!(ByteRange::Full, byte_range(Some("bytes=0-1,4-5"), 10));
+        assert_eq!(ByteRange::Full, byte_range(Some("items=0-1"), 10));
+    }
+
+    #[test]
+    fn serve_partial_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("channel-rust-nightly.toml");
+        std::fs::write(&path, "0123456789").unwrap();
+        let body = |response: Response<Body>| {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            runtime
+                .block_on(hyper::body::to_bytes(response.into_body()))
+                .unwrap()
+        };
+
+        let response = serve_file(&path, None).unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("bytes", response.headers()[header::ACCEPT_RANGES]);
+        assert_eq!(&b"0123456789"[..], body(response));
+
+        let response = serve_file(&path, Some("bytes=3-5")).unwrap();
+        assert_eq!(StatusCode::PARTIAL_CONTENT, response.status());
+        assert_eq!("bytes 3-5/10", response.headers()[header::CONTENT_RANGE]);
+        assert_eq!(&b"345"[..], body(response));
+
+        let response = serve_file(&path, Some("bytes=20-")).unwrap();
+        assert_eq!(StatusCode::RANGE_NOT_SATISFIABLE, response.status());
+        assert_eq!("bytes */10", response.headers()[header::CONTENT_RANGE]);
+    }
+
+    #[test]
+    fn vendored_sources() {
+        let config: toml::Value =
+            toml::from_str(&vendored_sources_config("/srv/vendor \"crates\"")).unwrap();
+        assert_eq!(
+            "vendored-sources",
+            config["source"]["crates-io"]["replace-with"]
+                .as_str()
+                .unwrap()
+        );
+        assert_eq!(
+            "/srv/vendor \"crates\"",
+            config["source"]["vendored-sources"]["directory"]
+                .as_str()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn sample_main_references_dependency() {
+        let source = sample_main("serde-json@1.0");
+        assert!(source.starts_with("use serde_json as _;\n"));
+        assert!(source.contains("fn main() {"));
+    }
+}
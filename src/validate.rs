@@ -0,0 +1,740 @@
+//! Sanity checks on channel manifests: both the live ones we download to learn about the previous
+//! release, and the ones generated by build-manifest before anything gets signed or published.
+//! The downloaded artifacts are also checked against the channel we're releasing, and for signs
+//! of truncation.
+
+use crate::config::Channel;
+use crate::Context;
+use anyhow::Error;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Components built as part of rustc itself, which must always have the same version as the
+/// `rust` package. Other components (clippy, rustfmt, miri...) have their own versioning.
+const RUSTC_VERSIONED_COMPONENTS: &[&str] = &[
+    "rustc",
+    "rust-std",
+    "rust-docs",
+    "rust-src",
+    "rustc-dev",
+    "rustc-docs",
+    "rust-analysis",
+    "rust-mingw",
+];
+
+impl Context {
+    /// Parses the channel manifest generated in `dir` and ensures it's internally consistent, and
+    /// that it doesn't drop anything rustup relies on compared to the `previous` live manifest.
+    pub(crate) fn validate_manifest(
+        &self,
+        dir: &Path,
+        previous: &toml::Value,
+    ) -> Result<(), Error> {
+        let path = dir.join(format!("channel-rust-{}.toml", self.config.channel));
+        let manifest: toml::Value = std::fs::read_to_string(&path)?.parse()?;
+
+        check_component_versions(&manifest, self.current_cargo_version.as_deref())
+            .and_then(|()| check_manifest_date(&manifest, &self.date))
+            .map_err(|err| err.context(format!("invalid manifest {}", path.display())))?;
+
+        let removed = removed_renames(previous, &manifest);
+        if !removed.is_empty() {
+            println!();
+            println!("WARNING! Renames missing from the new manifest, rustup upgrades may break:");
+            for rename in removed {
+                println!("  {}", rename);
+            }
+            println!();
+        }
+
+        Ok(())
+    }
+
+    /// The date of the release is captured once when promote-release starts, and it's used both in
+    /// the manifests and in the archive path. A release that started before midnight UTC is thus
+    /// still archived under the day it started, which we point out as it might be unexpected.
+    pub(crate) fn check_release_date(&self) {
+        let today = chrono::Utc::now()
+            .date_naive()
+            .format("%Y-%m-%d")
+            .to_string();
+        if self.config.archive_date.is_none() && today != self.date {
+            println!();
+            println!(
+                "WARNING! The release started on {} and crossed midnight UTC, it will be \
+                 archived under {} to match the manifests",
+                self.date, self.date
+            );
+            println!();
+        }
+    }
+
+    /// Ensures the artifacts we downloaded were built for the channel we're releasing, so that a
+    /// misconfigured download prefix can't promote nightly artifacts to stable (or vice versa).
+    pub(crate) fn check_artifacts_channel(&mut self) -> Result<(), Error> {
+        let version = self.load_version(|filename| filename.starts_with("rustc-"))?;
+        let channel = version_channel(&version);
+        if channel != self.config.channel {
+            anyhow::bail!(
+                "the downloaded artifacts are for the {} channel (version {}), but we're releasing {}",
+                channel,
+                version.trim(),
+                self.config.channel
+            );
+        }
+        Ok(())
+    }
+
+    /// Ensures none of the components has a version from another channel, catching components
+    /// built from the wrong branch. Only one tarball per component is inspected, as all the
+    /// targets are built from the same commit. Versions without a channel suffix are accepted, as
+    /// some tools (like miri) don't add it even on nightly.
+    pub(crate) fn check_components_channel(&self) -> Result<(), Error> {
+        let release = self.config.channel.release_name(self);
+        let mut tarballs = BTreeMap::new();
+        for entry in self.dl_dir().read_dir()? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if let Some(component) = tarball_component(&name, &release) {
+                tarballs.entry(component.to_string()).or_insert(path);
+            }
+        }
+
+        let channel = self.config.channel;
+        let mismatched = tarballs
+            .into_par_iter()
+            .map(|(component, path): (String, PathBuf)| {
+                let version = crate::find_version(xz2::read::XzDecoder::new(File::open(&path)?))?;
+                Ok(
+                    version.and_then(|version| match indicated_channel(&version) {
+                        Some(found) if found != channel => {
+                            Some(format!("{} ({})", component, version.trim()))
+                        }
+                        _ => None,
+                    }),
+                )
+            })
+            .collect::<Result<Vec<_>, Error>>()?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        if !mismatched.is_empty() {
+            anyhow::bail!(
+                "components with a version from another channel than {}:\n{}",
+                self.config.channel,
+                mismatched.join("\n")
+            );
+        }
+        Ok(())
+    }
+
+    /// Reports the `.tar.xz` tarballs smaller than PROMOTE_RELEASE_MIN_TARBALL_SIZE. Real
+    /// components are never that small, so they're most likely truncated CI uploads that would
+    /// otherwise be signed and published.
+    pub(crate) fn check_tarball_sizes(&self) -> Result<(), Error> {
+        let small = small_tarballs(&self.dl_dir(), self.config.min_tarball_size)?;
+        if small.is_empty() {
+            return Ok(());
+        }
+
+        let list = small
+            .iter()
+            .map(|(name, size)| format!("{} ({} bytes)", name, size))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if self.config.small_tarballs_fatal {
+            anyhow::bail!("suspiciously small tarballs:\n{}", list);
+        }
+        println!();
+        println!("WARNING! Suspiciously small tarballs:\n{}", list);
+        println!();
+        Ok(())
+    }
+
+    /// Ensures the artifacts referenced by the channel manifest generated in `dir` are not larger
+    /// than PROMOTE_RELEASE_MAX_TOTAL_SIZE in total, and that they didn't grow more than
+    /// PROMOTE_RELEASE_MAX_SIZE_GROWTH_PERCENT compared to the `previous` live manifest.
+    pub(crate) fn check_total_size(&self, dir: &Path, previous: &toml::Value) -> Result<(), Error> {
+        let max_total_size = self.config.max_total_size;
+        let max_growth = self.config.max_size_growth_percent;
+        if max_total_size.is_none() && max_growth.is_none() {
+            return Ok(());
+        }
+
+        let path = dir.join(format!("channel-rust-{}.toml", self.config.channel));
+        let manifest: toml::Value = std::fs::read_to_string(path)?.parse()?;
+        let mut total = 0;
+        for file in referenced_files(&manifest) {
+            if let Ok(metadata) = std::fs::metadata(self.dl_dir().join(file)) {
+                total += metadata.len();
+            }
+        }
+        println!("the artifacts of the release total {} bytes", total);
+
+        if let Some(max) = max_total_size {
+            if total > max {
+                anyhow::bail!(
+                    "the artifacts of the release total {} bytes, more than the maximum of {} bytes",
+                    total,
+                    max
+                );
+            }
+        }
+
+        if let Some(max_growth) = max_growth {
+            let Some(previous_total) = self.archived_size(previous)? else {
+                println!();
+                println!("WARNING! Couldn't find the size of the previous release, not comparing");
+                println!();
+                return Ok(());
+            };
+            let growth = (total as f64 / previous_total as f64 - 1.0) * 100.0;
+            println!(
+                "the previous release totals {} bytes ({:+.1}%)",
+                previous_total, growth
+            );
+            if growth > max_growth {
+                anyhow::bail!(
+                    "the artifacts of the release grew by {:.1}% since the previous release \
+                     ({} bytes to {} bytes), more than the maximum of {}%",
+                    growth,
+                    previous_total,
+                    total,
+                    max_growth
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the total size of the artifacts referenced by the `previous` live manifest, from
+    /// the listing of the archive it was published to.
+    fn archived_size(&self, previous: &toml::Value) -> Result<Option<u64>, Error> {
+        let Some(date) = previous.get("date").and_then(|date| date.as_str()) else {
+            return Ok(None);
+        };
+        let output = self
+            .aws_s3()
+            .arg("ls")
+            .arg(format!(
+                "s3://{}/{}/{}/",
+                self.config.upload_bucket, self.config.upload_dir, date
+            ))
+            .output()?;
+        if !output.status.success() {
+            return Ok(None);
+        }
+
+        // Each line is `date time size name`, and directories are listed as `PRE name/`.
+        let listing = String::from_utf8(output.stdout)?;
+        let sizes = listing
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.split_whitespace().skip(2);
+                let size = parts.next()?.parse::<u64>().ok()?;
+                let name = parts.next()?.rsplit('/').next()?;
+                Some((name, size))
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let mut total = None;
+        for file in referenced_files(previous) {
+            if let Some(size) = sizes.get(file) {
+                *total.get_or_insert(0) += size;
+            }
+        }
+        Ok(total)
+    }
+
+    /// Ensures every artifact referenced by the channel manifest generated in `dir` is present and
+    /// signed in `dl_dir`, as otherwise the published manifest would point to missing files.
+    pub(crate) fn verify_manifest_artifacts(&self, dir: &Path) -> Result<(), Error> {
+        let path = dir.join(format!("channel-rust-{}.toml", self.config.channel));
+        let manifest: toml::Value = std::fs::read_to_string(&path)?.parse()?;
+
+        let base = format!("{}/", self.upload_base());
+        let dl_dir = self.dl_dir();
+
+        let mut urls = Vec::new();
+        collect_urls(&manifest, &mut urls);
+
+        let mut errors = Vec::new();
+        for url in &urls {
+            let Some(file) = url
+                .strip_prefix(&base)
+                .and_then(|path| path.rsplit('/').next())
+            else {
+                errors.push(format!("{url} is not served from {base}"));
+                continue;
+            };
+            if !dl_dir.join(file).is_file() {
+                errors.push(format!("{file} is missing"));
+            } else if !dl_dir.join(format!("{file}.asc")).is_file() {
+                errors.push(format!("{file} is not signed"));
+            }
+        }
+
+        if !errors.is_empty() {
+            anyhow::bail!(
+                "{} references artifacts we're not going to upload:\n{}",
+                path.display(),
+                errors.join("\n")
+            );
+        }
+        println!("all {} artifacts in the manifest are present", urls.len());
+        Ok(())
+    }
+}
+
+/// Returns the version of the `rust` package in the manifest, reporting which part of the
+/// manifest is missing or has an unexpected type otherwise.
+pub(crate) fn rust_version(manifest: &toml::Value) -> Result<&str, Error> {
+    let mut value = manifest;
+    let mut path = String::new();
+    for key in ["pkg", "rust", "version"] {
+        if !path.is_empty() {
+            path.push('.');
+        }
+        path.push_str(key);
+        value = value.get(key).ok_or_else(|| {
+            anyhow::anyhow!(
+                "missing `{}` in manifest:\n{}",
+                path,
+                snippet(&manifest.to_string())
+            )
+        })?;
+    }
+    value.as_str().ok_or_else(|| {
+        anyhow::anyhow!(
+            "`{}` is a {} instead of a string in manifest:\n{}",
+            path,
+            value.type_str(),
+            snippet(&manifest.to_string())
+        )
+    })
+}
+
+/// Returns the start of `contents`, to include in error messages without dumping whole manifests.
+pub(crate) fn snippet(contents: &str) -> String {
+    const MAX_LEN: usize = 500;
+    match contents.char_indices().nth(MAX_LEN) {
+        Some((idx, _)) => format!("{}\n[...]", &contents[..idx]),
+        None => contents.to_string(),
+    }
+}
+
+pub(crate) fn package_version<'a>(manifest: &'a toml::Value, package: &str) -> Option<&'a str> {
+    manifest
+        .get("pkg")?
+        .get(package)?
+        .get("version")?
+        .as_str()
+        // Components missing from this release have an empty version.
+        .filter(|version| !version.is_empty())
+}
+
+/// Ensures all the components built alongside rustc share the version of the `rust` package, and
+/// that cargo has the version we detected from its tarball (if we detected one).
+fn check_component_versions(
+    manifest: &toml::Value,
+    cargo_version: Option<&str>,
+) -> Result<(), Error> {
+    let rust_version = package_version(manifest, "rust")
+        .ok_or_else(|| anyhow::anyhow!("missing version of the rust package"))?;
+
+    let mut errors = Vec::new();
+    for component in RUSTC_VERSIONED_COMPONENTS {
+        if let Some(version) = package_version(manifest, component) {
+            if version != rust_version {
+                errors.push(format!(
+                    "{component} has version {version:?}, expected {rust_version:?}"
+                ));
+            }
+        }
+    }
+
+    if let (Some(expected), Some(version)) = (cargo_version, package_version(manifest, "cargo")) {
+        if version.split(' ').next() != Some(expected) {
+            errors.push(format!(
+                "cargo has version {version:?}, expected {expected:?}"
+            ));
+        }
+    }
+
+    if !errors.is_empty() {
+        anyhow::bail!("inconsistent component versions:\n{}", errors.join("\n"));
+    }
+    Ok(())
+}
+
+/// Ensures the manifest is dated with the date of the archive it's going to be published to, as
+/// otherwise the links in the archive index would break.
+fn check_manifest_date(manifest: &toml::Value, date: &str) -> Result<(), Error> {
+    match manifest.get("date").and_then(|date| date.as_str()) {
+        Some(found) if found == date => Ok(()),
+        Some(found) => anyhow::bail!(
+            "the manifest is dated {}, but the release is archived under {}",
+            found,
+            date
+        ),
+        None => anyhow::bail!("the manifest has no date"),
+    }
+}
+
+/// Returns the name and size of the `.tar.xz` files in `dir` smaller than `threshold` bytes.
+fn small_tarballs(dir: &Path, threshold: u64) -> Result<Vec<(String, u64)>, Error> {
+    let mut small = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let size = entry.metadata()?.len();
+        if name.ends_with(".tar.xz") && size < threshold {
+            small.push((name, size));
+        }
+    }
+    small.sort();
+    Ok(small)
+}
+
+/// Returns the channel a rustc version string (like `1.70.0-beta.2 (...)`) was built for.
+fn version_channel(version: &str) -> Channel {
+    let number = version.split(' ').next().unwrap_or("");
+    if number.contains("-nightly") || number.contains("-dev") {
+        Channel::Nightly
+    } else if number.contains("-beta") {
+        Channel::Beta
+    } else {
+        Channel::Stable
+    }
+}
+
+/// Returns the channel in the version number, if it has a channel suffix.
+fn indicated_channel(version: &str) -> Option<Channel> {
+    let number = version.split(' ').next().unwrap_or("");
+    if number.contains('-') {
+        Some(version_channel(version))
+    } else {
+        None
+    }
+}
+
+/// Returns the component of a `$component-$release[-$target].tar.xz` tarball.
+fn tarball_component<'a>(file_name: &'a str, release: &str) -> Option<&'a str> {
+    file_name
+        .strip_suffix(".tar.xz")?
+        .split_once(&format!("-{}", release))
+        .map(|(component, _)| component)
+}
+
+/// Returns the names of the files referenced by the manifest.
+pub(crate) fn referenced_files(manifest: &toml::Value) -> BTreeSet<&str> {
+    let mut urls = Vec::new();
+    collect_urls(manifest, &mut urls);
+    urls.into_iter()
+        .filter_map(|url| url.rsplit('/').next())
+        .collect()
+}
+
+/// Collects the artifact URLs (the `url` and `xz_url` keys) anywhere in the manifest.
+fn collect_urls<'a>(value: &'a toml::Value, urls: &mut Vec<&'a str>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                match value.as_str() {
+                    Some(url) if key == "url" || key == "xz_url" => urls.push(url),
+                    _ => collect_urls(value, urls),
+                }
+            }
+        }
+        toml::Value::Array(array) => {
+            for value in array {
+                collect_urls(value, urls);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Returns the `[renames]` entries of the `previous` manifest which are missing or point to a
+/// different component in the `current` one. rustup relies on them to upgrade the components
+/// installed under their old name.
+fn removed_renames(previous: &toml::Value, current: &toml::Value) -> Vec<String> {
+    let renames = |manifest: &toml::Value| {
+        manifest
+            .get("renames")
+            .and_then(|renames| renames.as_table())
+            .map(|renames| {
+                renames
+                    .iter()
+                    .map(|(from, rename)| {
+                        let to = rename.get("to").and_then(|to| to.as_str()).unwrap_or("");
+                        (from.clone(), to.to_string())
+                    })
+                    .collect::<BTreeMap<_, _>>()
+            })
+            .unwrap_or_default()
+    };
+    let current = renames(current);
+
+    renames(previous)
+        .into_iter()
+        .filter_map(|(from, to)| match current.get(&from) {
+            Some(new_to) if *new_to == to => None,
+            Some(new_to) => Some(format!("{from} -> {to} (now {from} -> {new_to})")),
+            None => Some(format!("{from} -> {to}")),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(rustc: &str, cargo: &str) -> toml::Value {
+        format!(
+            r#"
+            [pkg.rust]
+            version = "1.70.0 (90c541806 2023-05-31)"
+            [pkg.rustc]
+            version = "{rustc}"
+            [pkg.rustc-dev]
+            version = ""
+            [pkg.cargo]
+            version = "{cargo}"
+            [pkg.clippy]
+            version = "0.1.70 (90c5418 2023-05-31)"
+            "#
+        )
+        .parse()
+        .unwrap()
+    }
+
+    #[test]
+    fn rust_version_from_manifest() {
+        let manifest = manifest("", "");
+        assert_eq!(
+            "1.70.0 (90c541806 2023-05-31)",
+            rust_version(&manifest).unwrap()
+        );
+
+        let missing: toml::Value = "[pkg.cargo]\nversion = \"1.70.0\"".parse().unwrap();
+        let err = rust_version(&missing).unwrap_err().to_string();
+        assert!(err.contains("missing `pkg.rust`"), "{}", err);
+
+        let wrong_type: toml::Value = "[pkg.rust]\nversion = 1".parse().unwrap();
+        let err = rust_version(&wrong_type).unwrap_err().to_string();
+        assert!(err.contains("`pkg.rust.version` is a integer"), "{}", err);
+    }
+
+    #[test]
+    fn snippet_truncates() {
+        assert_eq!("short", snippet("short"));
+        assert_eq!(
+            format!("{}\n[...]", "a".repeat(500)),
+            snippet(&"a".repeat(600))
+        );
+    }
+
+    #[test]
+    fn consistent_versions() {
+        let manifest = manifest(
+            "1.70.0 (90c541806 2023-05-31)",
+            "1.70.0 (ec8a8a0ca 2023-04-25)",
+        );
+        check_component_versions(&manifest, Some("1.70.0")).unwrap();
+        check_component_versions(&manifest, None).unwrap();
+    }
+
+    #[test]
+    fn mismatched_rustc_version() {
+        let manifest = manifest(
+            "1.69.0 (84c898d65 2023-04-16)",
+            "1.70.0 (ec8a8a0ca 2023-04-25)",
+        );
+        assert!(check_component_versions(&manifest, None).is_err());
+    }
+
+    #[test]
+    fn mismatched_cargo_version() {
+        let manifest = manifest(
+            "1.70.0 (90c541806 2023-05-31)",
+            "1.69.0 (6e9a83356 2023-04-12)",
+        );
+        assert!(check_component_versions(&manifest, Some("1.70.0")).is_err());
+    }
+
+    #[test]
+    fn renames() {
+        let previous: toml::Value = r#"
+            [renames.rls]
+            to = "rls-preview"
+            [renames.rustfmt]
+            to = "rustfmt-preview"
+            [renames.clippy]
+            to = "clippy-preview"
+            "#
+        .parse()
+        .unwrap();
+        let current: toml::Value = r#"
+            [renames.rls]
+            to = "rls-preview"
+            [renames.clippy]
+            to = "clippy"
+            [renames.miri]
+            to = "miri-preview"
+            "#
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            removed_renames(&previous, &current),
+            vec![
+                "clippy -> clippy-preview (now clippy -> clippy)",
+                "rustfmt -> rustfmt-preview"
+            ]
+        );
+        assert!(removed_renames(&current, &current).is_empty());
+    }
+
+    #[test]
+    fn manifest_urls() {
+        let manifest: toml::Value = r#"
+            [pkg.rustc.target.x86_64-unknown-linux-gnu]
+            available = true
+            url = "https://example.com/dist/2023-06-01/rustc.tar.gz"
+            xz_url = "https://example.com/dist/2023-06-01/rustc.tar.xz"
+            [pkg.rustc.target.aarch64-unknown-linux-gnu]
+            available = false
+            [[artifacts.installer-msi.target.x86_64-pc-windows-msvc]]
+            url = "https://example.com/dist/2023-06-01/rust.msi"
+            "#
+        .parse()
+        .unwrap();
+
+        let mut urls = Vec::new();
+        collect_urls(&manifest, &mut urls);
+        urls.sort();
+        assert_eq!(
+            urls,
+            vec![
+                "https://example.com/dist/2023-06-01/rust.msi",
+                "https://example.com/dist/2023-06-01/rustc.tar.gz",
+                "https://example.com/dist/2023-06-01/rustc.tar.xz",
+            ]
+        );
+    }
+
+    #[test]
+    fn channel_of_version() {
+        assert_eq!(
+            Channel::Stable,
+            version_channel("1.70.0 (90c541806 2023-05-31)")
+        );
+        assert_eq!(
+            Channel::Beta,
+            version_channel("1.71.0-beta.2 (a2b1646c5 2023-06-09)")
+        );
+        assert_eq!(
+            Channel::Nightly,
+            version_channel("1.72.0-nightly (8c74a5d27 2023-06-14)\n")
+        );
+    }
+
+    #[test]
+    fn manifest_date() {
+        let dated: toml::Value = "date = \"2023-06-01\"".parse().unwrap();
+        assert!(check_manifest_date(&dated, "2023-06-01").is_ok());
+        assert!(check_manifest_date(&dated, "2023-06-02").is_err());
+        assert!(check_manifest_date(&manifest("", ""), "2023-06-01").is_err());
+    }
+
+    #[test]
+    fn component_channels() {
+        assert_eq!(
+            Some(Channel::Nightly),
+            indicated_channel("0.1.72-nightly (8c74a5d 2023-06-14)")
+        );
+        assert_eq!(Some(Channel::Beta), indicated_channel("1.71.0-beta.3"));
+        assert_eq!(None, indicated_channel("miri 0.1.0 (8c74a5d 2023-06-14)"));
+        assert_eq!(None, indicated_channel("1.70.0 (90c541806 2023-05-31)"));
+
+        assert_eq!(
+            Some("rust-std"),
+            tarball_component("rust-std-beta-x86_64-unknown-linux-gnu.tar.xz", "beta")
+        );
+        assert_eq!(
+            Some("rust-src"),
+            tarball_component("rust-src-beta.tar.xz", "beta")
+        );
+        assert_eq!(
+            None,
+            tarball_component("rust-std-beta-x86_64-unknown-linux-gnu.tar.gz", "beta")
+        );
+    }
+
+    #[test]
+    fn small_tarball_sizes() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("rustc-nightly.tar.xz"), vec![0; 2048]).unwrap();
+        std::fs::write(dir.path().join("cargo-nightly.tar.xz"), "").unwrap();
+        std::fs::write(dir.path().join("channel-rust-nightly.toml"), "").unwrap();
+
+        assert_eq!(
+            vec![("cargo-nightly.tar.xz".to_string(), 0)],
+            small_tarballs(dir.path(), 1024).unwrap()
+        );
+        assert!(small_tarballs(dir.path(), 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn total_size() {
+        let aws = crate::fake_aws::FakeAws::new();
+        let target = |date: &str| {
+            format!(
+                "date = \"{date}\"\n\
+                 [pkg.rustc.target.x86_64-unknown-linux-gnu]\n\
+                 url = \"https://static.rust-lang.org/dist/{date}/rustc-nightly.tar.gz\"\n\
+                 xz_url = \"https://static.rust-lang.org/dist/{date}/rustc-nightly.tar.xz\"\n"
+            )
+        };
+        let previous: toml::Value = target("2023-05-31").parse().unwrap();
+        aws.put(
+            "static",
+            "dist/2023-05-31/rustc-nightly.tar.gz",
+            &"a".repeat(600),
+        );
+        aws.put(
+            "static",
+            "dist/2023-05-31/rustc-nightly.tar.xz",
+            &"a".repeat(400),
+        );
+        aws.put(
+            "static",
+            "dist/2023-05-31/cargo-beta.tar.xz",
+            &"a".repeat(10_000),
+        );
+
+        let check = |vars: &[(&str, &str)]| {
+            let ctx = aws.context_with_dl(vars, &[]);
+            std::fs::write(ctx.dl_dir().join("rustc-nightly.tar.gz"), "a".repeat(700)).unwrap();
+            std::fs::write(ctx.dl_dir().join("rustc-nightly.tar.xz"), "a".repeat(400)).unwrap();
+            let manifests = ctx.work.join("manifests");
+            std::fs::create_dir_all(&manifests).unwrap();
+            std::fs::write(
+                manifests.join("channel-rust-nightly.toml"),
+                target("2023-06-01"),
+            )
+            .unwrap();
+            ctx.check_total_size(&manifests, &previous)
+        };
+
+        check(&[]).unwrap();
+        check(&[("MAX_TOTAL_SIZE", "1100")]).unwrap();
+        assert!(check(&[("MAX_TOTAL_SIZE", "1099")]).is_err());
+        check(&[("MAX_SIZE_GROWTH_PERCENT", "15")]).unwrap();
+        let err = check(&[("MAX_SIZE_GROWTH_PERCENT", "5")]).unwrap_err();
+        assert!(err.to_string().contains("grew by 10.0%"), "{}", err);
+    }
+}